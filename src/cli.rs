@@ -1,25 +1,218 @@
-use std::ffi::OsString;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
-use std::io::{prelude::*, stdout};
-use std::path::PathBuf;
+use std::io::{prelude::*, stderr, stdin, stdout, BufWriter, Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use clap::{App, Arg, Shell, SubCommand};
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
 
 use rayon::{prelude::*, ThreadPoolBuilder};
+use serde_json::{json, Map, Value};
+use walkdir::WalkDir;
 
+use crate::console::{Console, Line};
+use crate::env;
 use crate::error::*;
+use crate::exec;
+use crate::files;
+use crate::git;
+use crate::host;
+use crate::i18n;
+use crate::limits;
+use crate::lock;
+use crate::missing;
+use crate::os_path;
+use crate::plugin;
+use crate::profile;
 use crate::render;
-use crate::spec::TemplateDef;
+use crate::spec::{Engine, HashAlgorithm, Spec, TemplateDef, Whitespace};
+use crate::state::{self, BuildState};
+use crate::trace;
+use crate::usage;
 
 pub(crate) fn get_parser<'a, 'b>() -> App<'a, 'b> {
     clap::app_from_crate!()
+        // Paths may be any bytes; they're read with value_of_os.
+        .global_setting(AppSettings::AllowInvalidUtf8)
+        .arg(
+            Arg::with_name("ERROR_FORMAT")
+                .help("Report errors as text, or as one JSON object per line with a stable code.")
+                .long("error-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("generate")
                 .about("Generate a single file from TEMPLATE and DATA, print to OUTPUT.")
-                .arg(Arg::with_name("TEMPLATE").required(true))
+                .arg(
+                    Arg::with_name("TEMPLATE")
+                        .help("Template file, or - to read the template from stdin.")
+                        .required(true),
+                )
                 .arg(Arg::with_name("DATA").required(true))
-                .arg(Arg::with_name("OUTPUT").default_value("-")),
+                .arg(Arg::with_name("OUTPUT").default_value("-"))
+                .arg(
+                    Arg::with_name("ENGINE")
+                        .help("Template engine used to render TEMPLATE.")
+                        .short("e")
+                        .long("engine")
+                        .possible_values(&Engine::variants())
+                        .default_value("handlebars"),
+                )
+                .arg(
+                    Arg::with_name("TRIM_BLOCKS")
+                        .help("Drop the first newline after a block tag.")
+                        .long("trim-blocks"),
+                )
+                .arg(
+                    Arg::with_name("LSTRIP_BLOCKS")
+                        .help("Drop spaces and tabs before a block tag at the start of a line.")
+                        .long("lstrip-blocks"),
+                )
+                .arg(
+                    Arg::with_name("TRACE")
+                        .help("Log lookups, helper calls and partials to stderr, or to FILE with --trace=FILE.")
+                        .long("trace")
+                        .value_name("FILE")
+                        .require_equals(true)
+                        .min_values(0)
+                        .max_values(1),
+                )
+                .arg(
+                    Arg::with_name("LENIENT")
+                        .help("Render missing variables as empty instead of failing.")
+                        .long("lenient")
+                        .conflicts_with("STRICT"),
+                )
+                .arg(
+                    Arg::with_name("STRICT")
+                        .help("Fail on missing variables.  This is the default.")
+                        .long("strict"),
+                )
+                .arg(
+                    Arg::with_name("COLLECT_MISSING")
+                        .help("Keep rendering past missing variables, then report all of them.")
+                        .long("collect-missing")
+                        .conflicts_with("LENIENT"),
+                )
+                .arg(
+                    Arg::with_name("WARN_UNUSED")
+                        .help("Warn about top-level data keys the template never references.")
+                        .long("warn-unused"),
+                )
+                .arg(
+                    Arg::with_name("HASH_CACHE")
+                        .help("Keep input digests in FILE, reusing them for inputs that haven't changed.")
+                        .long("hash-cache")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("NO_HASH")
+                        .help("Don't hash the inputs for `data_hash` and `template_hash`, leaving them null.")
+                        .long("no-hash"),
+                )
+                .arg(
+                    Arg::with_name("REPRODUCIBLE")
+                        .help("Render the same output for the same inputs, fixing `date` to SOURCE_DATE_EPOCH or else the Unix epoch.")
+                        .long("reproducible"),
+                )
+                .arg(
+                    Arg::with_name("HOST_INFO")
+                        .help("Add the hostname, user and working directory as `host`, `user` and `cwd`.")
+                        .long("host-info"),
+                )
+                .arg(
+                    Arg::with_name("CONTEXT")
+                        .help("A JSON object whose keys are added to the root context of every template.")
+                        .long("context")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("BUILD_COUNTER")
+                        .help("Count runs in FILE and add this run's number as `build_id`.")
+                        .long("build-counter")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("GIT")
+                        .help("Add the commit, branch, tag and dirty state of the current git repository as `git`.")
+                        .long("git"),
+                )
+                .arg(
+                    Arg::with_name("ALLOW_EXEC")
+                        .help("Enable the exec helper, which runs external commands from templates, and the helpers declared in the spec.")
+                        .long("allow-exec"),
+                )
+                .arg(
+                    Arg::with_name("ALLOW_ENV")
+                        .help("Let the env helper read the variables matching PATTERN, e.g. BUILD_*.  May be repeated.")
+                        .long("allow-env")
+                        .value_name("PATTERN")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("FILE_ROOT")
+                        .help("Resolve the paths of file helpers like read_file below DIR instead of each template's directory.")
+                        .long("file-root")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("LOCALE")
+                        .help("Language for the t and plural helpers, e.g. de-DE.")
+                        .long("locale")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("TRANSLATIONS")
+                        .help("Directory of translation catalogs named for their locale, e.g. de-DE.json.")
+                        .long("translations")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("PLUGIN")
+                        .help("Load handlebars helpers from the shared library PATH.  May be repeated.")
+                        .long("plugin")
+                        .value_name("PATH")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("MAX_DEPTH")
+                        .help("Maximum nesting depth of partials.")
+                        .long("max-depth")
+                        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                        .default_value(limits::DEFAULT_MAX_DEPTH),
+                )
+                .arg(
+                    Arg::with_name("MAX_OUTPUT")
+                        .help("Maximum size of each rendered file, in bytes or with a K, M or G suffix.")
+                        .long("max-output")
+                        .validator(|s| limits::parse_size(&s).map(|_| ()))
+                        .default_value(limits::DEFAULT_MAX_OUTPUT),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-template")
+                .about("Check FILE templates for syntax errors without rendering them")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Template files, or directories of templates, to check.")
+                        .multiple(true)
+                        .required(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name("completion")
@@ -63,12 +256,197 @@ pub(crate) fn get_parser<'a, 'b>() -> App<'a, 'b> {
                         .long("force")
                         .takes_value(false),
                 )
+                .arg(
+                    Arg::with_name("ORDERED")
+                        .help("Report entries in spec order, rather than as they finish.")
+                        .long("ordered"),
+                )
+                .arg(
+                    Arg::with_name("FAIL_FAST")
+                        .help("Start no more entries after the first failure, reporting the rest as not attempted.")
+                        .long("fail-fast")
+                        .conflicts_with("KEEP_GOING"),
+                )
+                .arg(
+                    Arg::with_name("KEEP_GOING")
+                        .help("Render every entry, whatever fails.  This is the default.")
+                        .long("keep-going"),
+                )
+                .arg(
+                    Arg::with_name("ALLOW_DUPLICATE_OUTPUTS")
+                        .help("Only warn when several entries write the same output, instead of failing.")
+                        .long("allow-duplicate-outputs"),
+                )
+                .arg(
+                    Arg::with_name("NO_LOCK")
+                        .help("Don't lock SPEC.lock, which keeps runs of the same spec from writing its outputs at once.")
+                        .long("no-lock"),
+                )
+                .arg(
+                    Arg::with_name("RETRIES")
+                        .help("Render entries that fail with an I/O error up to N more times, waiting twice as long before each.")
+                        .long("retries")
+                        .value_name("N")
+                        .validator(|s| s.parse::<u32>().map(|_| ()).map_err(|e| e.to_string()))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("STATE")
+                        .help("Decide what to remake by the content digests and options of the last builds, recorded in FILE or .ttgen-state beside SPEC, instead of by mod times.")
+                        .long("state")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("TIMEOUT")
+                        .help("Fail any entry whose render takes longer than SECONDS.")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .validator(|s| match s.parse::<f64>() {
+                            Ok(n) if n > 0.0 && n.is_finite() => Ok(()),
+                            _ => Err(format!("invalid timeout: {}", s)),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("LENIENT")
+                        .help("Render missing variables as empty instead of failing.")
+                        .long("lenient")
+                        .conflicts_with("STRICT"),
+                )
+                .arg(
+                    Arg::with_name("STRICT")
+                        .help("Fail on missing variables.  This is the default.")
+                        .long("strict"),
+                )
+                .arg(
+                    Arg::with_name("COLLECT_MISSING")
+                        .help("Keep rendering past missing variables, then report all of them.")
+                        .long("collect-missing")
+                        .conflicts_with("LENIENT"),
+                )
+                .arg(
+                    Arg::with_name("WARN_UNUSED")
+                        .help("Warn about top-level data keys the template never references.")
+                        .long("warn-unused"),
+                )
+                .arg(
+                    Arg::with_name("HASH_CACHE")
+                        .help("Keep input digests in FILE, reusing them for inputs that haven't changed.")
+                        .long("hash-cache")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("NO_HASH")
+                        .help("Don't hash the inputs for `data_hash` and `template_hash`, leaving them null.")
+                        .long("no-hash"),
+                )
+                .arg(
+                    Arg::with_name("REPRODUCIBLE")
+                        .help("Render the same output for the same inputs, fixing `date` to SOURCE_DATE_EPOCH or else the Unix epoch.")
+                        .long("reproducible"),
+                )
+                .arg(
+                    Arg::with_name("HOST_INFO")
+                        .help("Add the hostname, user and working directory as `host`, `user` and `cwd`.")
+                        .long("host-info"),
+                )
+                .arg(
+                    Arg::with_name("CONTEXT")
+                        .help("A JSON object whose keys are added to the root context of every template.")
+                        .long("context")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("BUILD_COUNTER")
+                        .help("Count runs in FILE and add this run's number as `build_id`.")
+                        .long("build-counter")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("GIT")
+                        .help("Add the commit, branch, tag and dirty state of the current git repository as `git`.")
+                        .long("git"),
+                )
+                .arg(
+                    Arg::with_name("ALLOW_EXEC")
+                        .help("Enable the exec helper, which runs external commands from templates, and the helpers declared in the spec.")
+                        .long("allow-exec"),
+                )
+                .arg(
+                    Arg::with_name("ALLOW_ENV")
+                        .help("Let the env helper read the variables matching PATTERN, e.g. BUILD_*.  May be repeated.")
+                        .long("allow-env")
+                        .value_name("PATTERN")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("FILE_ROOT")
+                        .help("Resolve the paths of file helpers like read_file below DIR instead of each template's directory.")
+                        .long("file-root")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("LOCALE")
+                        .help("Language for the t and plural helpers, e.g. de-DE.")
+                        .long("locale")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("TRANSLATIONS")
+                        .help("Directory of translation catalogs named for their locale, e.g. de-DE.json.")
+                        .long("translations")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("PLUGIN")
+                        .help("Load handlebars helpers from the shared library PATH.  May be repeated.")
+                        .long("plugin")
+                        .value_name("PATH")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("MAX_DEPTH")
+                        .help("Maximum nesting depth of partials.")
+                        .long("max-depth")
+                        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                        .default_value(limits::DEFAULT_MAX_DEPTH),
+                )
+                .arg(
+                    Arg::with_name("MAX_OUTPUT")
+                        .help("Maximum size of each rendered file, in bytes or with a K, M or G suffix.")
+                        .long("max-output")
+                        .validator(|s| limits::parse_size(&s).map(|_| ()))
+                        .default_value(limits::DEFAULT_MAX_OUTPUT),
+                )
                 .arg(
                     Arg::with_name("JOBS")
                         .help("Maximum number of parallel jobs to run.  Default (0) is infinite.")
                         .short("j")
                         .long("max-jobs")
                         .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("MAX_IO")
+                        .help("Maximum number of file reads and writes at a time, across all jobs.  Default (0) is infinite.")
+                        .long("max-io")
+                        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("PROFILE_OUT")
+                        .help("Write the time each entry spends in each phase to FILE, in the Chrome trace format.")
+                        .long("profile-out")
+                        .takes_value(true)
+                        .value_name("FILE"),
                 ),
         )
         .subcommand(
@@ -111,6 +489,15 @@ pub(crate) fn get_parser<'a, 'b>() -> App<'a, 'b> {
                             .long("force")
                             .takes_value(false),
                     )
+                    .arg(
+                        Arg::with_name("STATE")
+                            .help("Check the content digests of the last builds, recorded in FILE or .ttgen-state beside SPEC, instead of mod times.")
+                            .long("state")
+                            .takes_value(true)
+                            .min_values(0)
+                            .require_equals(true)
+                            .value_name("FILE"),
+                    )
                 )
                 .subcommand(SubCommand::with_name("count")
                     .about("report number of templates in SPEC")
@@ -132,11 +519,21 @@ where
     T: Into<OsString> + Clone,
 {
     let matches = a.get_matches_from_safe_borrow(arg_iter)?;
+    if let (_, Some(args)) = matches.subcommand() {
+        if args.value_of("ERROR_FORMAT") == Some("json") {
+            set_json_format();
+        }
+    }
+    // The hash cache and profile are saved even when rendering failed: its
+    // digests are still those of the inputs.
     match matches.subcommand() {
-        ("generate", Some(args)) => generate(args),
-        ("multigen", Some(args)) => multigen(args),
+        ("generate", Some(args)) => generate(args).and(state::save_hash_cache()),
+        ("multigen", Some(args)) => multigen(args)
+            .and(state::save_hash_cache())
+            .and(profile::save()),
         ("report", Some(args)) => report(args),
         ("clean", Some(args)) => clean(args),
+        ("check-template", Some(args)) => check_template(args),
         ("completion", Some(args)) => completion(a, args),
         ("example", _) => example(),
         _ => unimplemented!(),
@@ -165,15 +562,15 @@ fn set_max_jobs(jobs: &str, queued: usize) {
     }
 }
 
-fn box_writer(s: &str) -> Result<Box<dyn Write>> {
-    let writer: Box<dyn Write> = match s {
-        "-" => Box::new(stdout()),
-        other => {
-            let output = PathBuf::from(other);
+fn box_writer(s: &OsStr) -> Result<Box<dyn Write>> {
+    let writer: Box<dyn Write> = match s.to_str() {
+        Some("-") => Box::new(BufWriter::with_capacity(render::OUTPUT_BUFFER, stdout())),
+        _ => {
+            let output = PathBuf::from(s);
             if let Some(p) = &output.parent() {
                 fs::create_dir_all(p)?;
             };
-            Box::new(File::create(output)?)
+            Box::new(render::create_output(output)?)
         }
     };
 
@@ -184,67 +581,498 @@ fn completion(app: &mut App, args: &clap::ArgMatches) -> Result<()> {
     let shell: Shell =
         Shell::from_str(&args.value_of("SHELL").unwrap().to_ascii_uppercase()).unwrap();
     let bin_name = clap::crate_name!();
-    let mut writer = box_writer(args.value_of("OUTPUT").unwrap())?;
+    let mut writer = box_writer(args.value_of_os("OUTPUT").unwrap())?;
     app.gen_completions_to(bin_name, shell, &mut writer);
-    Ok(())
+    Ok(writer.flush()?)
 }
 
 fn clean(args: &clap::ArgMatches) -> Result<()> {
-    let spec_file = args.value_of("SPEC").unwrap();
-    let specs: Vec<TemplateDef> = serde_json::from_reader(File::open(spec_file)?)?;
+    let spec_file = args.value_of_os("SPEC").unwrap();
+    let specs = Spec::load(spec_file)?.templates;
 
     let jobs = args.value_of("JOBS").unwrap_or_default();
     set_max_jobs(jobs, specs.len());
 
-    specs.par_iter().map(|s| &s.output).for_each(|p| {
-        if let Err(e) = fs::remove_file(p) {
-            eprintln!("failed to remove: {}: error: {}", p.display(), e);
+    let console = Console::new(false, specs.len());
+    specs.par_iter().enumerate().for_each(|(i, s)| {
+        let p = &s.output;
+        let removed = if s.template.is_dir() {
+            remove_tree(s)
         } else {
-            println!("removed: {}", p.display());
-        }
+            fs::remove_file(p).within(p.display())
+        };
+        let line = if let Err(e) = removed {
+            let text = format!("failed to remove: {}", e);
+            Line::Err(e.report(text))
+        } else {
+            Line::Out(format!("removed: {}", p.display()))
+        };
+        console.report(i, &s.name, vec![line]);
     });
+    console.finish();
+
+    Ok(())
+}
+
+/// Remove the files rendered from `spec`'s directory template, then the
+/// directories they're in, where that leaves them empty.  Anything else in
+/// the output directory is kept, and files already gone are skipped.
+fn remove_tree(spec: &TemplateDef) -> Result<()> {
+    let outputs = render::tree_outputs(spec, &render::get_renderer())?;
+    let mut dirs = vec![spec.output.clone()];
+    for (path, is_dir) in outputs {
+        if is_dir {
+            dirs.push(path);
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(Error::from(e).within(path.display()))
+            }
+            _ => {}
+        }
+    }
+    for dir in dirs.iter().rev() {
+        // Fails for directories that still hold other files.
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+fn check_template(args: &clap::ArgMatches) -> Result<()> {
+    let mut files = Vec::new();
+    for arg in args.values_of_os("FILE").unwrap() {
+        let path = PathBuf::from(arg);
+        if path.is_dir() {
+            for entry in WalkDir::new(&path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+                let entry = entry.map_err(IOError::from)?;
+                if entry.file_type().is_file() {
+                    files.push(entry.into_path());
+                }
+            }
+        } else {
+            files.push(path);
+        }
+    }
+
+    let failures = files
+        .par_iter()
+        .filter(|p| {
+            let name = p.display().to_string();
+            let checked = match fs::read_to_string(p) {
+                Ok(source) => render::check_source(&name, &source),
+                // Directory templates copy non-UTF-8 files verbatim.
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                    println!("skipped: {} (not UTF-8)", name);
+                    return false;
+                }
+                Err(e) => Err(e.into()),
+            };
+            match checked {
+                Ok(()) => {
+                    println!("ok: {}", name);
+                    false
+                }
+                Err(e) => {
+                    let text = format!("error: {}: {}", name, e);
+                    eprintln!("{}", e.within(&name).report(text));
+                    true
+                }
+            }
+        })
+        .count();
 
+    if failures > 0 {
+        return Err(Failed {
+            count: failures,
+            what: "templates",
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The shared registry, with strict mode as chosen by `--lenient`/`--strict`,
+/// and the instrumentation for `--collect-missing` and `--warn-unused`.
+/// Also applies the `--max-depth` and `--max-output` limits, and registers
+/// `--allow-exec` and `--plugin` helpers, the `--allow-env` variables, the
+/// `--file-root` for the file helpers and the `--locale` catalog.  Sets
+/// the run-wide `--reproducible`, `--hash-cache`, `--build-counter`,
+/// `--git` and `--host-info` settings too, and the extra `context`, with
+/// `--context` values overriding the spec's.
+fn renderer(
+    args: &clap::ArgMatches,
+    mut context: Map<String, Value>,
+) -> Result<handlebars::Handlebars> {
+    let mut hb = render::get_renderer();
+    if args.is_present("LENIENT") {
+        hb.set_strict_mode(false);
+    }
+    if args.is_present("COLLECT_MISSING") {
+        missing::register(&mut hb);
+    }
+    if args.is_present("WARN_UNUSED") {
+        usage::register(&mut hb);
+    }
+    limits::set_max_depth(args.value_of("MAX_DEPTH").unwrap().parse().unwrap());
+    limits::set_max_output(limits::parse_size(args.value_of("MAX_OUTPUT").unwrap()).unwrap());
+    if args.is_present("ALLOW_EXEC") {
+        exec::register(&mut hb);
+    }
+    if let Some(patterns) = args.values_of("ALLOW_ENV") {
+        env::register(&mut hb, patterns.map(String::from).collect());
+    }
+    if args.is_present("REPRODUCIBLE") {
+        render::set_reproducible();
+    }
+    if args.is_present("GIT") {
+        render::set_git(git::metadata(&std::env::current_dir()?)?);
+    }
+    if let Some(path) = args.value_of_os("HASH_CACHE") {
+        state::load_hash_cache(Path::new(path));
+    }
+    if let Some(path) = args.value_of_os("BUILD_COUNTER") {
+        render::set_build_id(state::next_build_id(Path::new(path))?);
+    }
+    if args.is_present("HOST_INFO") {
+        render::set_host(host::metadata()?);
+    }
+    if let Some(path) = args.value_of_os("CONTEXT").map(Path::new) {
+        match serde_json::from_reader(File::open(path)?)? {
+            Value::Object(values) => context.extend(values),
+            _ => {
+                let msg = format!("{}: context must be a JSON object", path.display());
+                return Err(IOError::new(ErrorKind::InvalidData, msg).into());
+            }
+        }
+    }
+    if !context.is_empty() {
+        render::set_context(context);
+    }
+    if args.is_present("LOCALE") {
+        let dir = args.value_of_os("TRANSLATIONS").map(Path::new);
+        let catalog = i18n::Catalog::load(dir, args.value_of("LOCALE"))?;
+        i18n::register(&mut hb, catalog);
+    }
+    if let Some(root) = args.value_of_os("FILE_ROOT") {
+        files::register(&mut hb, Some(root.into()));
+    }
+    for path in args.values_of_os("PLUGIN").into_iter().flatten() {
+        let names = plugin::load(&mut hb, Path::new(path))?;
+        debug!("{:?}: registered helpers {:?}", path, names);
+    }
+    Ok(hb)
+}
+
+/// Register the helpers declared in a spec: shared libraries as plugins, and
+/// anything else as a script run like the `exec` helper.  Either runs code
+/// named by the spec, so both need `allow_exec`.
+fn register_helpers(
+    hb: &mut handlebars::Handlebars,
+    helpers: &BTreeMap<String, PathBuf>,
+    allow_exec: bool,
+) -> Result<()> {
+    for (name, path) in helpers {
+        if !allow_exec {
+            let msg = format!(
+                "helper {:?} declared in the spec runs {}; pass --allow-exec to allow it",
+                name,
+                path.display()
+            );
+            return Err(PluginError::from(msg).into());
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("so") | Some("dylib") | Some("dll") => plugin::load_helper(hb, path, name)?,
+            _ => exec::register_script(hb, name, path),
+        }
+    }
     Ok(())
 }
 
 fn generate(args: &clap::ArgMatches) -> Result<()> {
     // Unwrap due to parser guarantees.
-    let data = args.value_of("DATA").unwrap();
-    let template = args.value_of("TEMPLATE").unwrap();
-    let output = args.value_of("OUTPUT").unwrap();
-    let mut out_writer = box_writer(output)?;
-    let spec = TemplateDef::new("Anonymous", data, template, output)?;
-    let hb = render::get_renderer();
-    render::with_writer(&spec, &hb, &mut out_writer)
+    let data = args.value_of_os("DATA").unwrap();
+    let template = args.value_of_os("TEMPLATE").unwrap();
+    let output = args.value_of_os("OUTPUT").unwrap();
+    let engine = Engine::from_str(args.value_of("ENGINE").unwrap()).unwrap();
+    let whitespace = Whitespace {
+        trim_blocks: args.is_present("TRIM_BLOCKS"),
+        lstrip_blocks: args.is_present("LSTRIP_BLOCKS"),
+    };
+    let hash = if args.is_present("NO_HASH") {
+        HashAlgorithm::None
+    } else {
+        HashAlgorithm::default()
+    };
+    let mut hb = renderer(args, Map::new())?;
+
+    if Path::new(template).is_dir() {
+        if output == "-" {
+            let msg = "a directory TEMPLATE needs an OUTPUT directory";
+            return Err(IOError::new(ErrorKind::InvalidInput, msg).into());
+        }
+        if args.is_present("TRACE") {
+            warn!("--trace is not supported for directory templates");
+        }
+        let spec = TemplateDef::new("Anonymous", data, template, output)?
+            .with_engine(engine)
+            .with_whitespace(whitespace)
+            .with_hash(hash);
+        return render::with(&spec, &hb);
+    }
+
+    let mut source = String::new();
+    let mut spec = if template == "-" {
+        let spec = TemplateDef::new_unchecked(
+            "Anonymous".into(),
+            data.into(),
+            template.into(),
+            output.into(),
+        );
+        spec.validate_data()?;
+        stdin().read_to_string(&mut source)?;
+        spec
+    } else {
+        let spec = TemplateDef::new("Anonymous", data, template, output)?;
+        File::open(&spec.template)?.read_to_string(&mut source)?;
+        spec
+    }
+    .with_engine(engine)
+    .with_whitespace(whitespace)
+    .with_hash(hash);
+
+    // An explicit OUTPUT wins over one declared in the template's front matter.
+    if args.occurrences_of("OUTPUT") == 0 {
+        if let Some(p) = render::front_matter_output(&spec, &source, &hb)? {
+            spec.output = p;
+        }
+    }
+
+    let mut out_writer = box_writer(spec.output.as_os_str())?;
+    if args.is_present("TRACE") {
+        let sink: Box<dyn Write + Send> = match args.value_of_os("TRACE") {
+            Some(file) => Box::new(File::create(file)?),
+            None => Box::new(stderr()),
+        };
+        if engine != Engine::Handlebars {
+            warn!("--trace only applies to the handlebars engine");
+        }
+        trace::register(&mut hb, Arc::new(Mutex::new(sink)));
+    }
+    render::with_source_writer(&spec, &source, &hb, &mut out_writer)?;
+    Ok(out_writer.flush()?)
+}
+
+/// The wait before the first retry of a failed entry, doubled for each
+/// retry up to 64 times as long.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Options that change what multigen renders, recorded in the build state.
+const RENDER_OPTIONS: [&str; 14] = [
+    "LENIENT",
+    "NO_HASH",
+    "REPRODUCIBLE",
+    "HOST_INFO",
+    "CONTEXT",
+    "GIT",
+    "ALLOW_EXEC",
+    "ALLOW_ENV",
+    "FILE_ROOT",
+    "LOCALE",
+    "TRANSLATIONS",
+    "PLUGIN",
+    "MAX_DEPTH",
+    "MAX_OUTPUT",
+];
+
+/// The `RENDER_OPTIONS` that name files, recorded with the digests of the
+/// files so that editing one rebuilds the entries.
+const FILE_OPTIONS: [&str; 3] = ["CONTEXT", "TRANSLATIONS", "PLUGIN"];
+
+/// `RENDER_OPTIONS` that name directories, recorded as paths.
+const PATH_OPTIONS: [&str; 1] = ["FILE_ROOT"];
+
+/// `path` with the digest of its contents.  Scripts and libraries found on
+/// the search path rather than at `path` have none.
+fn file_value(path: &Path) -> Value {
+    json!({
+        "path": os_path::to_value(path),
+        "digest": render::digest(path).ok(),
+    })
+}
+
+/// The `--state` file for `spec_file`, if any.
+fn state_file(args: &clap::ArgMatches, spec_file: &OsStr) -> Option<PathBuf> {
+    if !args.is_present("STATE") {
+        return None;
+    }
+    let path = match args.value_of_os("STATE") {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(spec_file).with_file_name(".ttgen-state"),
+    };
+    Some(path)
+}
+
+/// The spec settings and `RENDER_OPTIONS` given to a multigen run, with the
+/// contents of the files they name and the values `--git` and `--host-info`
+/// add, so that changes to any of them rebuild the entries.
+fn render_options(args: &clap::ArgMatches, spec: &Spec) -> Value {
+    let mut options = Map::new();
+    for &name in RENDER_OPTIONS.iter().filter(|&&name| args.is_present(name)) {
+        let values = args.values_of_lossy(name).unwrap_or_default();
+        let value = if FILE_OPTIONS.contains(&name) {
+            let paths = args.values_of_os(name).into_iter().flatten();
+            Value::from(paths.map(|p| file_value(Path::new(p))).collect::<Vec<_>>())
+        } else if PATH_OPTIONS.contains(&name) {
+            let paths = args.values_of_os(name).into_iter().flatten();
+            Value::from(paths.map(|p| os_path::to_value(Path::new(p))).collect::<Vec<_>>())
+        } else if values.is_empty() {
+            Value::Bool(true)
+        } else {
+            Value::from(values)
+        };
+        options.insert(name.to_lowercase(), value);
+    }
+    if let Some(git) = render::git() {
+        options.insert("git".to_string(), git.clone());
+    }
+    if let Some(host) = render::host() {
+        options.insert("host_info".to_string(), Value::Object(host.clone()));
+    }
+    // Apart from the options, whose `context` is the `--context` file.
+    let helpers: Map<String, Value> = spec
+        .helpers
+        .iter()
+        .map(|(name, path)| (name.clone(), file_value(path)))
+        .collect();
+    let settings = json!({
+        "context": spec.context,
+        "helpers": helpers,
+        "siblings": spec.siblings,
+    });
+    options.insert("spec".to_string(), settings);
+    Value::Object(options)
+}
+
+/// Whether `spec` is out of date: by the builds recorded in `state`, if
+/// given, or else by mod times.
+fn needs_build(spec: &TemplateDef, state: Option<&BuildState>) -> bool {
+    match state {
+        Some(state) => !state.is_current(spec),
+        None => spec.should_build(),
+    }
 }
 
 fn multigen(args: &clap::ArgMatches) -> Result<()> {
-    let spec_file = args.value_of("SPEC").unwrap();
-    let specs: Vec<TemplateDef> = serde_json::from_reader(File::open(spec_file)?)?;
-    let hb = render::get_renderer();
+    let spec_file = args.value_of_os("SPEC").unwrap();
+    let _lock = if args.is_present("NO_LOCK") {
+        None
+    } else {
+        Some(lock::lock(Path::new(spec_file))?)
+    };
+    if let Some(path) = args.value_of_os("PROFILE_OUT") {
+        profile::start(Path::new(path));
+    }
+    let spec = profile::span("spec load", &Path::new(spec_file).display(), || {
+        Spec::load(spec_file)
+    })?;
+    let mut hb = renderer(args, spec.context.clone())?;
+    register_helpers(&mut hb, &spec.helpers, args.is_present("ALLOW_EXEC"))?;
+    let state = match state_file(args, spec_file) {
+        Some(path) => Some(BuildState::load(&path, Some(render_options(args, &spec)))?),
+        None => None,
+    };
+    let mut specs = spec.templates;
+    // Entries rendering into the same file in parallel leave it garbled.
+    let duplicates = render::duplicate_outputs(&specs);
+    if args.is_present("ALLOW_DUPLICATE_OUTPUTS") {
+        duplicates.iter().for_each(|d| eprintln!("warning: {}", d));
+    } else if !duplicates.is_empty() {
+        let msg = format!("duplicate outputs:\n{}", duplicates.join("\n"));
+        return Err(IOError::new(ErrorKind::InvalidInput, msg).into());
+    }
+    if args.is_present("NO_HASH") {
+        specs.iter_mut().for_each(|s| s.hash = HashAlgorithm::None);
+    }
+    if let Some(fields) = &spec.siblings {
+        render::set_siblings(&specs, fields);
+    }
 
     let force = args.is_present("FORCE");
+    let timeout = args
+        .value_of("TIMEOUT")
+        .map(|s| Duration::from_secs_f64(s.parse().unwrap()));
 
     let jobs = args.value_of("JOBS").unwrap_or_default();
     set_max_jobs(jobs, specs.len());
+    limits::set_max_io(args.value_of("MAX_IO").unwrap().parse().unwrap());
 
-    specs
+    let console = Console::new(args.is_present("ORDERED"), specs.len());
+    let (pending, skipped): (Vec<_>, Vec<_>) = specs
         .par_iter()
-        .filter_map(|s: &TemplateDef| {
-            if force || s.should_build() {
-                Some((render::with(s, &hb), s))
-            } else {
-                println!("skipped: {}", &s.name);
-                None
+        .enumerate()
+        .partition(|(_, s)| force || needs_build(s, state.as_ref()));
+    for (i, s) in skipped {
+        console.report(i, &s.name, vec![Line::Out(format!("skipped: {}", &s.name))]);
+    }
+
+    let templates = render::TemplateCache::build(pending.iter().map(|&(_, s)| s), &hb);
+    let data = render::DataCache::build(pending.iter().map(|&(_, s)| s));
+
+    // Start the largest inputs first, so no slow render is left running
+    // alone at the end.
+    let mut pending: Vec<_> = pending
+        .into_par_iter()
+        .map(|(i, s)| (Reverse(s.input_size()), i, s))
+        .collect();
+    pending.sort_by_key(|&(size, i, _)| (size, i));
+    let failures = AtomicUsize::new(0);
+    let fail_fast = args.is_present("FAIL_FAST");
+    let retries: u32 = args.value_of("RETRIES").unwrap().parse().unwrap();
+    limits::in_order(&pending, |&(_, i, s)| {
+        if fail_fast && failures.load(Ordering::Relaxed) > 0 {
+            let line = Line::Out(format!("not attempted: {}", s.name));
+            return console.report(i, &s.name, vec![line]);
+        }
+        let mut lines = Vec::new();
+        let mut attempt = 0;
+        let rendered = loop {
+            let rendered =
+                limits::with_timeout(timeout, || render::with_cache(s, &templates, &data, &hb));
+            match rendered.within(&s.name) {
+                Err(e) if attempt < retries && e.is_transient() => {
+                    lines.push(Line::Err(format!("retrying: {}", e)));
+                    std::thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt.min(6)));
+                    attempt += 1;
+                }
+                rendered => break rendered,
             }
-        })
-        .for_each(|(r, s)| {
-            if let Err(e) = r {
-                eprintln!("error: {}: {}", s.name, e);
-            } else {
-                println!("success: {}", s.name);
+        };
+        let line = if let Err(e) = rendered {
+            failures.fetch_add(1, Ordering::Relaxed);
+            Line::Err(e.report(format!("error: {}", e)))
+        } else {
+            if let Some(Err(e)) = state.as_ref().map(|state| state.record(s)) {
+                warn!("{}: not recorded in the build state: {}", s.name, e);
             }
-        });
+            Line::Out(format!("success: {}", s.name))
+        };
+        lines.push(line);
+        console.report(i, &s.name, lines);
+    });
+    console.finish();
+    if let Some(state) = state {
+        state.save()?;
+    }
+
+    let failures = failures.into_inner();
+    if failures > 0 {
+        return Err(Failed {
+            count: failures,
+            what: "entries",
+        }
+        .into());
+    }
     Ok(())
 }
 
@@ -254,8 +1082,8 @@ fn report(upper_args: &clap::ArgMatches) -> Result<()> {
         _ => unreachable!()
     };
 
-    let spec_file = args.value_of("SPEC").unwrap();
-    let specs: Vec<TemplateDef> = serde_json::from_reader(File::open(spec_file)?)?;
+    let spec_file = args.value_of_os("SPEC").unwrap();
+    let specs = Spec::load(spec_file)?.templates;
     let force = args.is_present("FORCE");
 
     let jobs = args.value_of("JOBS").unwrap_or_default();
@@ -263,15 +1091,27 @@ fn report(upper_args: &clap::ArgMatches) -> Result<()> {
 
     match name {
         "clean" => {
-            specs.par_iter().map(|s| &s.output).for_each(|p| {
+            let hb = render::get_renderer();
+            let removable = |s: &TemplateDef| -> Vec<PathBuf> {
+                if !s.template.is_dir() {
+                    return vec![s.output.clone()];
+                }
+                let outputs = render::tree_outputs(s, &hb).unwrap_or_default();
+                outputs.into_iter().filter(|(_, dir)| !dir).map(|(p, _)| p).collect()
+            };
+            specs.par_iter().flat_map(removable).for_each(|p| {
                 if p.exists() {
                     println!("Would remove: {}", p.display());
                 }
             });
         },
         "multigen" => {
+            let state = match state_file(args, spec_file) {
+                Some(path) => Some(BuildState::load(&path, None)?),
+                None => None,
+            };
             specs.par_iter().for_each(|s| {
-                if force || s.should_build() {
+                if force || needs_build(s, state.as_ref()) {
                     println!("Would build: {}", s.output.display());
                 } else {
                     println!("Would skip: {}", s.output.display());
@@ -288,4 +1128,94 @@ fn report(upper_args: &clap::ArgMatches) -> Result<()> {
 fn example() -> Result<()> {
     println!("{}", include_str!("example.json"));
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spec_helpers_need_allow_exec() {
+        let mut helpers = BTreeMap::new();
+        helpers.insert("shout".to_string(), PathBuf::from("scripts/shout.sh"));
+        helpers.insert("fmt".to_string(), PathBuf::from("libfmt.so"));
+        let mut hb = render::get_renderer();
+
+        let err = register_helpers(&mut hb, &helpers, false).unwrap_err();
+        assert!(err.to_string().contains("--allow-exec"), "{}", err);
+        assert!(hb.get_helper("shout").is_none() && hb.get_helper("fmt").is_none());
+
+        helpers.remove("fmt");
+        register_helpers(&mut hb, &helpers, true).unwrap_or_else(|e| panic!("{}", e));
+        assert!(hb.get_helper("shout").is_some());
+    }
+
+    #[test]
+    fn cleans_only_rendered_files() {
+        let dir = std::env::temp_dir().join(format!("ttgen-clean-{}", std::process::id()));
+        let file = |name: &str| {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "x").unwrap();
+            path
+        };
+        file("template/a.txt");
+        file("template/{{root.x}}/b.txt");
+        fs::write(dir.join("data.json"), "{\"x\": \"d\"}").unwrap();
+        let spec = TemplateDef::new_unchecked(
+            "tree".into(),
+            dir.join("data.json"),
+            dir.join("template"),
+            dir.join("out"),
+        );
+
+        let outputs = [file("out/a.txt"), file("out/d/b.txt")];
+        let kept = [file("out/keep.txt"), file("out/d/keep.txt")];
+        remove_tree(&spec).unwrap_or_else(|e| panic!("{}", e));
+        assert!(outputs.iter().all(|p| !p.exists()));
+        assert!(kept.iter().all(|p| p.exists()));
+
+        kept.iter().for_each(|p| fs::remove_file(p).unwrap());
+        file("out/a.txt");
+        remove_tree(&spec).unwrap_or_else(|e| panic!("{}", e));
+        assert!(!dir.join("out").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_follows_context_files() {
+        let dir = std::env::temp_dir().join(format!("ttgen-options-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, text: &str| {
+            let path = dir.join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+        let context = file("context.json", "{\"a\": 1}");
+        let spec = Spec::default();
+        let entry = TemplateDef::new_unchecked(
+            "example".into(),
+            file("data.json", "{}"),
+            file("template.hbs", "x"),
+            file("output.txt", "x"),
+        );
+        let options = || {
+            let argv = vec!["ttgen".as_ref(), "multigen".as_ref(), "--context".as_ref()];
+            let argv = argv.into_iter().chain(vec![context.as_os_str(), "spec.json".as_ref()]);
+            let matches = get_parser().get_matches_from(argv);
+            render_options(matches.subcommand_matches("multigen").unwrap(), &spec)
+        };
+        let path = dir.join("state.json");
+        let load = || BuildState::load(&path, Some(options())).unwrap_or_else(|e| panic!("{}", e));
+
+        let state = load();
+        state.record(&entry).unwrap_or_else(|e| panic!("{}", e));
+        state.save().unwrap_or_else(|e| panic!("{}", e));
+        assert!(load().is_current(&entry));
+        file("context.json", "{\"a\": 10}");
+        assert!(!load().is_current(&entry));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Per-entry status lines for multigen and clean.
+//!
+//! Each entry's lines are written together, under one lock of stdout and
+//! stderr, so parallel entries never interleave.  With `--ordered` they are
+//! also held back until every earlier entry of the spec has reported, so the
+//! log follows the spec whatever order the entries finish in.
+//!
+//! When stderr is a terminal, a progress line below the log shows how many
+//! entries are done, the last one finished and the estimated time left.
+
+use std::collections::BTreeMap;
+use std::io::{stderr, stdout, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Longest entry name shown in the progress line, so it doesn't wrap.
+const PROGRESS_NAME: usize = 40;
+const PROGRESS_BAR: usize = 20;
+
+pub enum Line {
+    Out(String),
+    Err(String),
+}
+
+struct State {
+    /// The next entry to report, when ordered, and the later ones that
+    /// already finished.
+    next: usize,
+    finished: BTreeMap<usize, Vec<Line>>,
+    done: usize,
+}
+
+pub struct Console {
+    ordered: bool,
+    total: usize,
+    /// When the progress line is shown, the start of the run.
+    progress: Option<Instant>,
+    state: Mutex<State>,
+}
+
+#[cfg(unix)]
+fn stderr_is_tty() -> bool {
+    // SAFETY: isatty only inspects the descriptor.
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+fn stderr_is_tty() -> bool {
+    false
+}
+
+impl Console {
+    /// A console for `total` entries.
+    pub fn new(ordered: bool, total: usize) -> Self {
+        Console {
+            ordered,
+            total,
+            progress: if stderr_is_tty() {
+                Some(Instant::now())
+            } else {
+                None
+            },
+            state: Mutex::new(State {
+                next: 0,
+                finished: BTreeMap::new(),
+                done: 0,
+            }),
+        }
+    }
+
+    /// Report the `lines` of the entry `name` at `index` in the spec.  When
+    /// ordered, every entry must report exactly once.
+    pub fn report(&self, index: usize, name: &str, lines: Vec<Line>) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.done += 1;
+        if self.progress.is_some() {
+            eprint!("\r\x1b[K");
+        }
+        if self.ordered {
+            state.finished.insert(index, lines);
+            while let Some(lines) = state.finished.remove(&state.next) {
+                write(&lines);
+                state.next += 1;
+            }
+        } else {
+            write(&lines);
+        }
+        if let Some(start) = self.progress {
+            eprint!(
+                "{}",
+                progress(state.done, self.total, start.elapsed(), name)
+            );
+        }
+    }
+
+    /// Clear the progress line once every entry has reported.
+    pub fn finish(&self) {
+        if self.progress.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+fn write(lines: &[Line]) {
+    let (out, err) = (stdout(), stderr());
+    let (mut out, mut err) = (out.lock(), err.lock());
+    for line in lines {
+        // Unlike println!, don't panic mid-run if a stream was closed.
+        let _ = match line {
+            Line::Out(s) => writeln!(out, "{}", s),
+            Line::Err(s) => writeln!(err, "{}", s),
+        };
+    }
+    let _ = out.flush();
+}
+
+/// The progress line after `done` of `total` entries took `elapsed`, the
+/// last being `name`.
+fn progress(done: usize, total: usize, elapsed: Duration, name: &str) -> String {
+    let filled = (done * PROGRESS_BAR)
+        .checked_div(total)
+        .unwrap_or(PROGRESS_BAR);
+    let bar = format!(
+        "{}{}",
+        "#".repeat(filled),
+        "-".repeat(PROGRESS_BAR - filled.min(PROGRESS_BAR))
+    );
+    let left = elapsed.mul_f64(total.saturating_sub(done) as f64 / done.max(1) as f64);
+    let secs = left.as_secs();
+    let name: String = name.chars().take(PROGRESS_NAME).collect();
+    format!(
+        "[{}] {}/{} ETA {}:{:02} {}",
+        bar,
+        done,
+        total,
+        secs / 60,
+        secs % 60,
+        name
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_progress() {
+        assert_eq!(
+            progress(5, 20, Duration::from_secs(10), "docs"),
+            "[#####---------------] 5/20 ETA 0:30 docs"
+        );
+        let long = "x".repeat(100);
+        let line = progress(20, 20, Duration::from_secs(600), &long);
+        assert!(line.starts_with("[####################] 20/20 ETA 0:00 xxx"));
+        assert_eq!(line.len(), 38 + PROGRESS_NAME);
+    }
+}
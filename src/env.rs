@@ -0,0 +1,102 @@
+//! The `env` helper, limited to the variables allowed with `--allow-env`.
+//!
+//! `{{env "BUILD_NUMBER"}}` is the value of the variable at render time, or
+//! null if it isn't set, so `{{default (env "BUILD_NUMBER") "dev"}}` gives a
+//! fallback.  Reading a variable no `--allow-env` pattern matches is an
+//! error.  Patterns are names, optionally with `*` wildcards: `BUILD_*`.
+
+use std::env;
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value;
+
+pub const HELPER_NAME: &str = "env";
+
+struct EnvHelper {
+    allowed: Vec<String>,
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // No wildcard: the whole name must match.
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl HelperDef for EnvHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let name = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("env helper expects a variable name"))?;
+        if !self.allowed.iter().any(|p| matches(p, name)) {
+            let msg = format!("env {}: not allowed, see --allow-env", name);
+            return Err(RenderError::new(msg));
+        }
+        let value = match env::var(name) {
+            Ok(v) => Value::String(v),
+            Err(env::VarError::NotPresent) => Value::Null,
+            Err(e) => return Err(RenderError::new(format!("env {}: {}", name, e))),
+        };
+        Ok(Some(ScopedJson::Derived(value)))
+    }
+}
+
+/// Register `env`, allowing the variables matching `allowed`.
+pub fn register(hb: &mut Handlebars, allowed: Vec<String>) {
+    hb.register_helper(HELPER_NAME, Box::new(EnvHelper { allowed }));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("BUILD_NUMBER", "BUILD_NUMBER"));
+        assert!(!matches("BUILD", "BUILD_NUMBER"));
+        assert!(matches("BUILD_*", "BUILD_NUMBER"));
+        assert!(matches("*_ID", "CI_JOB_ID"));
+        assert!(matches("CI_*_ID", "CI_JOB_ID"));
+        assert!(!matches("CI_*_ID", "CI_ID"));
+        assert!(matches("*", "HOME"));
+    }
+
+    #[test]
+    fn reads_allowed_variables_only() {
+        let mut hb = Handlebars::new();
+        register(
+            &mut hb,
+            vec!["CARGO_PKG_*".to_string(), "TTGEN_UNSET".to_string()],
+        );
+        let rendered = hb.render_template("{{env \"CARGO_PKG_NAME\"}}", &());
+        assert_eq!(rendered.unwrap(), "ttgen");
+        let rendered = hb.render_template("[{{env \"TTGEN_UNSET\"}}]", &());
+        assert_eq!(rendered.unwrap(), "[]");
+
+        let err = hb.render_template("{{env \"PATH\"}}", &()).unwrap_err();
+        assert!(err.to_string().contains("not allowed"), "{}", err);
+    }
+}
@@ -1,9 +1,13 @@
-use std::fmt::{Display, Error as FmtError, Formatter};
-use std::io::Error as IOError;
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Error as FmtError, Formatter};
+use std::io::{Error as IOError, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use clap::Error as ClapError;
 use handlebars::{RenderError, TemplateRenderError};
-use serde_json::Error as JSONError;
+use serde_json::{json, Error as JSONError, Value};
+
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
 
 macro_rules! error_impl {
     ( $( $x:ident ),* ) => {
@@ -30,9 +34,33 @@ macro_rules! error_impl {
                 }
             }
         }
+
+        impl Debug for TTGenError {
+            fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+                match self {
+                    $(
+                        TTGenError::$x(err) => Debug::fmt(err, f),
+                    )*
+                }
+            }
+        }
+
+        // Transparent: the wrapped error is this error, so its source is
+        // this error's source.
+        impl StdError for TTGenError {
+            fn source(&self) -> Option<&(dyn StdError + 'static)> {
+                match self {
+                    $(
+                        TTGenError::$x(err) => err.source(),
+                    )*
+                }
+            }
+        }
     };
 }
 
+/// Inputs that are missing or can't be used, with why for each.
+#[derive(Debug)]
 pub struct Missing(Vec<String>);
 
 impl From<Vec<String>> for Missing {
@@ -41,23 +69,334 @@ impl From<Vec<String>> for Missing {
     }
 }
 
+impl StdError for Missing {}
+
 impl Display for Missing {
     fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
         for msg in &self.0 {
-            writeln!(f, "missing file: {}", msg)?;
+            writeln!(f, "{}", msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every missing variable found by a `--collect-missing` render.
+#[derive(Debug)]
+pub struct Undefined(Vec<String>);
+
+impl From<Vec<String>> for Undefined {
+    fn from(v: Vec<String>) -> Self {
+        Self(v)
+    }
+}
+
+impl StdError for Undefined {}
+
+impl Display for Undefined {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        for msg in &self.0 {
+            writeln!(f, "missing variable: {}", msg)?;
         }
         Ok(())
     }
 }
 
+#[derive(Debug)]
+pub struct SubstError(String);
+
+impl From<String> for SubstError {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl StdError for SubstError {}
+
+impl Display for SubstError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        write!(f, "substitution error: {}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct FrontMatterError(String);
+
+impl From<String> for FrontMatterError {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl StdError for FrontMatterError {}
+
+impl Display for FrontMatterError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        write!(f, "front matter error: {}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct PluginError(String);
+
+impl From<String> for PluginError {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl StdError for PluginError {}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        write!(f, "plugin error: {}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct TranslationError(String);
+
+impl From<String> for TranslationError {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl StdError for TranslationError {}
+
+impl Display for TranslationError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        write!(f, "translation error: {}", self.0)
+    }
+}
+
+/// A template error with its location and the surrounding source lines.
+#[derive(Debug)]
+pub struct SourceError {
+    /// The code of the error that was located.
+    pub code: &'static str,
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub snippet: String,
+}
+
+impl StdError for SourceError {}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, " --> {}:{}:{}", self.name, self.line, self.column)?;
+        write!(f, "{}", self.snippet.trim_end())
+    }
+}
+
+/// Summary of a batch command where some items failed; details are printed as they occur.
+#[derive(Debug)]
+pub struct Failed {
+    pub count: usize,
+    pub what: &'static str,
+}
+
+impl StdError for Failed {}
+
+impl Display for Failed {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        write!(f, "{} {} failed", self.count, self.what)
+    }
+}
+
+/// An error with the file or entry it happened for.
+///
+/// By design its message is the context followed by the wrapped error's
+/// message, since most of ttgen prints errors with `{}` alone.  Its source
+/// is then the wrapped error's source rather than the wrapped error, so
+/// reporters walking the chain still print each message once.  Use
+/// `context` and `source` directly, or `TTGenError::to_json`, to take the
+/// layers apart.
+#[derive(Debug)]
+pub struct Within {
+    pub context: String,
+    pub source: TTGenError,
+}
+
+impl StdError for Within {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source.source()
+    }
+}
+
+impl Display for Within {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+/// Report errors as JSON objects, for `--error-format json`.
+pub fn set_json_format() {
+    JSON_FORMAT.store(true, Ordering::Relaxed);
+}
+
+impl TTGenError {
+    /// The stable code of this kind of error, for tools that classify
+    /// failures.  Codes are never reused for another kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TTGenError::Missing(_) => "E001",
+            TTGenError::TemplateRenderError(_) => "E002",
+            TTGenError::RenderError(_) => "E003",
+            TTGenError::JSONError(_) => "E004",
+            TTGenError::Undefined(_) => "E005",
+            TTGenError::SubstError(_) => "E006",
+            TTGenError::FrontMatterError(_) => "E007",
+            TTGenError::PluginError(_) => "E008",
+            TTGenError::TranslationError(_) => "E009",
+            TTGenError::IOError(_) => "E010",
+            TTGenError::ClapError(_) => "E011",
+            TTGenError::Failed(_) => "E012",
+            TTGenError::SourceError(e) => e.code,
+            TTGenError::Within(e) => e.source.code(),
+        }
+    }
+
+    /// The code, the message and the files or entries it happened for,
+    /// outermost first, with the location of template errors.
+    pub fn to_json(&self) -> Value {
+        let mut context = Vec::new();
+        let mut err = self;
+        while let TTGenError::Within(within) = err {
+            context.push(within.context.clone());
+            err = &within.source;
+        }
+        let mut value = json!({
+            "code": err.code(),
+            "message": err.to_string(),
+            "context": context,
+        });
+        if let TTGenError::SourceError(e) = err {
+            value["message"] = Value::from(e.message.clone());
+            value["file"] = Value::from(e.name.clone());
+            value["line"] = Value::from(e.line);
+            value["column"] = Value::from(e.column);
+        }
+        value
+    }
+
+    /// Whether this is an I/O error that may not happen again, such as a
+    /// failed read from a network share.  Missing files, bad input, denied
+    /// access and timed out renders fail the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TTGenError::IOError(e) => !matches!(
+                e.kind(),
+                ErrorKind::NotFound
+                    | ErrorKind::PermissionDenied
+                    | ErrorKind::AlreadyExists
+                    | ErrorKind::IsADirectory
+                    | ErrorKind::InvalidInput
+                    | ErrorKind::InvalidData
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Unsupported
+            ),
+            TTGenError::Within(e) => e.source.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// `text`, or this error as one line of JSON with `--error-format json`.
+    pub fn report(&self, text: String) -> String {
+        if JSON_FORMAT.load(Ordering::Relaxed) {
+            self.to_json().to_string()
+        } else {
+            text
+        }
+    }
+
+    /// This error, as one that happened for `context`.
+    pub fn within<D: Display>(self, context: D) -> Self {
+        Within {
+            context: context.to_string(),
+            source: self,
+        }
+        .into()
+    }
+}
+
+/// `TTGenError::within` for results.
+pub trait ResultExt<T> {
+    fn within<D: Display>(self, context: D) -> Result<T>;
+}
+
+impl<T, E: Into<TTGenError>> ResultExt<T> for std::result::Result<T, E> {
+    fn within<D: Display>(self, context: D) -> Result<T> {
+        self.map_err(|e| e.into().within(context))
+    }
+}
+
 error_impl!(
     IOError,
     RenderError,
     JSONError,
     TemplateRenderError,
     ClapError,
-    Missing
+    Missing,
+    Undefined,
+    SubstError,
+    FrontMatterError,
+    SourceError,
+    PluginError,
+    TranslationError,
+    Failed,
+    Within
 );
 
 pub type Error = TTGenError;
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chains_sources() {
+        let io = IOError::new(ErrorKind::NotFound, "gone");
+        let err = Err::<(), _>(io).within("data.json").unwrap_err();
+        let err = err.within("entry");
+        assert_eq!(err.to_string(), "entry: data.json: gone");
+        assert!(format!("{:?}", err).contains("data.json"));
+
+        // As printed by reporters that walk the chain, like anyhow's `{:#}`.
+        let mut chain = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(e) = source {
+            chain.push(e.to_string());
+            source = e.source();
+        }
+        assert_eq!(chain, ["entry: data.json: gone"]);
+    }
+
+    #[test]
+    fn tells_transient_errors() {
+        let io = |kind| TTGenError::from(IOError::new(kind, "failed")).within("data.json");
+        assert!(io(ErrorKind::Interrupted).is_transient());
+        assert!(io(ErrorKind::Other).is_transient());
+        assert!(!io(ErrorKind::NotFound).is_transient());
+        assert!(!TTGenError::from(RenderError::new("bad")).is_transient());
+    }
+
+    #[test]
+    fn reports_codes_and_context_as_json() {
+        let err = TTGenError::from(Missing::from(vec!["missing data file: a.json".to_string()]))
+            .within("data")
+            .within("entry");
+        assert_eq!(err.code(), "E001");
+        assert_eq!(
+            err.to_json(),
+            json!({
+                "code": "E001",
+                "message": "missing data file: a.json\n",
+                "context": ["entry", "data"],
+            })
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! The `exec` helper, enabled with `--allow-exec`.
+//!
+//! `{{exec "cmd" "arg" ... input=text}}` runs `cmd` with the remaining
+//! parameters as its arguments, writes `input` to its stdin and inserts its
+//! stdout.  As a block, `{{#exec "pandoc" "-t" "html"}}...{{/exec}}` pipes the
+//! rendered block instead.  Trailing newlines are dropped from the output, as
+//! with shell command substitution.  A command still running when the
+//! render's `--timeout` passes is killed.
+//!
+//! Script helpers declared in a spec's `helpers` section work the same way,
+//! with the script as the command and every parameter as an argument.
+
+use std::ffi::OsString;
+use std::io::{Read, Result as IOResult, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, Output, RenderContext,
+    RenderError, Renderable,
+};
+
+use crate::limits;
+use crate::trace::WriteOutput;
+
+pub const HELPER_NAME: &str = "exec";
+
+struct ExecHelper {
+    /// The command to run, or `None` to take it from the first parameter.
+    program: Option<OsString>,
+}
+
+impl HelperDef for ExecHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let mut params = h.params().iter().map(|p| p.value().render());
+        let program = match self.program {
+            Some(ref program) => program.clone(),
+            None => params
+                .next()
+                .ok_or_else(|| RenderError::new("exec helper missing command"))?
+                .into(),
+        };
+        let args: Vec<String> = params.collect();
+
+        let input = match h.template() {
+            Some(t) => {
+                let mut block = WriteOutput(Vec::new());
+                t.render(r, ctx, rc, &mut block)?;
+                block.0
+            }
+            None => h
+                .hash_get("input")
+                .map(|v| v.value().render().into_bytes())
+                .unwrap_or_default(),
+        };
+
+        let shown = Path::new(&program).display();
+        let fail = |e: std::io::Error| RenderError::new(format!("exec {}: {}", shown, e));
+        let mut child = Command::new(&program)
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(fail)?;
+
+        // Feed stdin from another thread, so a command that writes before it
+        // has read everything can't fill its stdout pipe and deadlock.
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let stdout = read_all(child.stdout.take().unwrap());
+        let stderr = read_all(child.stderr.take().unwrap());
+        // Past the deadline the threads are left behind, in case something
+        // the command started still holds its pipes open.
+        let status = limits::wait(&mut child).map_err(fail)?;
+        // A command that exits without reading its input is fine.
+        let _ = writer.join();
+        let (stdout, stderr) = (joined(stdout).map_err(fail)?, joined(stderr).map_err(fail)?);
+
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            let msg = format!("exec {}: {}: {}", shown, status, stderr.trim_end());
+            return Err(RenderError::new(msg));
+        }
+        let stdout = String::from_utf8_lossy(&stdout);
+        out.write(stdout.trim_end_matches(&['\r', '\n'][..]))?;
+        Ok(())
+    }
+}
+
+/// Read all of `pipe` on another thread.
+fn read_all<R: Read + Send + 'static>(mut pipe: R) -> JoinHandle<IOResult<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        pipe.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    })
+}
+
+fn joined(reader: JoinHandle<IOResult<Vec<u8>>>) -> IOResult<Vec<u8>> {
+    reader.join().expect("pipe reader panicked")
+}
+
+/// Register the `exec` helper.
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper(HELPER_NAME, Box::new(ExecHelper { program: None }));
+}
+
+/// Register a helper called `name` that runs the script at `path`.
+pub fn register_script(hb: &mut Handlebars, name: &str, path: &Path) {
+    let program = Some(path.as_os_str().to_owned());
+    hb.register_helper(name, Box::new(ExecHelper { program }));
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn pipes_input_through_commands() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = json!({"name": "ttgen"});
+
+        let rendered = hb.render_template("{{exec \"tr\" \"a-z\" \"A-Z\" input=name}}", &data);
+        assert_eq!(rendered.unwrap(), "TTGEN");
+        let rendered = hb.render_template("[{{#exec \"rev\"}}{{name}}{{/exec}}]", &data);
+        assert_eq!(rendered.unwrap(), "[negtt]");
+
+        let err = hb
+            .render_template("{{exec \"sh\" \"-c\" \"echo no >&2; exit 3\"}}", &data)
+            .unwrap_err();
+        assert!(err.to_string().contains("exit status: 3: no"), "{}", err);
+
+        register_script(&mut hb, "upper", Path::new("tr"));
+        let rendered = hb.render_template("{{upper \"a-z\" \"A-Z\" input=name}}", &data);
+        assert_eq!(rendered.unwrap(), "TTGEN");
+    }
+
+    #[test]
+    fn kills_commands_past_the_deadline() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let start = std::time::Instant::now();
+        let timeout = Some(std::time::Duration::from_millis(50));
+        let err = limits::with_timeout(timeout, || {
+            hb.render_template("{{exec \"sleep\" \"10\"}}", &json!({}))
+                .err()
+        })
+        .unwrap();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(start.elapsed().as_secs() < 5);
+    }
+}
@@ -0,0 +1,238 @@
+//! Filesystem helpers: `read_file`, `glob` and `file_exists`.
+//!
+//! `{{read_file "snippets/header.txt"}}` inserts the contents of a file as
+//! they are, neither escaped nor rendered as a template.
+//! `{{#each (glob "fragments/*.md") as |f|}}` lists the matching files in
+//! name order, and `{{#if (file_exists "logo.svg")}}` tests for one.  Globs
+//! support `*`, `?`, `[abc]` and `**` for any number of directories.
+//!
+//! Paths are relative to the directory of the template being rendered, or
+//! to the root given with `--file-root`, and may not lead outside it:
+//! absolute paths are refused, and `..` or symlinks escaping the directory
+//! are errors.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use regex::Regex;
+use serde_json::Value;
+use walkdir::WalkDir;
+
+/// A helper's work, given the directory to resolve paths in and its path
+/// parameter.
+type FileFn = fn(&Path, &str) -> Result<Value, String>;
+
+struct FileHelper {
+    /// The directory paths are resolved in, or `None` for the template's own.
+    root: Option<PathBuf>,
+    f: FileFn,
+}
+
+impl FileHelper {
+    fn base(&self, ctx: &Context) -> PathBuf {
+        match self.root {
+            Some(ref root) => root.clone(),
+            None => ctx
+                .data()
+                .get("template_file")
+                .and_then(Value::as_str)
+                .and_then(|t| Path::new(t).parent())
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."))
+                .to_path_buf(),
+        }
+    }
+}
+
+/// Resolve `path` below `base`, refusing anything that leads outside it.
+fn resolve(base: &Path, path: &str) -> Result<PathBuf, String> {
+    if Path::new(path).is_absolute() {
+        return Err(format!("{}: absolute paths are not allowed", path));
+    }
+    let base = base
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", base.display(), e))?;
+    let resolved = base
+        .join(path)
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if !resolved.starts_with(&base) {
+        return Err(format!("{}: outside of {}", path, base.display()));
+    }
+    Ok(resolved)
+}
+
+fn read_file(base: &Path, path: &str) -> Result<Value, String> {
+    let resolved = resolve(base, path)?;
+    let contents = fs::read_to_string(&resolved).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Value::String(contents))
+}
+
+fn file_exists(base: &Path, path: &str) -> Result<Value, String> {
+    if !Path::new(path).is_absolute() && !base.join(path).exists() {
+        return Ok(false.into());
+    }
+    resolve(base, path).map(|_| true.into())
+}
+
+/// Translate a glob into a regex matching whole `/`-separated paths.
+fn glob_regex(pattern: &str) -> Result<Regex, String> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('\\') => re.push_str("\\\\"),
+                        Some(c) => re.push(c),
+                        None => return Err(format!("{}: unclosed [", pattern)),
+                    }
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).map_err(|e| format!("{}: {}", pattern, e))
+}
+
+fn glob(base: &Path, pattern: &str) -> Result<Value, String> {
+    if Path::new(pattern).is_absolute() {
+        return Err(format!("{}: absolute paths are not allowed", pattern));
+    }
+    let re = glob_regex(pattern)?;
+    let walker = WalkDir::new(base)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    let mut matches = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(|e| format!("{}: {}", pattern, e))?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(base).unwrap_or(entry.path());
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        // Symlinks are listed only if they stay inside the directory.
+        if re.is_match(&relative) && resolve(base, &relative).is_ok() {
+            matches.push(Value::String(relative));
+        }
+    }
+    Ok(Value::Array(matches))
+}
+
+impl HelperDef for FileHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let path = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new(format!("{} helper expects a path", h.name())))?;
+        let value = (self.f)(&self.base(ctx), path)
+            .map_err(|msg| RenderError::new(format!("{} {}", h.name(), msg)))?;
+        Ok(Some(ScopedJson::Derived(value)))
+    }
+}
+
+/// Register the filesystem helpers, resolving paths below `root` if given,
+/// otherwise below each template's directory.
+pub fn register(hb: &mut Handlebars, root: Option<PathBuf>) {
+    let helpers: [(&str, FileFn); 3] = [
+        ("read_file", read_file),
+        ("glob", glob),
+        ("file_exists", file_exists),
+    ];
+    for &(name, f) in &helpers {
+        let root = root.clone();
+        hb.register_helper(name, Box::new(FileHelper { root, f }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_files_inside_the_root_only() {
+        let dir = std::env::temp_dir().join(format!("ttgen-read-file-{}", std::process::id()));
+        fs::create_dir_all(dir.join("snippets")).unwrap();
+        fs::write(dir.join("snippets/header.txt"), "{{not a template}}\n").unwrap();
+        fs::write(dir.join("secret.txt"), "no").unwrap();
+
+        let mut hb = Handlebars::new();
+        let template = dir.join("snippets/page.hbs");
+        let data = json!({"template_file": template.to_str().unwrap()});
+        register(&mut hb, None);
+        let rendered = hb.render_template("{{read_file \"header.txt\"}}", &data);
+        assert_eq!(rendered.unwrap(), "{{not a template}}\n");
+        let err = hb
+            .render_template("{{read_file \"../secret.txt\"}}", &data)
+            .unwrap_err();
+        assert!(err.to_string().contains("outside of"), "{}", err);
+
+        register(&mut hb, Some(dir.clone()));
+        let rendered = hb.render_template("{{read_file \"secret.txt\"}}", &data);
+        assert_eq!(rendered.unwrap(), "no");
+        let absolute = format!("{{{{read_file {:?}}}}}", dir.join("secret.txt"));
+        assert!(hb.render_template(&absolute, &data).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn globs_and_tests_files() {
+        let dir = std::env::temp_dir().join(format!("ttgen-glob-{}", std::process::id()));
+        for f in &["b.md", "a.md", "c.txt", "sub/d.md", "sub/deep/e.md"] {
+            let path = dir.join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let mut hb = Handlebars::new();
+        register(&mut hb, Some(dir.clone()));
+        let render = |t: &str| hb.render_template(t, &()).unwrap();
+        let list = |pattern: &str| {
+            render(&format!(
+                "{{{{#each (glob {:?}) as |f|}}}}{{{{f}}}} {{{{/each}}}}",
+                pattern
+            ))
+        };
+        assert_eq!(list("*.md"), "a.md b.md ");
+        assert_eq!(list("**/*.md"), "a.md b.md sub/d.md sub/deep/e.md ");
+        assert_eq!(list("sub/**"), "sub/d.md sub/deep/e.md ");
+        assert_eq!(list("[!a].md"), "b.md ");
+        assert_eq!(
+            render("{{file_exists \"c.txt\"}} {{file_exists \"x.txt\"}}"),
+            "true false"
+        );
+        assert!(hb.render_template("{{file_exists \"..\"}}", &()).is_err());
+        assert!(hb.render_template("{{glob \"/etc/*\"}}", &()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
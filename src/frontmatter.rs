@@ -0,0 +1,190 @@
+//! Template front matter: an optional leading block of settings, opened by a
+//! `---ttgen` line and closed by a `---` line.  The opening marker is distinct
+//! so that templates of YAML documents or RST overlines, which may start with
+//! a plain `---`, are rendered as they are.
+//!
+//! Only a flat subset of YAML is understood: `key: value` scalars, plus a
+//! `context:` mapping whose indented `key: value` entries are merged into the
+//! root context.
+//!
+//! ```text
+//! ---ttgen
+//! output: docs/{{root.slug}}.rst
+//! escape: none
+//! context:
+//!   title: "Release notes"
+//! ---
+//! ```
+
+use std::str::FromStr;
+
+use serde_json::{Map, Number, Value};
+
+use crate::error::*;
+use crate::spec::Escape;
+
+#[derive(Default, Debug, PartialEq)]
+pub struct FrontMatter {
+    /// Handlebars pattern for the output path, rendered against the root context.
+    /// Only `generate` without an explicit OUTPUT consults it; spec entries always
+    /// name their output, so their templates may not declare one.
+    pub output: Option<String>,
+    pub escape: Option<Escape>,
+    pub context: Map<String, Value>,
+}
+
+impl FrontMatter {
+    /// Fail if an output is declared, for templates whose output is named
+    /// elsewhere and would silently win over it.
+    pub fn reject_output(&self) -> Result<()> {
+        match self.output {
+            Some(_) => {
+                let msg = "output is only used by generate; name it in the spec entry";
+                Err(FrontMatterError::from(msg.to_string()).into())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn fail<T>(line: usize, msg: &str) -> Result<T> {
+    Err(FrontMatterError::from(format!("line {}: {}", line, msg)).into())
+}
+
+fn parse_scalar(raw: &str) -> Value {
+    let raw = raw.trim();
+    let quoted = raw.len() >= 2
+        && ((raw.starts_with('"') && raw.ends_with('"'))
+            || (raw.starts_with('\'') && raw.ends_with('\'')));
+    if quoted {
+        return Value::from(&raw[1..raw.len() - 1]);
+    }
+
+    match raw {
+        "" | "~" | "null" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => {
+            if let Ok(i) = raw.parse::<i64>() {
+                Value::from(i)
+            } else if let Some(n) = raw.parse::<f64>().ok().and_then(Number::from_f64) {
+                Value::Number(n)
+            } else {
+                Value::from(raw)
+            }
+        }
+    }
+}
+
+fn split_pair(line: usize, text: &str) -> Result<(String, &str)> {
+    match text.find(':') {
+        Some(i) if i > 0 => Ok((text[..i].trim().to_string(), &text[i + 1..])),
+        _ => fail(line, "expected `key: value`"),
+    }
+}
+
+fn parse_block(block: &str) -> Result<FrontMatter> {
+    let mut front = FrontMatter::default();
+    let mut in_context = false;
+
+    // Line numbers are 1-based and account for the opening `---ttgen`.
+    for (idx, raw) in block.lines().enumerate() {
+        let line = idx + 2;
+        let trimmed = raw.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if raw.starts_with(' ') || raw.starts_with('\t') {
+            if !in_context {
+                return fail(line, "unexpected indentation");
+            }
+            let (key, value) = split_pair(line, trimmed)?;
+            front.context.insert(key, parse_scalar(value));
+            continue;
+        }
+
+        in_context = false;
+        let (key, value) = split_pair(line, trimmed)?;
+        match key.as_str() {
+            "output" => match parse_scalar(value) {
+                Value::String(s) => front.output = Some(s),
+                _ => return fail(line, "output must be a string"),
+            },
+            "escape" => match parse_scalar(value) {
+                Value::String(s) => match Escape::from_str(&s) {
+                    Ok(e) => front.escape = Some(e),
+                    Err(e) => return fail(line, &e),
+                },
+                _ => return fail(line, "escape must be a string"),
+            },
+            "context" if value.trim().is_empty() => in_context = true,
+            "context" => return fail(line, "context must be an indented mapping"),
+            other => return fail(line, &format!("unknown key: {}", other)),
+        }
+    }
+
+    Ok(front)
+}
+
+/// Split `source` into its front matter and the remaining template body.
+///
+/// Sources that don't start with a `---ttgen` line are returned unchanged.
+pub fn split(source: &str) -> Result<(FrontMatter, &str)> {
+    let rest = match source
+        .strip_prefix("---ttgen\n")
+        .or_else(|| source.strip_prefix("---ttgen\r\n"))
+    {
+        Some(rest) => rest,
+        None => return Ok((FrontMatter::default(), source)),
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let front = parse_block(&rest[..offset])?;
+            return Ok((front, &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+
+    fail(1, "front matter is not closed by `---`")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_front_matter() {
+        let (front, body) = split("hello {{name}}\n").ok().unwrap();
+        assert_eq!(front, FrontMatter::default());
+        assert_eq!(body, "hello {{name}}\n");
+    }
+
+    #[test]
+    fn leaves_yaml_documents_alone() {
+        for source in &["---\nkind: {{root.kind}}\n---\nname: x\n", "---\nkind: x\n"] {
+            let (front, body) = split(source).ok().unwrap();
+            assert_eq!(front, FrontMatter::default());
+            assert_eq!(body, *source);
+        }
+    }
+
+    #[test]
+    fn parses_settings_and_context() {
+        let source = "---ttgen\noutput: out/{{root.slug}}.rst\nescape: none\ncontext:\n  title: \"Notes\"\n  level: 2\n---\nbody\n";
+        let (front, body) = split(source).ok().unwrap();
+        assert_eq!(body, "body\n");
+        assert_eq!(front.output.as_ref().unwrap(), "out/{{root.slug}}.rst");
+        assert_eq!(front.escape, Some(Escape::None));
+        assert_eq!(front.context["title"], Value::from("Notes"));
+        assert_eq!(front.context["level"], Value::from(2));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_unclosed_blocks() {
+        assert!(split("---ttgen\nfoo: bar\n---\n").is_err());
+        assert!(split("---ttgen\noutput: x\n").is_err());
+    }
+}
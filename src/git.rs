@@ -0,0 +1,92 @@
+//! Git metadata for the root context, enabled with `--git`.
+//!
+//! `git.commit` and `git.short` are the full and abbreviated hash of `HEAD`,
+//! `git.branch` the checked-out branch, `git.tag` a tag pointing at `HEAD`
+//! and `git.dirty` whether there are uncommitted changes.  Branch and tag
+//! are null when detached or untagged.  Metadata is read once per run, from
+//! the repository containing the working directory.
+
+use std::io::{Error as IOError, ErrorKind};
+use std::path::Path;
+use std::process::Command;
+
+use serde_json::{json, Value};
+
+use crate::error::*;
+
+/// Run `git args...` in `dir`, returning its trimmed output or `None` if it
+/// failed.
+fn git(dir: &Path, args: &[&str]) -> Result<Option<String>> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| IOError::new(e.kind(), format!("git: {}", e)))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+/// The `git` object for the repository containing `dir`.
+pub fn metadata(dir: &Path) -> Result<Value> {
+    let commit = git(dir, &["rev-parse", "--verify", "HEAD"])?.ok_or_else(|| {
+        let msg = format!("{}: not in a git repository with commits", dir.display());
+        IOError::new(ErrorKind::NotFound, msg)
+    })?;
+    let short = git(dir, &["rev-parse", "--short", "HEAD"])?;
+    let branch = git(dir, &["symbolic-ref", "--short", "-q", "HEAD"])?;
+    let tag = git(dir, &["describe", "--tags", "--exact-match", "HEAD"])?;
+    let status = git(dir, &["status", "--porcelain"])?.unwrap_or_default();
+
+    Ok(json!({
+        "commit": commit,
+        "short": short,
+        "branch": branch,
+        "tag": tag,
+        "dirty": !status.is_empty(),
+    }))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn reads_repository_metadata() {
+        let dir = std::env::temp_dir().join(format!("ttgen-git-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let run = |args: &[&str]| {
+            let ok = Command::new("git")
+                .args(["-c", "user.name=t", "-c", "user.email=t@example.com"])
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .map(|o| o.status.success());
+            ok.unwrap_or(false)
+        };
+        // Without git there's nothing to test.
+        if !run(&["init", "-q", "-b", "main"]) {
+            return;
+        }
+        assert!(metadata(&dir).is_err());
+
+        assert!(run(&["commit", "-q", "--allow-empty", "-m", "first"]));
+        assert!(run(&["tag", "v1"]));
+        fs::write(dir.join("new.txt"), "").unwrap();
+        let git = metadata(&dir).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(git["commit"].as_str().unwrap().len(), 40);
+        assert!(git["commit"]
+            .as_str()
+            .unwrap()
+            .starts_with(git["short"].as_str().unwrap()));
+        assert_eq!(git["branch"], "main");
+        assert_eq!(git["tag"], "v1");
+        assert_eq!(git["dirty"], true);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Helpers registered with every template, on top of the handlebars
+//! built-ins.
+//!
+//! Helpers returning arrays or objects are meant for subexpressions.  Their
+//! results have no path in the data, so blocks over them need a block
+//! parameter: `{{#each (helper ...) as |item|}}{{item}}{{/each}}`.
+//!
+//! Handlebars reads a bare name, `{{helper}}` or `(helper)`, as a data
+//! lookup, so every helper here takes at least one parameter.
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value;
+
+mod case;
+mod collections;
+mod date;
+mod digest;
+mod encoding;
+mod escape;
+mod logic;
+mod math;
+mod number;
+mod path;
+mod pattern;
+mod serialize;
+mod table;
+mod text;
+
+pub use self::encoding::{base64_decode, base64_encode};
+pub use self::escape::latex_escape;
+pub use self::path::relative_to;
+
+/// A helper computing a JSON value from its parameters, so it can be used
+/// as a subexpression as well as rendered.
+type ValueFn = for<'reg, 'rc> fn(&Helper<'reg, 'rc>) -> Result<Value, RenderError>;
+
+struct ValueHelper(ValueFn);
+
+impl HelperDef for ValueHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        Ok(Some(ScopedJson::Derived((self.0)(h)?)))
+    }
+}
+
+fn register_value(hb: &mut Handlebars, name: &str, f: ValueFn) {
+    hb.register_helper(name, Box::new(ValueHelper(f)));
+}
+
+/// The `i`th parameter of `h`.
+fn param<'a>(h: &'a Helper, i: usize) -> Result<&'a Value, RenderError> {
+    h.param(i)
+        .map(|p| p.value())
+        .ok_or_else(|| RenderError::new(format!("{} helper missing parameter {}", h.name(), i + 1)))
+}
+
+/// The `i`th parameter of `h`, which must be a string.
+fn str_param<'a>(h: &'a Helper, i: usize) -> Result<&'a str, RenderError> {
+    param(h, i)?.as_str().ok_or_else(|| {
+        RenderError::new(format!(
+            "{} helper parameter {} is not a string",
+            h.name(),
+            i + 1
+        ))
+    })
+}
+
+/// Whether `v` counts as true in `{{#if}}`.
+fn truthy(v: &Value) -> bool {
+    match v {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|f| f != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+        Value::Null => false,
+    }
+}
+
+/// Whether the hash argument `name` of `h` is `true`.
+fn flag(h: &Helper, name: &str) -> bool {
+    h.hash_get(name)
+        .is_some_and(|v| v.value().as_bool() == Some(true))
+}
+
+pub fn register(hb: &mut Handlebars) {
+    case::register(hb);
+    collections::register(hb);
+    date::register(hb);
+    digest::register(hb);
+    encoding::register(hb);
+    escape::register(hb);
+    logic::register(hb);
+    math::register(hb);
+    number::register(hb);
+    path::register(hb);
+    pattern::register(hb);
+    serialize::register(hb);
+    table::register(hb);
+    text::register(hb);
+}
@@ -0,0 +1,127 @@
+//! Case conversion: `snake_case`, `camel_case`, `pascal_case`, `kebab_case`,
+//! `screaming_snake_case`, `upper` and `lower`.
+//!
+//! Words are split at anything other than letters and digits, and where the
+//! case changes, so `HTTPServer2Config`, `http_server2_config` and
+//! `http server2 config` all convert alike.
+
+use handlebars::{handlebars_helper, Handlebars};
+
+fn words(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if let Some(&prev) = i.checked_sub(1).and_then(|i| chars.get(i)) {
+            let next = chars.get(i + 1).copied();
+            // fooBar and foo2Bar, or the R starting Request in HTTPRequest.
+            let boundary = c.is_uppercase()
+                && (prev.is_lowercase()
+                    || prev.is_numeric()
+                    || (prev.is_uppercase() && next.is_some_and(char::is_lowercase)));
+            if boundary && !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+fn joined(s: &str, sep: &str, f: fn(&str) -> String) -> String {
+    words(s).iter().map(|w| f(w)).collect::<Vec<_>>().join(sep)
+}
+
+pub fn snake_case(s: &str) -> String {
+    joined(s, "_", str::to_lowercase)
+}
+
+pub fn kebab_case(s: &str) -> String {
+    joined(s, "-", str::to_lowercase)
+}
+
+pub fn screaming_snake_case(s: &str) -> String {
+    joined(s, "_", str::to_uppercase)
+}
+
+pub fn pascal_case(s: &str) -> String {
+    joined(s, "", capitalize)
+}
+
+pub fn camel_case(s: &str) -> String {
+    let mut words = words(s).into_iter();
+    match words.next() {
+        Some(first) => words.fold(first.to_lowercase(), |acc, w| acc + &capitalize(&w)),
+        None => String::new(),
+    }
+}
+
+handlebars_helper!(snake_case_helper: |s: str| snake_case(s));
+handlebars_helper!(camel_case_helper: |s: str| camel_case(s));
+handlebars_helper!(pascal_case_helper: |s: str| pascal_case(s));
+handlebars_helper!(kebab_case_helper: |s: str| kebab_case(s));
+handlebars_helper!(screaming_snake_case_helper: |s: str| screaming_snake_case(s));
+handlebars_helper!(upper_helper: |s: str| s.to_uppercase());
+handlebars_helper!(lower_helper: |s: str| s.to_lowercase());
+
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper("snake_case", Box::new(snake_case_helper));
+    hb.register_helper("camel_case", Box::new(camel_case_helper));
+    hb.register_helper("pascal_case", Box::new(pascal_case_helper));
+    hb.register_helper("kebab_case", Box::new(kebab_case_helper));
+    hb.register_helper(
+        "screaming_snake_case",
+        Box::new(screaming_snake_case_helper),
+    );
+    hb.register_helper("upper", Box::new(upper_helper));
+    hb.register_helper("lower", Box::new(lower_helper));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_between_cases() {
+        for s in &[
+            "HTTPServer2Config",
+            "http_server2_config",
+            "http server2-config",
+        ] {
+            assert_eq!(snake_case(s), "http_server2_config");
+            assert_eq!(kebab_case(s), "http-server2-config");
+            assert_eq!(screaming_snake_case(s), "HTTP_SERVER2_CONFIG");
+            assert_eq!(pascal_case(s), "HttpServer2Config");
+            assert_eq!(camel_case(s), "httpServer2Config");
+        }
+        assert_eq!(snake_case("fooBar"), "foo_bar");
+        assert_eq!(snake_case("foo2Bar"), "foo2_bar");
+        assert_eq!(camel_case(""), "");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"name": "user id"});
+        let rendered = hb.render_template("{{pascal_case name}} {{upper name}}", &data);
+        assert_eq!(rendered.unwrap(), "UserId USER ID");
+    }
+}
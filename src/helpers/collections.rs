@@ -0,0 +1,493 @@
+//! Helpers over arrays.
+//!
+//! The aggregates `sum`, `min`, `max`, `avg` and `count_if` take an array
+//! and, optionally, a dotted field name to read from every element:
+//! `{{sum items "price"}}`.  Elements where the value is missing or null are
+//! skipped.
+//!
+//! `(sort_by items "-priority" "name")` sorts by one or more fields, a `-`
+//! prefix sorting that field in descending order.
+//!
+//! `(group_by items "category")` maps each value of a field to the elements
+//! having it, in order of first appearance.  Iterate it with
+//! `{{#each (group_by items "category") as |category items|}}`; this
+//! handlebars names the key first.
+//!
+//! `(unique items)` drops repeated elements, or with a field name, elements
+//! repeating an earlier element's value of that field.  `dedup` only drops
+//! runs of consecutive repeats, like `Vec::dedup`.
+//!
+//! `(range 1 10)` is the integers from 1 up to but excluding 10.
+//!
+//! `(zip names ports)` pairs up elements of parallel arrays, and
+//! `(enumerate items)` numbers elements from 1.
+//!
+//! `{{pointer root "/services/0/name"}}` looks up an RFC 6901 JSON Pointer.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use handlebars::{Handlebars, Helper, JsonRender, RenderError};
+use serde_json::{json, Map, Value};
+
+use super::math::{self, Num};
+use super::{flag, param, register_value, str_param, truthy};
+
+/// `v.a.b` for the field name `a.b`.  Array elements are numbered.
+pub(super) fn field<'a>(v: &'a Value, name: &str) -> &'a Value {
+    name.split('.').fold(v, |v, key| {
+        match v {
+            Value::Array(items) => key.parse().ok().and_then(|i: usize| items.get(i)),
+            _ => v.get(key),
+        }
+        .unwrap_or(&Value::Null)
+    })
+}
+
+pub(super) fn array<'a>(h: &'a Helper, i: usize) -> Result<&'a Vec<Value>, RenderError> {
+    param(h, i)?.as_array().ok_or_else(|| {
+        RenderError::new(format!(
+            "{} helper parameter {} is not an array",
+            h.name(),
+            i + 1
+        ))
+    })
+}
+
+/// The non-null values of the array in the first parameter, or of the field
+/// named by the second parameter in each of its elements.
+fn values<'a>(h: &'a Helper) -> Result<Vec<&'a Value>, RenderError> {
+    let items = array(h, 0)?.iter();
+    let values: Vec<&Value> = match h.param(1) {
+        Some(_) => {
+            let name = str_param(h, 1)?;
+            items.map(|v| field(v, name)).collect()
+        }
+        None => items.collect(),
+    };
+    Ok(values.into_iter().filter(|v| !v.is_null()).collect())
+}
+
+fn numbers(h: &Helper) -> Result<Vec<Num>, RenderError> {
+    values(h)?
+        .into_iter()
+        .map(|v| {
+            Num::from_json(v).ok_or_else(|| {
+                RenderError::new(format!("{} helper: {} is not a number", h.name(), v))
+            })
+        })
+        .collect()
+}
+
+fn sum(h: &Helper) -> Result<Value, RenderError> {
+    let total = numbers(h)?.into_iter().fold(Num::Int(0), |acc, n| {
+        math::apply(acc, n, i64::checked_add, |a, b| a + b)
+    });
+    math::value(h, total)
+}
+
+fn avg(h: &Helper) -> Result<Value, RenderError> {
+    let numbers = numbers(h)?;
+    if numbers.is_empty() {
+        return Ok(Value::Null);
+    }
+    let total: f64 = numbers.iter().map(|n| n.as_f64()).sum();
+    math::value(h, Num::Float(total / numbers.len() as f64))
+}
+
+fn extreme(h: &Helper, keep: fn(f64, f64) -> bool) -> Result<Value, RenderError> {
+    let best = numbers(h)?
+        .into_iter()
+        .fold(None, |best: Option<Num>, n| match best {
+            Some(b) if !keep(n.as_f64(), b.as_f64()) => Some(b),
+            _ => Some(n),
+        });
+    best.map_or(Ok(Value::Null), |n| math::value(h, n))
+}
+
+fn min(h: &Helper) -> Result<Value, RenderError> {
+    extreme(h, |n, best| n < best)
+}
+
+fn max(h: &Helper) -> Result<Value, RenderError> {
+    extreme(h, |n, best| n > best)
+}
+
+/// `{{count_if items "enabled"}}` counts the elements whose field is truthy;
+/// `{{count_if items "status" "ok"}}` those where it equals the value.
+fn count_if(h: &Helper) -> Result<Value, RenderError> {
+    let items = array(h, 0)?;
+    let name = str_param(h, 1)?;
+    let count = match h.param(2) {
+        Some(expected) => {
+            let expected = expected.value();
+            items.iter().filter(|v| field(v, name) == expected).count()
+        }
+        None => items.iter().filter(|v| truthy(field(v, name))).count(),
+    };
+    Ok(count.into())
+}
+
+fn rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+/// Sort order for JSON values: null, booleans, numbers, strings, then
+/// arrays and objects by their JSON text.  With `numeric`, strings that
+/// parse as numbers sort as those numbers.
+fn compare(a: &Value, b: &Value, numeric: bool, ignore_case: bool) -> Ordering {
+    let number = |v: &Value| match v {
+        Value::String(s) if numeric => s.trim().parse::<f64>().ok(),
+        _ => v.as_f64(),
+    };
+    if let (Some(x), Some(y)) = (number(a), number(b)) {
+        return x.partial_cmp(&y).unwrap_or(Ordering::Equal);
+    }
+    match (a, b) {
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        (Value::String(x), Value::String(y)) if ignore_case => {
+            x.to_lowercase().cmp(&y.to_lowercase())
+        }
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        _ => match rank(a).cmp(&rank(b)) {
+            Ordering::Equal => a.to_string().cmp(&b.to_string()),
+            unequal => unequal,
+        },
+    }
+}
+
+/// `(sort_by items "name" "-size")`, or `(sort_by values)` to sort the
+/// elements themselves.  `numeric=true` compares numeric strings as
+/// numbers, and `ignore_case=true` compares strings case-insensitively.
+/// The sort is stable.
+fn sort_by(h: &Helper) -> Result<Value, RenderError> {
+    let mut items = array(h, 0)?.clone();
+    let mut keys = Vec::new();
+    for i in 1..h.params().len() {
+        let key = str_param(h, i)?;
+        keys.push(match key.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (key, false),
+        });
+    }
+    let (numeric, ignore_case) = (flag(h, "numeric"), flag(h, "ignore_case"));
+
+    items.sort_by(|a, b| {
+        if keys.is_empty() {
+            return compare(a, b, numeric, ignore_case);
+        }
+        for &(name, descending) in &keys {
+            let order = compare(field(a, name), field(b, name), numeric, ignore_case);
+            let order = if descending { order.reverse() } else { order };
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        Ordering::Equal
+    });
+    Ok(Value::Array(items))
+}
+
+/// `(group_by items "category")`.  Keys are the field's rendered value, so
+/// elements without the field are grouped under the empty string.
+fn group_by(h: &Helper) -> Result<Value, RenderError> {
+    let name = str_param(h, 1)?;
+    let mut groups = Map::new();
+    for item in array(h, 0)? {
+        let key = field(item, name).render();
+        let group = groups
+            .entry(key)
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if let Value::Array(group) = group {
+            group.push(item.clone());
+        }
+    }
+    Ok(Value::Object(groups))
+}
+
+/// What `unique` and `dedup` compare: the element, or its field named by the
+/// second parameter.
+fn dedup_key<'a>(h: &'a Helper) -> Result<impl Fn(&Value) -> String + 'a, RenderError> {
+    let name = match h.param(1) {
+        Some(_) => Some(str_param(h, 1)?),
+        None => None,
+    };
+    Ok(move |v: &Value| match name {
+        Some(name) => field(v, name).to_string(),
+        None => v.to_string(),
+    })
+}
+
+fn unique(h: &Helper) -> Result<Value, RenderError> {
+    let key = dedup_key(h)?;
+    let mut seen = HashSet::new();
+    let items = array(h, 0)?.iter().filter(|v| seen.insert(key(v)));
+    Ok(items.cloned().collect())
+}
+
+fn dedup(h: &Helper) -> Result<Value, RenderError> {
+    let key = dedup_key(h)?;
+    let mut last = None;
+    let items = array(h, 0)?.iter().filter(|v| {
+        let k = Some(key(v));
+        let repeat = k == last;
+        last = k;
+        !repeat
+    });
+    Ok(items.cloned().collect())
+}
+
+/// Ranges longer than this are refused rather than allocated.
+const MAX_RANGE: usize = 1_000_000;
+
+fn int(h: &Helper, i: usize) -> Result<i64, RenderError> {
+    param(h, i)?.as_i64().ok_or_else(|| {
+        RenderError::new(format!(
+            "{} helper parameter {} is not an integer",
+            h.name(),
+            i + 1
+        ))
+    })
+}
+
+/// `(range 5)` is 0 to 4, `(range 1 10)` is 1 to 9 and `(range 10 0 -2)`
+/// is 10, 8, 6, 4, 2.
+fn range(h: &Helper) -> Result<Value, RenderError> {
+    let (start, end) = match h.params().len() {
+        1 => (0, int(h, 0)?),
+        _ => (int(h, 0)?, int(h, 1)?),
+    };
+    let step = match h.param(2) {
+        Some(_) => int(h, 2)?,
+        None => 1,
+    };
+    if step == 0 {
+        return Err(RenderError::new("range step must not be zero"));
+    }
+
+    let span = (i128::from(end) - i128::from(start)) / i128::from(step);
+    if span > MAX_RANGE as i128 {
+        let msg = format!("range longer than {} elements", MAX_RANGE);
+        return Err(RenderError::new(msg));
+    }
+    let mut values = Vec::new();
+    let mut n = start;
+    while (step > 0 && n < end) || (step < 0 && n > end) {
+        values.push(Value::from(n));
+        n = match n.checked_add(step) {
+            Some(n) => n,
+            None => break,
+        };
+    }
+    Ok(Value::Array(values))
+}
+
+/// `(zip a b ...)` is an array of arrays holding the elements at each
+/// index, as long as the shortest input: `{{pair.[0]}}`, `{{pair.[1]}}`.
+fn zip(h: &Helper) -> Result<Value, RenderError> {
+    let arrays = (0..h.params().len().max(1))
+        .map(|i| array(h, i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let len = arrays.iter().map(|a| a.len()).min().unwrap_or(0);
+    let rows = (0..len).map(|i| arrays.iter().map(|a| a[i].clone()).collect::<Value>());
+    Ok(rows.collect())
+}
+
+/// `(enumerate items)` wraps each element as `{"index": 0, "number": 1,
+/// "item": ...}`, `number` counting from 1.
+fn enumerate(h: &Helper) -> Result<Value, RenderError> {
+    let items = array(h, 0)?.iter().enumerate();
+    let wrapped = items.map(|(i, item)| json!({"index": i, "number": i + 1, "item": item}));
+    Ok(wrapped.collect())
+}
+
+/// The value `pointer` refers to in the first parameter, or null if there
+/// is none.
+fn pointer(h: &Helper) -> Result<Value, RenderError> {
+    let pointer = str_param(h, 1)?;
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        let msg = format!("pointer helper: {:?} does not start with /", pointer);
+        return Err(RenderError::new(msg));
+    }
+    Ok(param(h, 0)?
+        .pointer(pointer)
+        .cloned()
+        .unwrap_or(Value::Null))
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "sum", sum);
+    register_value(hb, "min", min);
+    register_value(hb, "max", max);
+    register_value(hb, "avg", avg);
+    register_value(hb, "count_if", count_if);
+    register_value(hb, "sort_by", sort_by);
+    register_value(hb, "group_by", group_by);
+    register_value(hb, "unique", unique);
+    register_value(hb, "dedup", dedup);
+    register_value(hb, "range", range);
+    register_value(hb, "zip", zip);
+    register_value(hb, "enumerate", enumerate);
+    register_value(hb, "pointer", pointer);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aggregates_arrays_and_fields() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "nums": [3, 1.5, null, 4],
+            "items": [
+                {"price": 10, "tag": {"on": true}, "status": "ok"},
+                {"price": 5, "tag": {"on": false}, "status": "ok"},
+                {"tag": {}, "status": "failed"},
+            ],
+            "empty": [],
+        });
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{sum nums}} {{min nums}} {{max nums}}"),
+            "8.5 1.5 4"
+        );
+        assert_eq!(
+            render("{{sum items \"price\"}} {{avg items \"price\"}}"),
+            "15 7.5"
+        );
+        assert_eq!(render("{{count_if items \"tag.on\"}}"), "1");
+        assert_eq!(render("{{count_if items \"status\" \"ok\"}}"), "2");
+        assert_eq!(
+            render("{{sum empty}}[{{avg empty}}][{{max empty}}]"),
+            "0[][]"
+        );
+        assert!(hb
+            .render_template("{{sum items \"status\"}}", &data)
+            .is_err());
+    }
+
+    #[test]
+    fn sorts_by_fields() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "items": [
+                {"name": "b", "prio": 1, "v": "10"},
+                {"name": "a", "prio": 2, "v": "9"},
+                {"name": "C", "prio": 1, "v": "100"},
+            ],
+            "values": [3, null, "x", 1],
+        });
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+        let names = |args: &str| {
+            render(&format!(
+                "{{{{#each (sort_by items {}) as |i|}}}}{{{{i.name}}}}{{{{/each}}}}",
+                args
+            ))
+        };
+
+        assert_eq!(names("\"name\""), "Cab");
+        assert_eq!(names("\"name\" ignore_case=true"), "abC");
+        assert_eq!(names("\"-prio\" \"name\""), "aCb");
+        assert_eq!(names("\"v\""), "bCa");
+        assert_eq!(names("\"v\" numeric=true"), "abC");
+        assert_eq!(
+            render("{{#each (sort_by values) as |v|}}[{{v}}]{{/each}}"),
+            "[][1][3][x]"
+        );
+    }
+
+    #[test]
+    fn groups_by_field() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"items": [
+            {"n": 1, "kind": "b"}, {"n": 2, "kind": "a"}, {"n": 3, "kind": "b"}, {"n": 4},
+        ]});
+        let source = "{{#each (group_by items \"kind\") as |kind group|}}\
+                      {{kind}}:{{#each group as |i|}}{{i.n}}{{/each}};{{/each}}";
+        assert_eq!(hb.render_template(source, &data).unwrap(), "b:13;a:2;:4;");
+    }
+
+    #[test]
+    fn removes_duplicates() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "tags": ["a", "b", "b", "a", 1, "1"],
+            "pages": [{"s": "x", "n": 1}, {"s": "y", "n": 2}, {"s": "x", "n": 3}],
+        });
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        let each =
+            |list: &str, item: &str| format!("{{{{#each {} as |i|}}}}{}{{{{/each}}}}", list, item);
+        assert_eq!(render(&each("(unique tags)", "{{i}},")), "a,b,1,1,");
+        assert_eq!(render(&each("(dedup tags)", "{{i}},")), "a,b,a,1,1,");
+        assert_eq!(render(&each("(unique pages \"s\")", "{{i.n}}")), "12");
+    }
+
+    #[test]
+    fn generates_ranges() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let render = |args: &str| {
+            let source = format!(
+                "{{{{#each (range {}) as |n|}}}}{{{{n}}}},{{{{/each}}}}",
+                args
+            );
+            hb.render_template(&source, &()).unwrap()
+        };
+
+        assert_eq!(render("3"), "0,1,2,");
+        assert_eq!(render("1 4"), "1,2,3,");
+        assert_eq!(render("10 0 -3"), "10,7,4,1,");
+        assert_eq!(render("4 1"), "");
+        assert!(hb.render_template("{{range 0 10000000}}", &()).is_err());
+    }
+
+    #[test]
+    fn zips_and_enumerates() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"names": ["a", "b", "c"], "ports": [80, 443]});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{#each (zip names ports) as |p|}}{{p.[0]}}={{p.[1]}} {{/each}}"),
+            "a=80 b=443 "
+        );
+        assert_eq!(
+            render(
+                "{{#each (enumerate names) as |e|}}{{e.number}}.{{e.item}}@{{e.index}} {{/each}}"
+            ),
+            "1.a@0 2.b@1 3.c@2 "
+        );
+    }
+
+    #[test]
+    fn resolves_pointers() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "services": [{"name": "web"}], "a/b": {"~": 1}, "path": "/services/0/name",
+        });
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(render("{{pointer this path}}"), "web");
+        assert_eq!(render("{{pointer this \"/a~1b/~0\"}}"), "1");
+        assert_eq!(render("[{{pointer this \"/services/9\"}}]"), "[]");
+        assert!(hb
+            .render_template("{{pointer this \"services\"}}", &data)
+            .is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Date helpers: `date_format`, `now`, `duration` and `time_ago`.
+//!
+//! Dates are RFC 3339 timestamps, `YYYY-MM-DD` dates (midnight UTC) or
+//! numbers of seconds since the Unix epoch.  Formats are chrono's strftime
+//! patterns, e.g. `%Y-%m-%d` or `%B %e, %Y`.
+//!
+//! Durations are numbers of seconds or ISO 8601 durations like `PT2H15M`.
+//! Years and months have no fixed length, so those designators are refused.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone};
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::Value;
+
+use super::{param, register_value, str_param};
+use crate::render;
+
+fn parse(v: &Value) -> Option<DateTime<FixedOffset>> {
+    let utc = FixedOffset::east(0);
+    match v {
+        Value::String(s) => DateTime::parse_from_rfc3339(s).ok().or_else(|| {
+            let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+            Some(utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+        }),
+        Value::Number(n) => {
+            let secs = n.as_f64()?;
+            let nanos = ((secs - secs.floor()) * 1e9).round() as u32;
+            utc.timestamp_opt(secs.floor() as i64, nanos).single()
+        }
+        _ => None,
+    }
+}
+
+fn format(h: &Helper, date: DateTime<FixedOffset>, i: usize) -> Result<Value, RenderError> {
+    let fmt = str_param(h, i)?;
+    // chrono panics when displaying an invalid pattern.
+    if StrftimeItems::new(fmt).any(|item| item == Item::Error) {
+        let msg = format!("{} helper: invalid format {:?}", h.name(), fmt);
+        return Err(RenderError::new(msg));
+    }
+    Ok(date.format(fmt).to_string().into())
+}
+
+/// `{{date_format root.published "%Y-%m-%d"}}`.  Timestamps keep their UTC
+/// offset.
+fn date_format(h: &Helper) -> Result<Value, RenderError> {
+    let v = param(h, 0)?;
+    let date = parse(v)
+        .ok_or_else(|| RenderError::new(format!("date_format helper: not a date: {}", v)))?;
+    format(h, date, 1)
+}
+
+/// `{{now "%Y"}}` formats the time of this run, the instant `date` holds.
+fn now(h: &Helper) -> Result<Value, RenderError> {
+    format(h, render::now().with_timezone(&FixedOffset::east(0)), 0)
+}
+
+const UNITS: [(&str, &str, i64); 7] = [
+    ("y", "year", 365 * 86400),
+    ("mo", "month", 30 * 86400),
+    ("w", "week", 7 * 86400),
+    ("d", "day", 86400),
+    ("h", "hour", 3600),
+    ("m", "minute", 60),
+    ("s", "second", 1),
+];
+
+/// Seconds in an ISO 8601 duration such as `P1DT2H` or `PT0.5S`.
+fn parse_iso_duration(s: &str) -> Option<f64> {
+    let rest = s.strip_prefix('P')?;
+    let (mut secs, mut time, mut number) = (0.0, false, String::new());
+    for c in rest.chars() {
+        let scale = match (c, time) {
+            ('T', false) if number.is_empty() => {
+                time = true;
+                continue;
+            }
+            ('0'..='9', _) | ('.', _) | (',', _) => {
+                number.push(if c == ',' { '.' } else { c });
+                continue;
+            }
+            ('W', false) => 7.0 * 86400.0,
+            ('D', false) => 86400.0,
+            ('H', true) => 3600.0,
+            ('M', true) => 60.0,
+            ('S', true) => 1.0,
+            _ => return None,
+        };
+        secs += number.parse::<f64>().ok()? * scale;
+        number.clear();
+    }
+    let complete = number.is_empty() && !rest.is_empty() && !rest.ends_with('T');
+    if complete {
+        Some(secs)
+    } else {
+        None
+    }
+}
+
+fn seconds(h: &Helper, i: usize) -> Result<f64, RenderError> {
+    let v = param(h, i)?;
+    let secs = match v {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => parse_iso_duration(s),
+        _ => None,
+    };
+    secs.ok_or_else(|| RenderError::new(format!("{} helper: not a duration: {}", h.name(), v)))
+}
+
+/// `90061` as `1d 1h 1m 1s`, leaving out zero units and keeping at most
+/// `precision` of them.  Week, month and year units are not used.
+pub fn format_duration(secs: f64, precision: usize) -> String {
+    let mut left = secs.abs().round() as i64;
+    let mut parts = Vec::new();
+    for &(short, _, size) in &UNITS[3..] {
+        if left >= size && parts.len() < precision {
+            parts.push(format!("{}{}", left / size, short));
+            left %= size;
+        }
+    }
+    if parts.is_empty() {
+        return "0s".to_string();
+    }
+    let sign = if secs < 0.0 { "-" } else { "" };
+    format!("{}{}", sign, parts.join(" "))
+}
+
+/// `{{duration 8100}}` is `2h 15m`; `precision=1` keeps only the largest
+/// unit.
+fn duration(h: &Helper) -> Result<Value, RenderError> {
+    let precision = match h.hash_get("precision") {
+        Some(p) => p
+            .value()
+            .as_u64()
+            .filter(|&p| p > 0)
+            .ok_or_else(|| RenderError::new("duration precision is not a positive integer"))?
+            as usize,
+        None => UNITS.len(),
+    };
+    Ok(format_duration(seconds(h, 0)?, precision).into())
+}
+
+/// `3 days ago`, or `in 3 days` for a future time, in the largest whole
+/// unit.
+pub fn relative(secs: i64) -> String {
+    let span = secs.abs();
+    let (count, name) = UNITS
+        .iter()
+        .find(|&&(_, _, size)| span >= size)
+        .map_or((0, "second"), |&(_, name, size)| (span / size, name));
+    if count == 0 {
+        return "just now".to_string();
+    }
+    let plural = if count == 1 { "" } else { "s" };
+    if secs >= 0 {
+        format!("{} {}{} ago", count, name, plural)
+    } else {
+        format!("in {} {}{}", count, name, plural)
+    }
+}
+
+/// `{{time_ago root.last_seen}}`, relative to the time of this run.
+fn time_ago(h: &Helper) -> Result<Value, RenderError> {
+    let v = param(h, 0)?;
+    let date =
+        parse(v).ok_or_else(|| RenderError::new(format!("time_ago helper: not a date: {}", v)))?;
+    let secs = render::now().signed_duration_since(date).num_seconds();
+    Ok(relative(secs).into())
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "date_format", date_format);
+    register_value(hb, "now", now);
+    register_value(hb, "duration", duration);
+    register_value(hb, "time_ago", time_ago);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_dates() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "ts": "2019-06-01T14:30:00+02:00", "day": "2019-06-01", "epoch": 1559392200, "before": -1.5,
+        });
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{date_format ts \"%Y-%m-%d %H:%M %z\"}}"),
+            "2019-06-01 14:30 +0200"
+        );
+        assert_eq!(render("{{date_format day \"%B %e, %Y\"}}"), "June  1, 2019");
+        assert_eq!(
+            render("{{date_format epoch \"%FT%T\"}}"),
+            "2019-06-01T12:30:00"
+        );
+        assert_eq!(
+            render("{{date_format before \"%FT%T%.3f\"}}"),
+            "1969-12-31T23:59:58.500"
+        );
+        assert_eq!(
+            render("{{now \"%Y\"}}"),
+            render::now().format("%Y").to_string()
+        );
+        assert!(hb
+            .render_template("{{date_format day \"%Q\"}}", &data)
+            .is_err());
+        assert!(hb
+            .render_template("{{date_format \"soon\" \"%Y\"}}", &data)
+            .is_err());
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(parse_iso_duration("PT2H15M"), Some(8100.0));
+        assert_eq!(parse_iso_duration("P1W1DT0,5S"), Some(691_200.5));
+        assert_eq!(parse_iso_duration("P1M"), None);
+        assert_eq!(parse_iso_duration("PT"), None);
+        assert_eq!(format_duration(8100.0, 7), "2h 15m");
+        assert_eq!(format_duration(-90061.0, 2), "-1d 1h");
+        assert_eq!(format_duration(0.2, 7), "0s");
+
+        assert_eq!(relative(3 * 86400 + 5), "3 days ago");
+        assert_eq!(relative(-3600), "in 1 hour");
+        assert_eq!(relative(0), "just now");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"d": "P2DT3H"});
+        let rendered = hb.render_template("{{duration d}}/{{duration d precision=1}}", &data);
+        assert_eq!(rendered.unwrap(), "2d 3h/2d");
+    }
+}
@@ -0,0 +1,235 @@
+//! Hash and identifier helpers: `sha256`, `sha1`, `md5` and `uuid`.
+//!
+//! `{{sha256 value}}` is the lowercase hex digest of a string, or of the
+//! compact JSON of any other value; `length=8` keeps the first eight
+//! characters, for cache-busting tokens.  `sha1` and `md5` are there for
+//! formats that call for them, not for anything needing security.
+//!
+//! `{{uuid "dns" "example.com"}}` derives a name-based (version 5) UUID, the
+//! same on every run.  The namespace is `dns`, `url`, `oid`, `x500` or any
+//! UUID.  `{{uuid "random"}}` is a random (version 4) UUID, so output using
+//! it changes on every render.
+
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use super::encoding::hex_decode;
+use super::{param, register_value, str_param};
+
+/// MD5, from RFC 1321.
+fn md5(input: &[u8]) -> [u8; 16] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32)
+        .collect();
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for chunk in message.chunks(64) {
+        let m: Vec<u32> = chunk
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(k[i])
+                .wrapping_add(m[g])
+                .rotate_left(S[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (s, v) in state.iter_mut().zip(&[a, b, c, d]) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (out, word) in digest.chunks_mut(4).zip(&state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash(h: &Helper, f: fn(&[u8]) -> String) -> Result<Value, RenderError> {
+    let input = match param(h, 0)? {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    let mut digest = f(input.as_bytes());
+    if let Some(length) = h.hash_get("length") {
+        match length.value().as_u64() {
+            Some(n) if n > 0 => digest.truncate(n as usize),
+            _ => {
+                let msg = format!("{} helper length must be a positive integer", h.name());
+                return Err(RenderError::new(msg));
+            }
+        }
+    }
+    Ok(digest.into())
+}
+
+fn sha256(h: &Helper) -> Result<Value, RenderError> {
+    hash(h, |b| hex(&Sha256::digest(b)))
+}
+
+fn sha1(h: &Helper) -> Result<Value, RenderError> {
+    hash(h, |b| hex(&Sha1::digest(b)))
+}
+
+fn md5_helper(h: &Helper) -> Result<Value, RenderError> {
+    hash(h, |b| hex(&md5(b)))
+}
+
+/// The namespaces defined by RFC 4122, appendix C.
+fn namespace(name: &str) -> Option<[u8; 16]> {
+    let uuid = match name {
+        "dns" => "6ba7b810-9dad-11d1-80b4-00c04fd430c8",
+        "url" => "6ba7b811-9dad-11d1-80b4-00c04fd430c8",
+        "oid" => "6ba7b812-9dad-11d1-80b4-00c04fd430c8",
+        "x500" => "6ba7b814-9dad-11d1-80b4-00c04fd430c8",
+        other => other,
+    };
+    parse_uuid(uuid)
+}
+
+/// Parse a UUID in its 8-4-4-4-12 hex digit form.
+fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = s.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    if lengths != [8, 4, 4, 4, 12] {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hex_decode(&groups.concat())?);
+    Some(bytes)
+}
+
+/// Set the version and RFC 4122 variant bits, and format.
+fn format_uuid(mut bytes: [u8; 16], version: u8) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | (version << 4);
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+pub fn uuid5(namespace: [u8; 16], name: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(namespace);
+    hasher.input(name.as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hasher.result()[..16]);
+    format_uuid(bytes, 5)
+}
+
+fn uuid(h: &Helper) -> Result<Value, RenderError> {
+    let ns = str_param(h, 0)?;
+    if ns == "random" {
+        return Ok(format_uuid(rand::random(), 4).into());
+    }
+    let ns = namespace(ns)
+        .ok_or_else(|| RenderError::new(format!("uuid helper: unknown namespace {:?}", ns)))?;
+    Ok(uuid5(ns, str_param(h, 1)?).into())
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "sha256", sha256);
+    register_value(hb, "sha1", sha1);
+    register_value(hb, "md5", md5_helper);
+    register_value(hb, "uuid", uuid);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hashes_strings_and_values() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        let fox = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(hex(&md5(fox)), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(hex(&md5(&[b'a'; 64])), "014842d480b571495a4a0363793f7367");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"s": "abc", "v": {"a": [1, 2]}});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+        assert_eq!(
+            render("{{sha256 s}}"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            render("{{sha1 s}}"),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(render("{{md5 s length=8}}"), "90015098");
+        assert_eq!(render("{{md5 v}}"), render("{{md5 \"{\\\"a\\\":[1,2]}\"}}"));
+        assert!(hb.render_template("{{md5 s length=0}}", &data).is_err());
+    }
+
+    #[test]
+    fn derives_uuids() {
+        // Python: uuid.uuid5(uuid.NAMESPACE_DNS, "python.org")
+        let dns = namespace("dns").unwrap();
+        assert_eq!(
+            uuid5(dns, "python.org"),
+            "886313e1-3b8a-5372-9b90-0c9aee199e5d"
+        );
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let custom = "{{uuid \"886313e1-3b8a-5372-9b90-0c9aee199e5d\" \"x\"}}";
+        let first = hb.render_template(custom, &()).unwrap();
+        assert_eq!(hb.render_template(custom, &()).unwrap(), first);
+
+        let random = hb.render_template("{{uuid \"random\"}}", &()).unwrap();
+        assert_eq!(random.len(), 36);
+        assert_eq!(&random[14..15], "4");
+        assert_ne!(
+            hb.render_template("{{uuid \"random\"}}", &()).unwrap(),
+            random
+        );
+        assert!(hb.render_template("{{uuid \"nope\" \"x\"}}", &()).is_err());
+        for bad in &[
+            "886313e13b8a53729b900c9aee199e5d",
+            "886313e1-3b8a-5372-9b90-0c9aee199e5",
+            "+86313e1-3b8a-5372-9b90-0c9aee199e5d",
+            "--886313e13b8a-5372-9b90-0c9aee199e5d",
+            "886313e1-3b8a-5372-9b90-0c9aee199e5g",
+        ] {
+            assert_eq!(parse_uuid(bad), None, "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Encoding helpers: `base64_encode`, `base64_decode`, `hex_encode`,
+//! `hex_decode`, `url_encode` and `url_decode`.
+//!
+//! Strings are encoded as their UTF-8 bytes, and decoding must give valid
+//! UTF-8 back.  Base64 uses the standard alphabet with padding, or the
+//! URL-safe one without it given `url=true`; decoding accepts either.
+//!
+//! `url_encode` percent-encodes everything but the RFC 3986 unreserved
+//! characters, so its output is safe as a path segment or a query string
+//! key or value.  With `form=true` spaces become `+`, as in HTML form
+//! data, and `url_decode` takes `+` back to a space.
+
+use handlebars::{Handlebars, Helper, JsonRender, RenderError};
+use serde_json::Value;
+
+use super::{flag, param, register_value, str_param};
+
+const STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_SAFE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn base64_encode(bytes: &[u8], url: bool) -> String {
+    let alphabet = if url { URL_SAFE } else { STANDARD };
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..=chunk.len() {
+            out.push(alphabet[((n >> (18 - 6 * i)) & 63) as usize] as char);
+        }
+        if !url {
+            out.push_str(&"=="[..3 - chunk.len()]);
+        }
+    }
+    out
+}
+
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'\n' | b'\r' => continue,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // A lone trailing character can't hold a whole byte.
+    if bits >= 6 {
+        return None;
+    }
+    Some(out)
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    // from_str_radix would take a sign, as in "+f".
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+pub fn url_encode(bytes: &[u8], form: bool) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b' ' if form => out.push('+'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+pub fn url_decode(s: &str, form: bool) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                // from_str_radix would take a sign, as in "%+1".
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' if form => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    Some(out)
+}
+
+fn decoded(h: &Helper, bytes: Option<Vec<u8>>) -> Result<Value, RenderError> {
+    let bytes =
+        bytes.ok_or_else(|| RenderError::new(format!("{} helper: invalid input", h.name())))?;
+    String::from_utf8(bytes)
+        .map(Value::from)
+        .map_err(|_| RenderError::new(format!("{} helper: result is not UTF-8", h.name())))
+}
+
+fn base64_encode_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(base64_encode(str_param(h, 0)?.as_bytes(), flag(h, "url")).into())
+}
+
+fn base64_decode_helper(h: &Helper) -> Result<Value, RenderError> {
+    decoded(h, base64_decode(str_param(h, 0)?))
+}
+
+fn hex_encode_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(hex_encode(str_param(h, 0)?.as_bytes()).into())
+}
+
+fn hex_decode_helper(h: &Helper) -> Result<Value, RenderError> {
+    decoded(h, hex_decode(str_param(h, 0)?))
+}
+
+fn url_encode_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(url_encode(param(h, 0)?.render().as_bytes(), flag(h, "form")).into())
+}
+
+fn url_decode_helper(h: &Helper) -> Result<Value, RenderError> {
+    decoded(h, url_decode(str_param(h, 0)?, flag(h, "form")))
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "base64_encode", base64_encode_helper);
+    register_value(hb, "base64_decode", base64_decode_helper);
+    register_value(hb, "hex_encode", hex_encode_helper);
+    register_value(hb, "hex_decode", hex_decode_helper);
+    register_value(hb, "url_encode", url_encode_helper);
+    register_value(hb, "url_decode", url_decode_helper);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes() {
+        let cases: &[(&str, &str)] = &[
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+        ];
+        for (plain, encoded) in cases {
+            assert_eq!(base64_encode(plain.as_bytes(), false), *encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(base64_encode(&[0xfb, 0xff], true), "-_8");
+        assert_eq!(base64_decode("-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(base64_decode("Z"), None);
+        assert_eq!(hex_encode(b"\x00hi"), "006869");
+        assert_eq!(hex_decode("006869").unwrap(), b"\x00hi");
+        assert_eq!(hex_decode("0g"), None);
+        assert_eq!(hex_decode("+f"), None);
+        assert_eq!(hex_decode("+f+f"), None);
+        assert_eq!(url_encode("a b/ü~".as_bytes(), false), "a%20b%2F%C3%BC~");
+        assert_eq!(url_encode(b"a b+", true), "a+b%2B");
+        assert_eq!(url_decode("a+b%2f", false).unwrap(), b"a+b/");
+        assert_eq!(url_decode("a+b%2B", true).unwrap(), b"a b+");
+        assert_eq!(url_decode("%2", false), None);
+        assert_eq!(url_decode("%+1", false), None);
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"pw": "s3cr3t!"});
+        let rendered = hb.render_template("{{base64_decode (base64_encode pw)}}", &data);
+        assert_eq!(rendered.unwrap(), "s3cr3t!");
+        assert!(hb.render_template("{{hex_decode \"ff\"}}", &data).is_err());
+        let rendered = hb.render_template("?q={{url_encode pw}}&n={{url_encode 1}}", &data);
+        assert_eq!(rendered.unwrap(), "?q=s3cr3t%21&n=1");
+    }
+}
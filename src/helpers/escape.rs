@@ -0,0 +1,183 @@
+//! Escaping and quoting helpers: `html_escape`, `html_unescape`,
+//! `latex_escape`, `sh_quote` and `ps_quote`.
+//!
+//! The escape mode of a spec entry applies to `{{value}}` only.  Helper
+//! results are written as they are, and `{{{value}}}` writes trusted data
+//! unescaped, so templates mixing formats can escape exactly what they mean
+//! to with `{{html_escape value}}`, whatever the mode.  `html_unescape`
+//! decodes the named entities `&amp;`, `&lt;`, `&gt;`, `&quot;` and `&apos;`
+//! and numeric ones like `&#39;` or `&#x27;`, leaving anything else alone.
+//! `latex_escape` makes text safe in LaTeX, as `escape: latex` in front
+//! matter does for every `{{value}}`.
+//!
+//! `{{sh_quote value}}` is a single-quoted POSIX shell word, and
+//! `{{ps_quote value}}` a single-quoted PowerShell string, each standing for
+//! exactly the rendered value whatever it contains.
+
+use handlebars::{html_escape, Handlebars, Helper, JsonRender, RenderError};
+use serde_json::Value;
+
+use super::{param, register_value, str_param};
+
+/// The character an entity body such as `amp` or `#x27` stands for.
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => {
+            let code = name.strip_prefix('#')?;
+            let code = match code.strip_prefix('x').or_else(|| code.strip_prefix('X')) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            std::char::from_u32(code)
+        }
+    }
+}
+
+pub fn html_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest
+            .find(';')
+            .and_then(|end| entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escape the characters LaTeX treats specially in text.
+pub fn latex_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '~' => out.push_str(r"\textasciitilde{}"),
+            '^' => out.push_str(r"\textasciicircum{}"),
+            '\\' => out.push_str(r"\textbackslash{}"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn sh_quote(s: &str) -> String {
+    // A quote can't be escaped inside single quotes: close the string, add
+    // an escaped quote and reopen it.
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+pub fn ps_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        // PowerShell also takes the typographic single quotes as quotes.
+        if let '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' = c {
+            out.push(c);
+        }
+        out.push(c);
+    }
+    out.push('\'');
+    out
+}
+
+fn html_escape_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(html_escape(&param(h, 0)?.render()).into())
+}
+
+fn html_unescape_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(html_unescape(str_param(h, 0)?).into())
+}
+
+fn latex_escape_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(latex_escape(&param(h, 0)?.render()).into())
+}
+
+fn sh_quote_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(sh_quote(&param(h, 0)?.render()).into())
+}
+
+fn ps_quote_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(ps_quote(&param(h, 0)?.render()).into())
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "html_escape", html_escape_helper);
+    register_value(hb, "html_unescape", html_unescape_helper);
+    register_value(hb, "latex_escape", latex_escape_helper);
+    register_value(hb, "sh_quote", sh_quote_helper);
+    register_value(hb, "ps_quote", ps_quote_helper);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escapes_html_explicitly() {
+        assert_eq!(
+            html_unescape("&lt;a href=&quot;?a=1&amp;b=2&quot;&gt; &#39;&#x4e2d; &copy; & x;"),
+            "<a href=\"?a=1&b=2\"> '\u{4e2d} &copy; & x;"
+        );
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        hb.register_escape_fn(handlebars::no_escape);
+        let data = serde_json::json!({"s": "<b>&amp;</b>"});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+        assert_eq!(render("{{html_escape s}}"), "&lt;b&gt;&amp;amp;&lt;/b&gt;");
+        assert_eq!(render("{{html_unescape s}}"), "<b>&</b>");
+        assert_eq!(
+            render("{{html_escape (html_unescape s)}}"),
+            "&lt;b&gt;&amp;&lt;/b&gt;"
+        );
+
+        hb.register_escape_fn(html_escape);
+        let rendered = hb.render_template("{{s}} {{{s}}} {{html_unescape s}}", &data);
+        assert_eq!(
+            rendered.unwrap(),
+            "&lt;b&gt;&amp;amp;&lt;/b&gt; <b>&amp;</b> <b>&</b>"
+        );
+    }
+
+    #[test]
+    fn escapes_latex() {
+        assert_eq!(
+            latex_escape(r"50% of $x_1 & {y} #2 ~ ^ \"),
+            r"50\% of \$x\_1 \& \{y\} \#2 \textasciitilde{} \textasciicircum{} \textbackslash{}"
+        );
+    }
+
+    #[test]
+    fn quotes_for_shells() {
+        assert_eq!(sh_quote(""), "''");
+        assert_eq!(sh_quote("it's $HOME"), r"'it'\''s $HOME'");
+        assert_eq!(ps_quote("it's $env:HOME"), "'it''s $env:HOME'");
+        assert_eq!(ps_quote("\u{2019}"), "'\u{2019}\u{2019}'");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"n": 8080, "s": "a b"});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+        assert_eq!(render("{{sh_quote s}} {{sh_quote n}}"), "'a b' '8080'");
+    }
+}
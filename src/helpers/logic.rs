@@ -0,0 +1,148 @@
+//! Helpers for conditions and values that may be missing: `default`,
+//! `coalesce`, `contains` and `in`, and the `let` block helper.
+//!
+//! Strict mode only checks plain expressions, so a missing variable passed
+//! to these is simply absent instead of an error.
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    Renderable,
+};
+use serde_json::{Map, Value};
+
+use super::{param, register_value};
+
+/// `{{default value "fallback"}}` is `value` unless it is missing or null.
+fn default(h: &Helper) -> Result<Value, RenderError> {
+    let value = param(h, 0)?;
+    match value {
+        Value::Null => Ok(param(h, 1)?.clone()),
+        _ => Ok(value.clone()),
+    }
+}
+
+/// `{{coalesce a b c}}` is the first parameter that is neither missing nor
+/// null, or null if none is.
+fn coalesce(h: &Helper) -> Result<Value, RenderError> {
+    let first = h.params().iter().map(|p| p.value()).find(|v| !v.is_null());
+    Ok(first.cloned().unwrap_or(Value::Null))
+}
+
+/// Whether the array `haystack` has an element equal to `needle`, the
+/// string has it as a substring, or the object has it as a key.
+fn has(haystack: &Value, needle: &Value) -> bool {
+    match (haystack, needle) {
+        (Value::Array(items), _) => items.contains(needle),
+        (Value::String(s), Value::String(sub)) => s.contains(sub.as_str()),
+        (Value::Object(map), Value::String(key)) => map.contains_key(key),
+        _ => false,
+    }
+}
+
+/// `{{#if (contains tags "beta")}}`.
+fn contains(h: &Helper) -> Result<Value, RenderError> {
+    Ok(has(param(h, 0)?, param(h, 1)?).into())
+}
+
+/// `{{#if (in "beta" tags)}}`, `contains` with the arguments swapped.
+fn is_in(h: &Helper) -> Result<Value, RenderError> {
+    Ok(has(param(h, 1)?, param(h, 0)?).into())
+}
+
+/// `{{#let total=(sum items "qty") label="Total"}}...{{/let}}` evaluates
+/// its hash once and makes each entry a variable within the block.
+///
+/// The variables are pushed like block parameters, and share their quirk in
+/// this handlebars version: within the block, `this` resolves to them rather
+/// than to the current element of an inner `each`, so give such loops a
+/// block parameter of their own.
+struct LetHelper;
+
+impl HelperDef for LetHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let t = match h.template() {
+            Some(t) => t,
+            None => return Err(RenderError::new("let helper must be used as a block")),
+        };
+        let vars: Map<String, Value> = h
+            .hash()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.value().clone()))
+            .collect();
+
+        rc.push_block_context(&vars)?;
+        let result = t.render(r, ctx, rc, out);
+        rc.pop_block_context();
+        result
+    }
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "default", default);
+    register_value(hb, "coalesce", coalesce);
+    register_value(hb, "contains", contains);
+    register_value(hb, "in", is_in);
+    hb.register_helper("let", Box::new(LetHelper));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn falls_back_on_missing_values() {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register(&mut hb);
+        let data = serde_json::json!({"set": 0, "null": null});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{default unset \"x\"}} {{default set \"x\"}}"),
+            "x 0"
+        );
+        assert_eq!(render("{{default null \"x\"}}"), "x");
+        assert_eq!(render("{{coalesce unset null set}}"), "0");
+        assert_eq!(render("[{{coalesce unset null}}]"), "[]");
+    }
+
+    #[test]
+    fn tests_membership() {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register(&mut hb);
+        let data = serde_json::json!({"tags": ["a", 1], "name": "ttgen", "map": {"k": 0}});
+        let check = |cond: &str| {
+            let source = format!("{{{{#if {}}}}}y{{{{else}}}}n{{{{/if}}}}", cond);
+            hb.render_template(&source, &data).unwrap()
+        };
+
+        assert_eq!(check("(contains tags \"a\")"), "y");
+        assert_eq!(check("(contains tags \"1\")"), "n");
+        assert_eq!(check("(in 1 tags)"), "y");
+        assert_eq!(check("(contains name \"tg\")"), "y");
+        assert_eq!(check("(contains map \"k\")"), "y");
+        assert_eq!(check("(contains unset \"a\")"), "n");
+    }
+
+    #[test]
+    fn binds_block_variables() {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register(&mut hb);
+        let data = serde_json::json!({"a": 1, "list": ["x", "y"]});
+
+        let source = "{{#let v=(coalesce unset a) w=\"!\"}}\
+                      {{v}}{{w}}{{#each list as |i|}}{{i}}{{v}}{{/each}}\
+                      {{#let v=2}}{{v}}{{w}}{{/let}}{{v}}{{/let}}";
+        assert_eq!(hb.render_template(source, &data).unwrap(), "1!x1y12!1");
+        assert!(hb.render_template("{{let v=1}}", &data).is_err());
+    }
+}
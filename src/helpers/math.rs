@@ -0,0 +1,191 @@
+//! Arithmetic on JSON numbers: `add`, `sub`, `mul`, `div`, `mod`, `round`,
+//! `floor` and `ceil`.
+//!
+//! Integers stay integers while the result is exact and fits in an `i64`;
+//! otherwise the operation is done in floating point.  `{{div 7 2}}` is 3.5
+//! and `{{div 6 2}}` is 3.
+
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::{Number, Value};
+
+use super::{param, register_value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Num {
+    Int(i64),
+    Float(f64),
+}
+
+impl Num {
+    pub(super) fn from_json(v: &Value) -> Option<Self> {
+        let n = v.as_f64()?;
+        Some(v.as_i64().map_or(Num::Float(n), Num::Int))
+    }
+
+    pub(super) fn as_f64(self) -> f64 {
+        match self {
+            Num::Int(i) => i as f64,
+            Num::Float(f) => f,
+        }
+    }
+
+    fn is_zero(self) -> bool {
+        self.as_f64() == 0.0
+    }
+}
+
+fn num(h: &Helper, i: usize) -> Result<Num, RenderError> {
+    let v = param(h, i)?;
+    Num::from_json(v).ok_or_else(|| {
+        RenderError::new(format!(
+            "{} helper parameter {} is not a number: {}",
+            h.name(),
+            i + 1,
+            v
+        ))
+    })
+}
+
+pub(super) fn value(h: &Helper, n: Num) -> Result<Value, RenderError> {
+    match n {
+        Num::Int(i) => Ok(i.into()),
+        Num::Float(f) => Number::from_f64(f)
+            .map(Value::Number)
+            .ok_or_else(|| RenderError::new(format!("{} helper result is not finite", h.name()))),
+    }
+}
+
+pub(super) type IntOp = fn(i64, i64) -> Option<i64>;
+pub(super) type FloatOp = fn(f64, f64) -> f64;
+
+pub(super) fn apply(a: Num, b: Num, int_op: IntOp, float_op: FloatOp) -> Num {
+    if let (Num::Int(a), Num::Int(b)) = (a, b) {
+        if let Some(n) = int_op(a, b) {
+            return Num::Int(n);
+        }
+    }
+    Num::Float(float_op(a.as_f64(), b.as_f64()))
+}
+
+/// Apply an operation across all the parameters, left to right.
+fn fold(h: &Helper, int_op: IntOp, float_op: FloatOp) -> Result<Value, RenderError> {
+    let mut acc = num(h, 0)?;
+    // At least two operands.
+    num(h, 1)?;
+    for i in 1..h.params().len() {
+        acc = apply(acc, num(h, i)?, int_op, float_op);
+    }
+    value(h, acc)
+}
+
+fn add(h: &Helper) -> Result<Value, RenderError> {
+    fold(h, i64::checked_add, |a, b| a + b)
+}
+
+fn sub(h: &Helper) -> Result<Value, RenderError> {
+    fold(h, i64::checked_sub, |a, b| a - b)
+}
+
+fn mul(h: &Helper) -> Result<Value, RenderError> {
+    fold(h, i64::checked_mul, |a, b| a * b)
+}
+
+fn divisor(h: &Helper) -> Result<Num, RenderError> {
+    let b = num(h, 1)?;
+    if b.is_zero() {
+        return Err(RenderError::new(format!(
+            "{} helper: division by zero",
+            h.name()
+        )));
+    }
+    Ok(b)
+}
+
+fn div(h: &Helper) -> Result<Value, RenderError> {
+    let (a, b) = (num(h, 0)?, divisor(h)?);
+    let exact = |a: i64, b: i64| match a.checked_rem(b) {
+        Some(0) => a.checked_div(b),
+        _ => None,
+    };
+    value(h, apply(a, b, exact, |a, b| a / b))
+}
+
+fn modulo(h: &Helper) -> Result<Value, RenderError> {
+    let (a, b) = (num(h, 0)?, divisor(h)?);
+    value(h, apply(a, b, i64::checked_rem, |a, b| a % b))
+}
+
+/// An integer result, when the float is within range of one.
+fn integral(f: f64) -> Num {
+    if f >= i64::MIN as f64 && f < i64::MAX as f64 {
+        Num::Int(f as i64)
+    } else {
+        Num::Float(f)
+    }
+}
+
+/// `{{round x}}` rounds to an integer, `{{round x 2}}` to two decimals.
+fn round(h: &Helper) -> Result<Value, RenderError> {
+    let x = num(h, 0)?;
+    match h.param(1) {
+        None => value(h, integral(x.as_f64().round())),
+        Some(_) => {
+            let digits = match num(h, 1)? {
+                Num::Int(d) if (0..=15).contains(&d) => d as i32,
+                _ => {
+                    return Err(RenderError::new(
+                        "round digits must be an integer from 0 to 15",
+                    ))
+                }
+            };
+            let scale = 10f64.powi(digits);
+            value(h, Num::Float((x.as_f64() * scale).round() / scale))
+        }
+    }
+}
+
+fn floor(h: &Helper) -> Result<Value, RenderError> {
+    value(h, integral(num(h, 0)?.as_f64().floor()))
+}
+
+fn ceil(h: &Helper) -> Result<Value, RenderError> {
+    value(h, integral(num(h, 0)?.as_f64().ceil()))
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "add", add);
+    register_value(hb, "sub", sub);
+    register_value(hb, "mul", mul);
+    register_value(hb, "div", div);
+    register_value(hb, "mod", modulo);
+    register_value(hb, "round", round);
+    register_value(hb, "floor", floor);
+    register_value(hb, "ceil", ceil);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn computes_with_integer_and_float_coercion() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data =
+            serde_json::json!({"port": 8000, "ratio": 0.125, "big": i64::MAX, "min": i64::MIN});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(render("{{add port 80 1}}"), "8081");
+        assert_eq!(render("{{sub port ratio}}"), "7999.875");
+        assert_eq!(render("{{mul ratio 8}}"), "1");
+        assert_eq!(render("{{div 6 2}} {{div 7 2}} {{mod 7 2}}"), "3 3.5 1");
+        assert_eq!(render("{{add big 1}}"), "9223372036854776000");
+        assert_eq!(render("{{div min -1}}"), "9223372036854776000");
+        assert_eq!(render("{{round 2.5}} {{round ratio 2}}"), "3 0.13");
+        assert_eq!(render("{{floor -1.5}} {{ceil 1.2}}"), "-2 2");
+        assert_eq!(render("{{floor (mul (div port 3) 100)}}"), "266666");
+
+        assert!(hb.render_template("{{div 1 0}}", &data).is_err());
+        assert!(hb.render_template("{{add port \"1\"}}", &data).is_err());
+    }
+}
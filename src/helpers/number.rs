@@ -0,0 +1,189 @@
+//! Number formatting: `num_format`.
+//!
+//! `{{num_format n locale="de-DE" style="currency" currency="EUR"}}` gives
+//! `1.234,50 €`.  Styles are `decimal` (the default), `percent`, which
+//! multiplies by 100, and `currency`.  `decimals=N` fixes the number of
+//! decimals; otherwise decimals are kept as needed for `decimal` and
+//! default to none for `percent` and the currency's minor unit for
+//! `currency`.
+//!
+//! There is no locale database here, just the separators and currency
+//! placement for a handful of common locales.  A bare language such as
+//! `de` picks the first locale listed for it.
+
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::Value;
+
+use super::{param, register_value};
+
+struct Locale {
+    name: &'static str,
+    group: &'static str,
+    decimal: char,
+    /// Currency pattern, `n` standing for the number and `¤` for the symbol.
+    currency: &'static str,
+    percent: &'static str,
+}
+
+const NBSP: &str = "\u{a0}";
+
+#[rustfmt::skip]
+const LOCALES: &[Locale] = &[
+    Locale { name: "en-US", group: ",", decimal: '.', currency: "¤n", percent: "n%" },
+    Locale { name: "en-GB", group: ",", decimal: '.', currency: "¤n", percent: "n%" },
+    Locale { name: "de-DE", group: ".", decimal: ',', currency: "n\u{a0}¤", percent: "n\u{a0}%" },
+    Locale { name: "de-CH", group: "’", decimal: '.', currency: "¤\u{a0}n", percent: "n%" },
+    Locale { name: "fr-FR", group: "\u{202f}", decimal: ',', currency: "n\u{a0}¤", percent: "n\u{a0}%" },
+    Locale { name: "es-ES", group: ".", decimal: ',', currency: "n\u{a0}¤", percent: "n\u{a0}%" },
+    Locale { name: "it-IT", group: ".", decimal: ',', currency: "n\u{a0}¤", percent: "n%" },
+    Locale { name: "nl-NL", group: ".", decimal: ',', currency: "¤\u{a0}n", percent: "n%" },
+    Locale { name: "pt-BR", group: ".", decimal: ',', currency: "¤\u{a0}n", percent: "n%" },
+    Locale { name: "sv-SE", group: NBSP, decimal: ',', currency: "n\u{a0}¤", percent: "n\u{a0}%" },
+    Locale { name: "ja-JP", group: ",", decimal: '.', currency: "¤n", percent: "n%" },
+];
+
+/// Symbol and minor unit digits for a currency code.
+fn currency(code: &str) -> (&str, usize) {
+    match code {
+        "USD" => ("$", 2),
+        "EUR" => ("€", 2),
+        "GBP" => ("£", 2),
+        "JPY" => ("¥", 0),
+        "CHF" => ("CHF", 2),
+        "SEK" => ("kr", 2),
+        "BRL" => ("R$", 2),
+        _ => (code, 2),
+    }
+}
+
+fn locale(name: &str) -> Option<&'static Locale> {
+    let exact = LOCALES.iter().find(|l| l.name.eq_ignore_ascii_case(name));
+    exact.or_else(|| {
+        LOCALES
+            .iter()
+            .find(|l| l.name.split('-').next().unwrap().eq_ignore_ascii_case(name))
+    })
+}
+
+/// `n` with `decimals` digits after the point, or as many as it needs, and
+/// separators from `locale`.  Keeps the sign.
+fn digits(n: f64, decimals: Option<usize>, locale: &Locale) -> String {
+    let text = match decimals {
+        // Round half away from zero, as people do; `{:.N}` rounds to even.
+        Some(d) => {
+            let scale = 10f64.powi(d as i32);
+            format!("{:.*}", d, (n.abs() * scale).round() / scale)
+        }
+        None => n.abs().to_string(),
+    };
+    let (int, frac) = match text.find('.') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (&text[..], ""),
+    };
+
+    let mut out = String::new();
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            out.push_str(locale.group);
+        }
+        out.push(c);
+    }
+    if !frac.is_empty() {
+        out.push(locale.decimal);
+        out.push_str(frac);
+    }
+    out
+}
+
+fn string_hash<'a>(h: &'a Helper, name: &str, default: &'a str) -> Result<&'a str, RenderError> {
+    match h.hash_get(name) {
+        Some(v) => v
+            .value()
+            .as_str()
+            .ok_or_else(|| RenderError::new(format!("num_format {} is not a string", name))),
+        None => Ok(default),
+    }
+}
+
+fn num_format(h: &Helper) -> Result<Value, RenderError> {
+    let v = param(h, 0)?;
+    let n = v
+        .as_f64()
+        .ok_or_else(|| RenderError::new(format!("num_format helper: not a number: {}", v)))?;
+    let name = string_hash(h, "locale", "en-US")?;
+    let locale =
+        locale(name).ok_or_else(|| RenderError::new(format!("unknown locale: {}", name)))?;
+    let decimals = match h.hash_get("decimals") {
+        Some(d) => match d.value().as_u64() {
+            Some(d) if d <= 20 => Some(d as usize),
+            _ => return Err(RenderError::new("num_format decimals must be from 0 to 20")),
+        },
+        None => None,
+    };
+
+    let (pattern, text, symbol) = match string_hash(h, "style", "decimal")? {
+        "decimal" => ("n", digits(n, decimals, locale), ""),
+        "percent" => {
+            let text = digits(n * 100.0, decimals.or(Some(0)), locale);
+            (locale.percent, text, "")
+        }
+        "currency" => {
+            let (symbol, minor) = currency(string_hash(h, "currency", "USD")?);
+            let text = digits(n, decimals.or(Some(minor)), locale);
+            (locale.currency, text, symbol)
+        }
+        other => {
+            return Err(RenderError::new(format!(
+                "unknown num_format style: {}",
+                other
+            )))
+        }
+    };
+    // No sign when rounding left nothing but zeros.
+    let sign = if n < 0.0 && text.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        "-"
+    } else {
+        ""
+    };
+    let body = pattern.replacen('n', &text, 1).replace('¤', symbol);
+    Ok(format!("{}{}", sign, body).into())
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "num_format", num_format);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_for_locales() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"price": 1234.5, "ratio": 0.256, "big": -1234567});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(render("{{num_format big}}"), "-1,234,567");
+        assert_eq!(render("{{num_format price decimals=2}}"), "1,234.50");
+        assert_eq!(
+            render("{{num_format price locale=\"de-DE\" style=\"currency\" currency=\"EUR\"}}"),
+            "1.234,50\u{a0}€"
+        );
+        assert_eq!(
+            render("{{num_format price style=\"currency\" currency=\"JPY\"}}"),
+            "¥1,235"
+        );
+        assert_eq!(
+            render("{{num_format ratio style=\"percent\" decimals=1}}"),
+            "25.6%"
+        );
+        assert_eq!(
+            render("{{num_format ratio locale=\"fr\" style=\"percent\"}}"),
+            "26\u{a0}%"
+        );
+        assert!(hb
+            .render_template("{{num_format price locale=\"xx\"}}", &data)
+            .is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Path helpers: `basename`, `dirname`, `extension`, `with_extension`,
+//! `join_path` and `relative_to`.
+//!
+//! These work on the path strings alone and never touch the filesystem.
+//! `{{relative_to "docs/api/index.html" "docs/guide"}}` is
+//! `../api/index.html`; both paths must be relative, or both absolute.
+
+use std::path::{Component, Path, PathBuf};
+
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::Value;
+
+use super::{param, register_value, str_param};
+
+fn path_value(p: &Path) -> Value {
+    Value::String(p.to_string_lossy().into_owned())
+}
+
+fn basename(h: &Helper) -> Result<Value, RenderError> {
+    let path = Path::new(str_param(h, 0)?);
+    let name = path.file_name().unwrap_or_default();
+    Ok(Value::String(name.to_string_lossy().into_owned()))
+}
+
+fn dirname(h: &Helper) -> Result<Value, RenderError> {
+    let path = Path::new(str_param(h, 0)?);
+    Ok(path_value(path.parent().unwrap_or_else(|| Path::new(""))))
+}
+
+/// The extension without its dot, or `""`.
+fn extension(h: &Helper) -> Result<Value, RenderError> {
+    let path = Path::new(str_param(h, 0)?);
+    let ext = path.extension().unwrap_or_default();
+    Ok(Value::String(ext.to_string_lossy().into_owned()))
+}
+
+/// `{{with_extension "a/b.md" "html"}}` is `a/b.html`; an empty extension
+/// removes it.
+fn with_extension(h: &Helper) -> Result<Value, RenderError> {
+    let path = Path::new(str_param(h, 0)?);
+    let ext = str_param(h, 1)?.trim_start_matches('.');
+    Ok(path_value(&path.with_extension(ext)))
+}
+
+fn join_path(h: &Helper) -> Result<Value, RenderError> {
+    param(h, 0)?;
+    let mut path = PathBuf::new();
+    for i in 0..h.params().len() {
+        path.push(str_param(h, i)?);
+    }
+    Ok(path_value(&path))
+}
+
+/// Remove `.` and resolve `..` against earlier components, leaving leading
+/// `..` of relative paths in place.
+fn normalize(path: &Path) -> Vec<Component<'_>> {
+    let mut out: Vec<Component> = Vec::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => match out.last() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn relative_to(path: &Path, base: &Path) -> Result<PathBuf, String> {
+    if path.is_absolute() != base.is_absolute() {
+        return Err(format!(
+            "{} and {} must both be relative or both absolute",
+            path.display(),
+            base.display()
+        ));
+    }
+    let (path, base) = (normalize(path), normalize(base));
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    if base[common..].contains(&Component::ParentDir) {
+        return Err("base path escapes the common directory".to_string());
+    }
+
+    let mut out: PathBuf = base[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .collect();
+    out.extend(&path[common..]);
+    if out.as_os_str().is_empty() {
+        out.push(".");
+    }
+    Ok(out)
+}
+
+fn relative_to_helper(h: &Helper) -> Result<Value, RenderError> {
+    let path = Path::new(str_param(h, 0)?);
+    let base = Path::new(str_param(h, 1)?);
+    let relative = relative_to(path, base)
+        .map_err(|e| RenderError::new(format!("relative_to helper: {}", e)))?;
+    Ok(path_value(&relative))
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "basename", basename);
+    register_value(hb, "dirname", dirname);
+    register_value(hb, "extension", extension);
+    register_value(hb, "with_extension", with_extension);
+    register_value(hb, "join_path", join_path);
+    register_value(hb, "relative_to", relative_to_helper);
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manipulates_paths() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"p": "docs/guide/intro.md"});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{basename p}} {{dirname p}} {{extension p}}"),
+            "intro.md docs/guide md"
+        );
+        assert_eq!(
+            render("{{with_extension p \".html\"}}"),
+            "docs/guide/intro.html"
+        );
+        assert_eq!(render("{{with_extension p \"\"}}"), "docs/guide/intro");
+        assert_eq!(
+            render("{{join_path (dirname p) \"img\" \"a.png\"}}"),
+            "docs/guide/img/a.png"
+        );
+
+        let rel = |a: &str, b: &str| relative_to(Path::new(a), Path::new(b));
+        assert_eq!(
+            rel("docs/api/index.html", "docs/guide").unwrap(),
+            Path::new("../api/index.html")
+        );
+        assert_eq!(rel("/a/b", "/a/b/c/").unwrap(), Path::new(".."));
+        assert_eq!(rel("./a/../b", "b").unwrap(), Path::new("."));
+        assert_eq!(rel("../x", "y").unwrap(), Path::new("../../x"));
+        assert!(rel("x", "../y").is_err());
+        assert!(rel("/x", "y").is_err());
+    }
+}
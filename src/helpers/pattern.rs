@@ -0,0 +1,111 @@
+//! Regular expression helpers, using the `regex` crate's syntax.  String
+//! literals in templates are JSON strings, so backslashes are doubled:
+//!
+//! - `{{regex_match s "^v\\d+"}}` is whether `s` matches anywhere.
+//! - `{{regex_replace s "(\\w+)@" "$1 at "}}` replaces every match; the
+//!   replacement may refer to groups as `$1` or `$name`.
+//! - `(regex_captures s pattern)` is the first match's named groups as an
+//!   object, or all its groups as an array when none are named, starting
+//!   with the whole match.  Groups that didn't take part are null, and so is
+//!   the result when nothing matches.
+//!
+//! Compiled patterns are cached for the life of the process.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use handlebars::{Handlebars, Helper, RenderError};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use super::{register_value, str_param};
+
+static CACHE: Lazy<Mutex<HashMap<String, Regex>>> = Lazy::new(Default::default);
+
+fn compiled(h: &Helper, i: usize) -> Result<Regex, RenderError> {
+    let pattern = str_param(h, i)?;
+    let mut cache = CACHE.lock().unwrap();
+    if let Some(re) = cache.get(pattern) {
+        return Ok(re.clone());
+    }
+    let re =
+        Regex::new(pattern).map_err(|e| RenderError::new(format!("{} helper: {}", h.name(), e)))?;
+    cache.insert(pattern.to_string(), re.clone());
+    Ok(re)
+}
+
+fn regex_match(h: &Helper) -> Result<Value, RenderError> {
+    Ok(compiled(h, 1)?.is_match(str_param(h, 0)?).into())
+}
+
+fn regex_replace(h: &Helper) -> Result<Value, RenderError> {
+    let re = compiled(h, 1)?;
+    let replaced = re.replace_all(str_param(h, 0)?, str_param(h, 2)?);
+    Ok(replaced.into_owned().into())
+}
+
+fn regex_captures(h: &Helper) -> Result<Value, RenderError> {
+    let re = compiled(h, 1)?;
+    let caps = match re.captures(str_param(h, 0)?) {
+        Some(caps) => caps,
+        None => return Ok(Value::Null),
+    };
+    let group = |m: Option<regex::Match>| m.map_or(Value::Null, |m| m.as_str().into());
+
+    let named: Map<String, Value> = re
+        .capture_names()
+        .flatten()
+        .map(|name| (name.to_string(), group(caps.name(name))))
+        .collect();
+    if named.is_empty() {
+        Ok(caps.iter().map(group).collect())
+    } else {
+        Ok(Value::Object(named))
+    }
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "regex_match", regex_match);
+    register_value(hb, "regex_replace", regex_replace);
+    register_value(hb, "regex_captures", regex_captures);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_replaces_and_captures() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"v": "release-1.2"});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render(r#"{{#if (regex_match v "\\d+\\.\\d+$")}}yes{{/if}}"#),
+            "yes"
+        );
+        assert_eq!(
+            render(r#"{{regex_replace v "(\\d+)" "<$1>"}}"#),
+            "release-<1>.<2>"
+        );
+        assert_eq!(
+            render(
+                r#"{{#with (regex_captures v "(\\w+)-(\\d+)") as |c|}}{{c.[0]}} {{c.[2]}}{{/with}}"#
+            ),
+            "release-1 1"
+        );
+        assert_eq!(
+            render(r#"{{#with (regex_captures v "-(?P<major>\\d+)") as |c|}}{{c.major}}{{/with}}"#),
+            "1"
+        );
+        assert_eq!(
+            render(r#"{{#unless (regex_captures v "x")}}none{{/unless}}"#),
+            "none"
+        );
+        assert!(hb
+            .render_template(r#"{{regex_match v "("}}"#, &data)
+            .is_err());
+    }
+}
@@ -0,0 +1,427 @@
+//! Serialization helpers: `json`, `json_pretty`, `yaml` and `toml`.
+//!
+//! `{{json value}}` is the compact JSON for any value of the context, and
+//! `{{json_pretty value indent=4}}` the indented form, two spaces by default.
+//! Object keys keep the order of the data.
+//!
+//! `{{yaml value}}` is block-style YAML, nested by `indent=` spaces (two by
+//! default), without a trailing newline.  Strings are left plain only when
+//! YAML can't read them as anything else; multi-line strings become literal
+//! blocks.  To splice a fragment into a deeper level, indent it:
+//! `{{indent (yaml root.env) 8 skip_first=true}}`.
+//!
+//! `{{toml value}}` writes an object as a TOML document, with nested
+//! objects as `[tables]` and arrays of objects as `[[arrays of tables]]`,
+//! and anything else as an inline value.  TOML has no null, so null entries
+//! of tables are left out; nulls anywhere else are an error.
+
+use handlebars::{Handlebars, Helper, RenderError};
+use serde::Serialize;
+use serde_json::ser::{PrettyFormatter, Serializer};
+use serde_json::Value;
+
+use super::{param, register_value};
+
+/// The `indent=` of `h`, or `default`.
+fn indent(h: &Helper, default: u64) -> Result<usize, RenderError> {
+    match h.hash_get("indent") {
+        None => Ok(default as usize),
+        Some(v) => match v.value().as_u64() {
+            Some(n) if n <= 16 => Ok(n as usize),
+            _ => Err(RenderError::new(format!(
+                "{} indent must be an integer from 0 to 16",
+                h.name()
+            ))),
+        },
+    }
+}
+
+/// Whether `s` reads back as the same string when written plain in YAML.
+fn yaml_plain(s: &str) -> bool {
+    const RESERVED: &[&str] = &[
+        "null", "~", "true", "false", "yes", "no", "on", "off", "y", "n",
+    ];
+    let first = match s.chars().next() {
+        Some(c) => c,
+        None => return false,
+    };
+    (first.is_alphabetic() || first == '_' || first == '/')
+        && !s.ends_with(' ')
+        && !s.ends_with(':')
+        && !s.contains(": ")
+        && !s.contains(" #")
+        && !s.contains("  ")
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || "_./@+-:=()".contains(c))
+        && !RESERVED.iter().any(|r| r.eq_ignore_ascii_case(s))
+}
+
+fn yaml_scalar(v: &Value) -> String {
+    match v {
+        Value::String(s) if yaml_plain(s) => s.clone(),
+        // A JSON string is a valid YAML double-quoted scalar.
+        Value::String(_) => v.to_string(),
+        Value::Array(_) => "[]".to_string(),
+        Value::Object(_) => "{}".to_string(),
+        v => v.to_string(),
+    }
+}
+
+/// Whether `s` can be written as a literal block: several lines of text
+/// whose first line doesn't start with a space.
+fn yaml_literal(s: &str) -> bool {
+    s.trim_end_matches('\n').contains('\n')
+        && !s.starts_with(' ')
+        && !s.chars().any(|c| c.is_control() && c != '\n')
+}
+
+/// Write the entry starting with `prefix`, which is `key:` or `-` already
+/// indented to `depth`, followed by its value.
+fn yaml_entry(out: &mut String, prefix: &str, v: &Value, depth: usize, indent: usize) {
+    let nested = match v {
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+        _ => false,
+    };
+    match v {
+        _ if nested && prefix.ends_with('-') => {
+            // The first line of the item follows the dash.
+            let mut block = String::new();
+            yaml_block(&mut block, v, depth + 1, indent);
+            out.push_str(prefix);
+            out.push_str(&" ".repeat(indent - 1));
+            out.push_str(&block[(depth + 1) * indent..]);
+        }
+        _ if nested => {
+            out.push_str(prefix);
+            out.push('\n');
+            yaml_block(out, v, depth + 1, indent);
+        }
+        Value::String(s) if yaml_literal(s) => {
+            let body = s.trim_end_matches('\n');
+            let chomp = match s.len() - body.len() {
+                0 => "-",
+                1 => "",
+                _ => "+",
+            };
+            out.push_str(&format!("{} |{}\n", prefix, chomp));
+            let pad = " ".repeat((depth + 1) * indent);
+            for line in body.split('\n') {
+                if !line.is_empty() {
+                    out.push_str(&pad);
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+            // Kept trailing newlines beyond the first are blank lines.
+            for _ in 1..s.len() - body.len() {
+                out.push('\n');
+            }
+        }
+        v => {
+            out.push_str(&format!("{} {}\n", prefix, yaml_scalar(v)));
+        }
+    }
+}
+
+/// Write the entries of a non-empty array or object at `depth`.
+fn yaml_block(out: &mut String, v: &Value, depth: usize, indent: usize) {
+    let pad = " ".repeat(depth * indent);
+    match v {
+        Value::Array(items) => {
+            for item in items {
+                yaml_entry(out, &format!("{}-", pad), item, depth, indent);
+            }
+        }
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = yaml_scalar(&Value::String(key.clone()));
+                yaml_entry(out, &format!("{}{}:", pad, key), value, depth, indent);
+            }
+        }
+        _ => unreachable!("yaml_block takes arrays and objects"),
+    }
+}
+
+pub fn yaml(v: &Value, indent: usize) -> String {
+    let mut out = String::new();
+    match v {
+        Value::Array(a) if !a.is_empty() => yaml_block(&mut out, v, 0, indent),
+        Value::Object(o) if !o.is_empty() => yaml_block(&mut out, v, 0, indent),
+        v => out = yaml_scalar(v),
+    }
+    if out.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        toml_string(key)
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn toml_inline(v: &Value) -> Result<String, String> {
+    Ok(match v {
+        Value::Null => return Err("TOML has no null".to_string()),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => match n.as_f64() {
+            // Debug keeps the fraction of whole floats: 1.0, not 1.
+            Some(f) if n.is_f64() => format!("{:?}", f),
+            _ => n.to_string(),
+        },
+        Value::String(s) => toml_string(s),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(toml_inline).collect::<Result<_, _>>()?;
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(map) if map.is_empty() => "{}".to_string(),
+        Value::Object(map) => {
+            let mut entries = Vec::with_capacity(map.len());
+            for (k, v) in map.iter().filter(|(_, v)| !v.is_null()) {
+                entries.push(format!("{} = {}", toml_key(k), toml_inline(v)?));
+            }
+            format!("{{ {} }}", entries.join(", "))
+        }
+    })
+}
+
+fn is_table_array(v: &Value) -> bool {
+    v.as_array()
+        .is_some_and(|a| !a.is_empty() && a.iter().all(Value::is_object))
+}
+
+/// Write the table at `path`, as an element of an array of tables if
+/// `array_item`, then the tables nested in it.
+fn toml_table(
+    out: &mut String,
+    path: &mut Vec<String>,
+    map: &serde_json::Map<String, Value>,
+    array_item: bool,
+) -> Result<(), String> {
+    let nested = |v: &Value| v.is_object() || is_table_array(v);
+    let plain: Vec<(&String, &Value)> = map
+        .iter()
+        .filter(|(_, v)| !v.is_null() && !nested(v))
+        .collect();
+
+    // A table holding only tables is declared by their headers.
+    if array_item || (!path.is_empty() && (!plain.is_empty() || map.is_empty())) {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let name: Vec<String> = path.iter().map(|k| toml_key(k)).collect();
+        let (open, close) = if array_item { ("[[", "]]") } else { ("[", "]") };
+        out.push_str(&format!("{}{}{}\n", open, name.join("."), close));
+    }
+    for (k, v) in plain {
+        let value = toml_inline(v).map_err(|e| format!("{}: {}", k, e))?;
+        out.push_str(&format!("{} = {}\n", toml_key(k), value));
+    }
+    for (k, v) in map.iter().filter(|(_, v)| nested(v)) {
+        path.push(k.clone());
+        match v {
+            Value::Object(table) => toml_table(out, path, table, false)?,
+            Value::Array(items) => {
+                for item in items.iter().filter_map(Value::as_object) {
+                    toml_table(out, path, item, true)?;
+                }
+            }
+            _ => unreachable!("only tables are nested"),
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+pub fn toml(v: &Value) -> Result<String, String> {
+    match v {
+        Value::Object(map) => {
+            let mut out = String::new();
+            toml_table(&mut out, &mut Vec::new(), map, false)?;
+            if out.ends_with('\n') {
+                out.pop();
+            }
+            Ok(out)
+        }
+        v => toml_inline(v),
+    }
+}
+
+pub fn json_pretty(v: &Value, indent: usize) -> String {
+    let pad = " ".repeat(indent);
+    let mut out = Vec::new();
+    let mut ser =
+        Serializer::with_formatter(&mut out, PrettyFormatter::with_indent(pad.as_bytes()));
+    v.serialize(&mut ser)
+        .expect("serializing a JSON value can't fail");
+    String::from_utf8(out).expect("serde_json writes UTF-8")
+}
+
+fn json(h: &Helper) -> Result<Value, RenderError> {
+    Ok(param(h, 0)?.to_string().into())
+}
+
+fn json_pretty_helper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(json_pretty(param(h, 0)?, indent(h, 2)?).into())
+}
+
+fn yaml_helper(h: &Helper) -> Result<Value, RenderError> {
+    let indent = indent(h, 2)?;
+    if indent < 2 {
+        return Err(RenderError::new("yaml indent must be at least 2"));
+    }
+    Ok(yaml(param(h, 0)?, indent).into())
+}
+
+fn toml_helper(h: &Helper) -> Result<Value, RenderError> {
+    toml(param(h, 0)?)
+        .map(Value::from)
+        .map_err(|e| RenderError::new(format!("toml helper: {}", e)))
+}
+
+pub fn register(hb: &mut Handlebars) {
+    register_value(hb, "json", json);
+    register_value(hb, "json_pretty", json_pretty_helper);
+    register_value(hb, "yaml", yaml_helper);
+    register_value(hb, "toml", toml_helper);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dumps_json() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"v": {"b": [1, "x"], "a": null}, "s": "q\""});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{json v}} {{json s}}"),
+            r#"{"b":[1,"x"],"a":null} "q\"""#
+        );
+        assert_eq!(
+            render("{{json_pretty v}}"),
+            "{\n  \"b\": [\n    1,\n    \"x\"\n  ],\n  \"a\": null\n}"
+        );
+        assert_eq!(render("{{json_pretty v.b indent=0}}"), "[\n1,\n\"x\"\n]");
+        assert!(hb
+            .render_template("{{json_pretty v indent=-1}}", &data)
+            .is_err());
+    }
+
+    #[test]
+    fn dumps_yaml() {
+        let v = serde_json::json!({
+            "name": "web",
+            "replicas": 2,
+            "labels": {"app": "web", "tier": null},
+            "ports": [{"port": 80, "protocol": "TCP"}, {"port": 443}],
+            "args": ["--flag", "yes", "1.5", "a: b", "", [1, []]],
+            "script": "set -e\nmake\n",
+            "empty": {},
+        });
+        let expected = r#"name: web
+replicas: 2
+labels:
+  app: web
+  tier: null
+ports:
+  - port: 80
+    protocol: TCP
+  - port: 443
+args:
+  - "--flag"
+  - "yes"
+  - "1.5"
+  - "a: b"
+  - ""
+  - - 1
+    - []
+script: |
+  set -e
+  make
+empty: {}"#;
+        assert_eq!(yaml(&v, 2), expected);
+        assert_eq!(
+            yaml(&v["ports"], 4),
+            "-   port: 80\n    protocol: TCP\n-   port: 443"
+        );
+        assert_eq!(
+            yaml(&serde_json::json!({"a": "x\n\ny", "b": "x\ny\n\n"}), 2),
+            "a: |-\n  x\n\n  y\nb: |+\n  x\n  y\n"
+        );
+        assert_eq!(
+            yaml(&serde_json::json!("multi\nline"), 2),
+            "\"multi\\nline\""
+        );
+    }
+
+    #[test]
+    fn dumps_toml() {
+        let v = serde_json::json!({
+            "package": {"name": "ttgen", "version": "1.0", "authors": ["A \"B\""]},
+            "ratio": 1.0,
+            "optional": null,
+            "dependencies": {"serde": {"version": "1", "features": ["derive"]}},
+            "bin": [{"name": "a", "opts": {"x": 1}}, {"name": "b"}],
+            "Service": {"Exec Start": "/bin/x\targ", "empty": {}},
+            "points": [{"x": 1}, 2],
+        });
+        let expected = r#"ratio = 1.0
+points = [{ x = 1 }, 2]
+
+[package]
+name = "ttgen"
+version = "1.0"
+authors = ["A \"B\""]
+
+[dependencies.serde]
+version = "1"
+features = ["derive"]
+
+[[bin]]
+name = "a"
+
+[bin.opts]
+x = 1
+
+[[bin]]
+name = "b"
+
+[Service]
+"Exec Start" = "/bin/x\targ"
+
+[Service.empty]"#;
+        assert_eq!(toml(&v).unwrap(), expected);
+        assert_eq!(toml(&serde_json::json!([1, "a"])).unwrap(), r#"[1, "a"]"#);
+        assert!(toml(&serde_json::json!({"a": [null]})).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! The `table` helper, rendering rows of data as an aligned text table.
+//!
+//! `{{table rows}}` is an RST grid table, and `{{table rows style="pipe"}}`
+//! a Markdown pipe table.  Rows are objects, with a column for each key of
+//! the first row unless `columns=` lists the fields to show, or arrays whose
+//! first row is the header.  `headers=` replaces the header texts.  Columns
+//! holding only numbers are right-aligned.
+//!
+//! As a block, `{{#table rows}}{{this}} kB{{/table}}` renders each cell with
+//! the block, the cell's value as `this`, its field or header as `@column`
+//! and its row's number as `@index`, so cells can be formatted in the
+//! template and still line up.  Alignment follows the values, not the text.
+//!
+//! Grid tables keep line breaks within cells; pipe tables can't, so there
+//! they become spaces, and `|` in cells is escaped.
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, Output, RenderContext,
+    RenderError, Renderable, ScopedJson,
+};
+use serde_json::Value;
+use unicode_width::UnicodeWidthStr;
+
+use super::collections::{array, field};
+use crate::trace::WriteOutput;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Grid,
+    Pipe,
+}
+
+struct Column {
+    header: String,
+    width: usize,
+    numeric: bool,
+}
+
+/// How a cell's value becomes its text, given its column and row number.
+type CellFn<'a> = dyn FnMut(&Value, &str, usize) -> Result<String, RenderError> + 'a;
+
+fn plain_cell(v: &Value, _: &str, _: usize) -> Result<String, RenderError> {
+    Ok(match v {
+        Value::Null => String::new(),
+        v => v.render(),
+    })
+}
+
+fn cell_text(text: String, style: Style) -> String {
+    match style {
+        Style::Grid => text,
+        Style::Pipe => text.replace('\n', " ").replace('|', "\\|"),
+    }
+}
+
+fn strings(h: &Helper, name: &str) -> Result<Option<Vec<String>>, RenderError> {
+    let value = match h.hash_get(name) {
+        Some(v) => v.value(),
+        None => return Ok(None),
+    };
+    let items = value.as_array().map(|items| {
+        items
+            .iter()
+            .map(|v| v.as_str().map(String::from))
+            .collect::<Option<Vec<String>>>()
+    });
+    match items {
+        Some(Some(items)) => Ok(Some(items)),
+        _ => Err(RenderError::new(format!(
+            "table {} must be an array of strings",
+            name
+        ))),
+    }
+}
+
+/// Header texts and the cell values of each row.
+fn cells<'a>(
+    h: &'a Helper,
+    rows: &'a [Value],
+) -> Result<(Vec<String>, Vec<Vec<&'a Value>>), RenderError> {
+    match rows.first() {
+        Some(Value::Array(header)) => {
+            let body = rows[1..]
+                .iter()
+                .map(|row| match row {
+                    Value::Array(cells) => Ok(cells.iter().collect()),
+                    _ => Err(RenderError::new("table rows must all be arrays or objects")),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((header.iter().map(|v| v.render()).collect(), body))
+        }
+        Some(Value::Object(first)) => {
+            let columns = match strings(h, "columns")? {
+                Some(columns) => columns,
+                None => first.keys().cloned().collect(),
+            };
+            let body = rows
+                .iter()
+                .map(|row| match row {
+                    Value::Object(_) => Ok(columns.iter().map(|c| field(row, c)).collect()),
+                    _ => Err(RenderError::new("table rows must all be arrays or objects")),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((columns, body))
+        }
+        Some(_) => Err(RenderError::new("table rows must be arrays or objects")),
+        None => Ok((Vec::new(), Vec::new())),
+    }
+}
+
+fn pad(text: &str, width: usize, right: bool) -> String {
+    let fill = " ".repeat(width.saturating_sub(text.width()));
+    if right {
+        fill + text
+    } else {
+        format!("{}{}", text, fill)
+    }
+}
+
+fn grid(columns: &[Column], rows: &[Vec<String>]) -> String {
+    let rule = |c: char| {
+        let parts: Vec<String> = columns
+            .iter()
+            .map(|col| c.to_string().repeat(col.width + 2))
+            .collect();
+        format!("+{}+\n", parts.join("+"))
+    };
+    let line = |cells: &[&str], header: bool| {
+        let parts: Vec<String> = columns
+            .iter()
+            .zip(cells)
+            .map(|(col, text)| format!(" {} ", pad(text, col.width, col.numeric && !header)))
+            .collect();
+        format!("|{}|\n", parts.join("|"))
+    };
+    // Each cell's lines, padded with blank ones to the height of its row.
+    let row_lines = |row: &[&str], header: bool| {
+        let split: Vec<Vec<&str>> = row.iter().map(|t| t.lines().collect()).collect();
+        let height = split.iter().map(Vec::len).max().unwrap_or(0).max(1);
+        (0..height)
+            .map(|i| {
+                let cells: Vec<&str> = split
+                    .iter()
+                    .map(|l| l.get(i).copied().unwrap_or(""))
+                    .collect();
+                line(&cells, header)
+            })
+            .collect::<String>()
+    };
+
+    let header: Vec<&str> = columns.iter().map(|c| c.header.as_str()).collect();
+    let mut out = rule('-');
+    out.push_str(&row_lines(&header, true));
+    out.push_str(&rule('='));
+    for row in rows {
+        let row: Vec<&str> = row.iter().map(String::as_str).collect();
+        out.push_str(&row_lines(&row, false));
+        out.push_str(&rule('-'));
+    }
+    out
+}
+
+fn pipe(columns: &[Column], rows: &[Vec<String>]) -> String {
+    let line = |cells: Vec<String>| format!("| {} |\n", cells.join(" | "));
+    let mut out = line(
+        columns
+            .iter()
+            .map(|c| pad(&c.header, c.width, false))
+            .collect(),
+    );
+    out.push_str(&line(
+        columns
+            .iter()
+            .map(|c| {
+                let dashes = "-".repeat(c.width);
+                if c.numeric {
+                    format!("{}:", &dashes[1..])
+                } else {
+                    dashes
+                }
+            })
+            .collect(),
+    ));
+    for row in rows {
+        out.push_str(&line(
+            columns
+                .iter()
+                .zip(row)
+                .map(|(c, text)| pad(text, c.width, c.numeric))
+                .collect(),
+        ));
+    }
+    out
+}
+
+fn table(h: &Helper, cell: &mut CellFn) -> Result<String, RenderError> {
+    let style = match h.hash_get("style").map(|v| v.value()) {
+        None => Style::Grid,
+        Some(Value::String(s)) if s == "grid" => Style::Grid,
+        Some(Value::String(s)) if s == "pipe" => Style::Pipe,
+        Some(other) => {
+            let msg = format!("table style must be \"grid\" or \"pipe\", not {}", other);
+            return Err(RenderError::new(msg));
+        }
+    };
+    let (mut headers, values) = cells(h, array(h, 0)?)?;
+    let keys = headers.clone();
+    if let Some(replaced) = strings(h, "headers")? {
+        if replaced.len() != headers.len() {
+            return Err(RenderError::new(format!(
+                "table has {} columns but {} headers",
+                headers.len(),
+                replaced.len()
+            )));
+        }
+        headers = replaced;
+    }
+    if headers.is_empty() {
+        return Ok(String::new());
+    }
+
+    let min_width = if style == Style::Pipe { 3 } else { 1 };
+    let mut columns: Vec<Column> = headers
+        .into_iter()
+        .map(|header| {
+            let header = cell_text(header, style);
+            Column {
+                width: header.lines().map(str::width).max().unwrap_or(0),
+                header,
+                numeric: true,
+            }
+        })
+        .collect();
+    let mut rows = Vec::with_capacity(values.len());
+    for (index, row) in values.into_iter().enumerate() {
+        let mut texts = Vec::with_capacity(columns.len());
+        for (i, col) in columns.iter_mut().enumerate() {
+            let value = row.get(i).copied().unwrap_or(&Value::Null);
+            let text = cell_text(cell(value, &keys[i], index)?, style);
+            col.width = col
+                .width
+                .max(text.lines().map(str::width).max().unwrap_or(0));
+            col.numeric &= value.is_number() || value.is_null();
+            texts.push(text);
+        }
+        rows.push(texts);
+    }
+    for col in &mut columns {
+        col.width = col.width.max(min_width);
+        col.numeric &= !rows.is_empty();
+    }
+
+    Ok(match style {
+        Style::Grid => grid(&columns, &rows),
+        Style::Pipe => pipe(&columns, &rows),
+    })
+}
+
+struct TableHelper;
+
+impl HelperDef for TableHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        Ok(Some(ScopedJson::Derived(table(h, &mut plain_cell)?.into())))
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars,
+        _: &'rc Context,
+        rc: &mut RenderContext<'reg>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let t = match h.template() {
+            Some(t) => t,
+            None => return Ok(out.write(&table(h, &mut plain_cell)?)?),
+        };
+        let text = table(h, &mut |value, column, index| {
+            let mut cell_rc = rc.new_for_block();
+            cell_rc.set_local_var("@column".to_string(), Value::from(column));
+            cell_rc.set_local_var("@index".to_string(), Value::from(index));
+            let mut cell = WriteOutput(Vec::new());
+            t.render(r, &Context::wraps(value)?, &mut cell_rc, &mut cell)?;
+            String::from_utf8(cell.0).map_err(|_| RenderError::new("table cell is not UTF-8"))
+        })?;
+        Ok(out.write(&text)?)
+    }
+}
+
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper("table", Box::new(TableHelper));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aligns_tables() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "rows": [
+                {"name": "ttgen", "version": "1.0", "size": 120},
+                {"name": "a|b", "version": "2.0\nbeta", "size": 7},
+            ],
+            "grid": [["日本", "x"], ["a", null]],
+            "columns": ["size", "name"],
+        });
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(
+            render("{{table rows}}"),
+            "\
++-------+---------+------+
+| name  | version | size |
++=======+=========+======+
+| ttgen | 1.0     |  120 |
++-------+---------+------+
+| a|b   | 2.0     |    7 |
+|       | beta    |      |
++-------+---------+------+
+"
+        );
+        assert_eq!(
+            render("{{table rows style=\"pipe\" columns=columns}}"),
+            "\
+| size | name  |
+| ---: | ----- |
+|  120 | ttgen |
+|    7 | a\\|b  |
+"
+        );
+        assert_eq!(
+            render("{{table grid}}"),
+            "\
++------+---+
+| 日本 | x |
++======+===+
+| a    |   |
++------+---+
+"
+        );
+        assert!(hb
+            .render_template("{{table grid headers=columns.[0]}}", &data)
+            .is_err());
+        assert_eq!(
+            render("{{#table rows style=\"pipe\" columns=columns}}{{@column}}{{#if @index}}~{{/if}}={{this}}{{/table}}"),
+            "\
+| size     | name       |
+| -------: | ---------- |
+| size=120 | name=ttgen |
+|  size~=7 | name~=a\\|b |
+"
+        );
+    }
+}
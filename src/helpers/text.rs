@@ -0,0 +1,322 @@
+//! Text helpers: `slugify`, `split`, `join`, `truncate`, `indent`, `wrap` and
+//! `rst_heading`.
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, JsonRender, Output, RenderContext, RenderError,
+};
+use serde_json::Value;
+use textwrap::Wrapper;
+use unicode_width::UnicodeWidthStr;
+
+use super::{flag, param, register_value, str_param};
+
+/// ASCII spelling of a lowercase Latin letter with diacritics.
+fn transliterate(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĳ' => "ij",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    })
+}
+
+/// Lowercase `s`, spell accented Latin letters in ASCII, and join the runs
+/// of letters and digits with `sep`.  Letters from other scripts are kept.
+pub fn slugify(s: &str, sep: &str) -> String {
+    let mut slug = String::with_capacity(s.len());
+    let mut pending = false;
+    for c in s.chars().flat_map(char::to_lowercase) {
+        let ascii = transliterate(c);
+        if ascii.is_none() && !c.is_alphanumeric() {
+            pending = true;
+            continue;
+        }
+        if pending && !slug.is_empty() {
+            slug.push_str(sep);
+        }
+        pending = false;
+        match ascii {
+            Some(ascii) => slug.push_str(ascii),
+            None => slug.push(c),
+        }
+    }
+    slug
+}
+
+fn slugify_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let s = h
+        .param(0)
+        .and_then(|p| p.value().as_str())
+        .ok_or_else(|| RenderError::new("slugify helper expects a string"))?;
+    let sep = match h.hash_get("sep") {
+        Some(sep) => sep
+            .value()
+            .as_str()
+            .ok_or_else(|| RenderError::new("slugify sep is not a string"))?,
+        None => "-",
+    };
+    out.write(&slugify(s, sep))?;
+    Ok(())
+}
+
+/// `(split s ",")` is the array of pieces of `s` between separators;
+/// `trim=true` trims whitespace from each.
+fn split(h: &Helper) -> Result<Value, RenderError> {
+    let trim = flag(h, "trim");
+    let s = str_param(h, 0)?;
+    let sep = str_param(h, 1)?;
+    let pieces = s.split(sep).map(|p| if trim { p.trim() } else { p });
+    Ok(pieces.map(Value::from).collect())
+}
+
+/// `{{join items ", "}}` renders the items of an array with `sep` between.
+fn join(h: &Helper) -> Result<Value, RenderError> {
+    let items = param(h, 0)?
+        .as_array()
+        .ok_or_else(|| RenderError::new("join helper expects an array"))?;
+    let sep = str_param(h, 1)?;
+    let rendered: Vec<String> = items.iter().map(JsonRender::render).collect();
+    Ok(rendered.join(sep).into())
+}
+
+/// Shorten `s` to at most `max` characters, `suffix` included, or to `max`
+/// words followed by `suffix`.
+pub fn truncate(s: &str, max: usize, suffix: &str, words: bool) -> String {
+    if words {
+        let mut rest = s.split_whitespace();
+        let kept: Vec<&str> = rest.by_ref().take(max).collect();
+        if rest.next().is_none() {
+            return s.to_string();
+        }
+        return kept.join(" ") + suffix;
+    }
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let keep = max.saturating_sub(suffix.chars().count());
+    s.chars()
+        .take(keep)
+        .chain(suffix.chars())
+        .take(max)
+        .collect()
+}
+
+/// `{{truncate s 20}}` or `{{truncate s 5 words=true suffix="…"}}`; the
+/// suffix defaults to `...`.
+fn truncate_helper(h: &Helper) -> Result<Value, RenderError> {
+    let s = str_param(h, 0)?;
+    let max = param(h, 1)?
+        .as_u64()
+        .ok_or_else(|| RenderError::new("truncate length is not a non-negative integer"))?;
+    let suffix = match h.hash_get("suffix") {
+        Some(v) => v
+            .value()
+            .as_str()
+            .ok_or_else(|| RenderError::new("truncate suffix is not a string"))?,
+        None => "...",
+    };
+    Ok(truncate(s, max as usize, suffix, flag(h, "words")).into())
+}
+
+/// Indent every non-blank line of `s` by `width` spaces, except the first
+/// when `skip_first` is set.
+pub fn indent(s: &str, width: usize, skip_first: bool) -> String {
+    let pad = " ".repeat(width);
+    let mut indented = String::with_capacity(s.len());
+    for (i, line) in s.split_inclusive('\n').enumerate() {
+        let skip = (i == 0 && skip_first) || line.trim().is_empty();
+        if !skip {
+            indented.push_str(&pad);
+        }
+        indented.push_str(line);
+    }
+    indented
+}
+
+/// `{{indent s 4}}`; `skip_first=true` leaves the first line alone, for
+/// values that continue a line the template already started.
+fn indent_helper(h: &Helper) -> Result<Value, RenderError> {
+    let s = str_param(h, 0)?;
+    let width = param(h, 1)?
+        .as_u64()
+        .ok_or_else(|| RenderError::new("indent width is not a non-negative integer"))?;
+    Ok(indent(s, width as usize, flag(h, "skip_first")).into())
+}
+
+/// Wrap each line of `s` at `width` columns.  Words longer than a line are
+/// left whole, so URLs and paths survive.
+pub fn wrap(s: &str, width: usize) -> String {
+    let wrapper = Wrapper::new(width).break_words(false);
+    let lines: Vec<String> = s.split('\n').map(|line| wrapper.fill(line)).collect();
+    lines.join("\n")
+}
+
+/// `{{wrap s 72}}`.
+fn wrap_helper(h: &Helper) -> Result<Value, RenderError> {
+    let s = str_param(h, 0)?;
+    let width = param(h, 1)?
+        .as_u64()
+        .filter(|&w| w > 0)
+        .ok_or_else(|| RenderError::new("wrap width is not a positive integer"))?;
+    Ok(wrap(s, width as usize).into())
+}
+
+/// `title` underlined with `c`, and overlined too given `overline`.  The
+/// lines match the width docutils measures, where wide characters count as
+/// two columns.
+pub fn rst_heading(title: &str, c: char, overline: bool) -> String {
+    let line: String = std::iter::repeat_n(c, title.width()).collect();
+    if overline {
+        format!("{}\n{}\n{}", line, title, line)
+    } else {
+        format!("{}\n{}", title, line)
+    }
+}
+
+/// `{{rst_heading title "="}}`; `overline=true` adds a line above as well.
+fn rst_heading_helper(h: &Helper) -> Result<Value, RenderError> {
+    let title = str_param(h, 0)?.trim();
+    if title.is_empty() || title.contains('\n') {
+        return Err(RenderError::new(
+            "rst_heading title must be a single non-empty line",
+        ));
+    }
+    let mut chars = str_param(h, 1)?.chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_punctuation() => c,
+        _ => {
+            return Err(RenderError::new(
+                "rst_heading underline must be one punctuation character",
+            ))
+        }
+    };
+    Ok(rst_heading(title, c, flag(h, "overline")).into())
+}
+
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper("slugify", Box::new(slugify_helper));
+    register_value(hb, "split", split);
+    register_value(hb, "join", join);
+    register_value(hb, "truncate", truncate_helper);
+    register_value(hb, "indent", indent_helper);
+    register_value(hb, "wrap", wrap_helper);
+    register_value(hb, "rst_heading", rst_heading_helper);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slugifies() {
+        assert_eq!(
+            slugify("  Crème Brûlée: A How-To!  ", "-"),
+            "creme-brulee-a-how-to"
+        );
+        assert_eq!(slugify("Straße 5", "_"), "strasse_5");
+        assert_eq!(slugify("Привет, мир", "-"), "привет-мир");
+        assert_eq!(slugify("--", "-"), "");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"title": "Hello, World"});
+        let rendered = hb.render_template("{{slugify title}} {{slugify title sep=\"_\"}}", &data);
+        assert_eq!(rendered.unwrap(), "hello-world hello_world");
+    }
+
+    #[test]
+    fn truncates() {
+        assert_eq!(truncate("héllo wörld", 8, "...", false), "héllo...");
+        assert_eq!(truncate("héllo", 5, "...", false), "héllo");
+        assert_eq!(truncate("abc", 2, "...", false), "..");
+        assert_eq!(truncate("one  two three", 2, "…", true), "one two…");
+        assert_eq!(truncate("one two", 2, "…", true), "one two");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"s": "abcdefgh"});
+        let rendered = hb.render_template("{{truncate s 4}} {{truncate s 4 suffix=\"\"}}", &data);
+        assert_eq!(rendered.unwrap(), "a... abcd");
+    }
+
+    #[test]
+    fn indents() {
+        assert_eq!(indent("a\n\n  b\n", 2, false), "  a\n\n    b\n");
+        assert_eq!(indent("a\nb", 4, true), "a\n    b");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"body": "line 1\nline 2"});
+        let rendered = hb.render_template("key: |\n{{indent body 2}}", &data);
+        assert_eq!(rendered.unwrap(), "key: |\n  line 1\n  line 2");
+    }
+
+    #[test]
+    fn wraps_each_line() {
+        let s = "the quick brown fox\n\njumps over https://example.com/a/long/path";
+        assert_eq!(
+            wrap(s, 10),
+            "the quick\nbrown fox\n\njumps over\nhttps://example.com/a/long/path"
+        );
+    }
+
+    #[test]
+    fn underlines_rst_headings() {
+        assert_eq!(rst_heading("Crème", '-', false), "Crème\n-----");
+        assert_eq!(rst_heading("日本", '=', true), "====\n日本\n====");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"title": " Release 1.0 "});
+        let rendered = hb.render_template("{{rst_heading title \"~\"}}", &data);
+        assert_eq!(rendered.unwrap(), "Release 1.0\n~~~~~~~~~~~");
+        assert!(hb
+            .render_template("{{rst_heading title \"ab\"}}", &data)
+            .is_err());
+        assert!(hb
+            .render_template("{{rst_heading \"\" \"=\"}}", &data)
+            .is_err());
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({"csv": "a, b,c", "list": ["x", 1, true]});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(render("{{join list \"/\"}}"), "x/1/true");
+        assert_eq!(render("{{join (split csv \",\") \"|\"}}"), "a| b|c");
+        assert_eq!(
+            render("{{#each (split csv \",\" trim=true) as |s|}}[{{s}}]{{/each}}"),
+            "[a][b][c]"
+        );
+    }
+}
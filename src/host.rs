@@ -0,0 +1,62 @@
+//! Host metadata for the root context, enabled with `--host-info`.
+//!
+//! `host` is the machine's hostname, `user` the name of the user running
+//! ttgen and `cwd` the working directory.  `user` comes from `USER`,
+//! `LOGNAME` or `USERNAME` and is null if none is set.
+
+use std::env;
+
+use serde_json::{Map, Value};
+
+use crate::error::*;
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, and the name is cut at the
+    // first NUL, if any, below.
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+fn user() -> Option<String> {
+    ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+}
+
+/// The `host`, `user` and `cwd` values of the root context.
+pub fn metadata() -> Result<Map<String, Value>> {
+    let mut map = Map::new();
+    map.insert(
+        "host".to_string(),
+        hostname().map_or(Value::Null, Value::from),
+    );
+    map.insert("user".to_string(), user().map_or(Value::Null, Value::from));
+    let cwd = env::current_dir()?;
+    map.insert("cwd".to_string(), cwd.display().to_string().into());
+    Ok(map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_host_metadata() {
+        let host = metadata().unwrap_or_else(|e| panic!("{}", e));
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(host["cwd"], cwd.display().to_string());
+        assert!(host["host"].is_string() || host["host"].is_null());
+        assert_eq!(host.len(), 3);
+    }
+}
@@ -0,0 +1,233 @@
+//! Pluralization and translation: the `plural` and `t` helpers.
+//!
+//! `--locale de-DE` picks the language and `--translations DIR` the
+//! catalogs, JSON files named for a locale (`de-DE.json`) or a language
+//! (`de.json`).  Both files are read if they exist, the locale's entries
+//! winning.  A catalog maps each key to its message:
+//!
+//! ```json
+//! {"Hello, {name}!": "Hallo, {name}!",
+//!  "{count} files": {"one": "{count} Datei", "other": "{count} Dateien"}}
+//! ```
+//!
+//! `{{t "Hello, {name}!" name=user}}` looks the key up and fills `{name}`
+//! from the hash.  As with gettext, keys are the source text, so a key
+//! missing from the catalog stands for itself.  Messages with plural forms
+//! pick one by `count=`.
+//!
+//! `{{plural n "file" "files"}}` is the singular or the plural form by the
+//! rules of the locale, English by default.  Only the "one" and "other"
+//! categories are told apart.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, JsonRender, RenderContext, RenderError, ScopedJson,
+};
+use serde_json::{Map, Value};
+
+use crate::error::*;
+
+#[derive(Default)]
+pub struct Catalog {
+    locale: Option<String>,
+    messages: Map<String, Value>,
+}
+
+impl Catalog {
+    /// The catalog for `locale` from the files in `dir`, if any.
+    pub fn load(dir: Option<&Path>, locale: Option<&str>) -> Result<Self> {
+        let mut catalog = Catalog {
+            locale: locale.map(String::from),
+            messages: Map::new(),
+        };
+        let (dir, locale) = match (dir, locale) {
+            (Some(dir), Some(locale)) => (dir, locale),
+            _ => return Ok(catalog),
+        };
+
+        let language = locale.split(['-', '_']).next().unwrap_or(locale);
+        let mut found = false;
+        for name in &[language, locale] {
+            let path = dir.join(format!("{}.json", name));
+            if !path.is_file() {
+                continue;
+            }
+            let fail = |e: String| TranslationError::from(format!("{}: {}", path.display(), e));
+            let text = fs::read_to_string(&path).map_err(|e| fail(e.to_string()))?;
+            match serde_json::from_str(&text).map_err(|e| fail(e.to_string()))? {
+                Value::Object(messages) => catalog.messages.extend(messages),
+                _ => return Err(fail("a catalog must be a JSON object".to_string()).into()),
+            }
+            found = true;
+        }
+        if !found {
+            let msg = format!("no catalog for {} in {}", locale, dir.display());
+            return Err(TranslationError::from(msg).into());
+        }
+        Ok(catalog)
+    }
+
+    /// Whether `n` takes the singular form.
+    fn is_one(&self, n: f64) -> bool {
+        let locale = self.locale.as_deref().unwrap_or("en").to_ascii_lowercase();
+        let language = locale.split(['-', '_']).next().unwrap_or("");
+        match language {
+            "ja" | "ko" | "zh" | "th" | "vi" | "id" => false,
+            "pt" if locale != "pt-pt" && locale != "pt_pt" => (0.0..2.0).contains(&n.trunc()),
+            "fr" => (0.0..2.0).contains(&n.trunc()),
+            _ => n == 1.0,
+        }
+    }
+
+    fn category(&self, n: f64) -> &'static str {
+        if self.is_one(n) {
+            "one"
+        } else {
+            "other"
+        }
+    }
+}
+
+fn count(h: &Helper) -> Option<std::result::Result<f64, RenderError>> {
+    h.hash_get("count").map(|v| {
+        v.value()
+            .as_f64()
+            .ok_or_else(|| RenderError::new(format!("{} count is not a number", h.name())))
+    })
+}
+
+/// Replace each `{name}` in `message` with the hash argument `name`.
+fn fill(message: &str, h: &Helper) -> String {
+    let mut out = message.to_string();
+    for (name, value) in h.hash() {
+        out = out.replace(&format!("{{{}}}", name), &value.value().render());
+    }
+    out
+}
+
+struct TranslateHelper(Arc<Catalog>);
+
+impl TranslateHelper {
+    fn message(&self, h: &Helper, key: &str) -> std::result::Result<String, RenderError> {
+        match self.0.messages.get(key) {
+            None => Ok(key.to_string()),
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(Value::Object(forms)) => {
+                let n = count(h).ok_or_else(|| {
+                    RenderError::new(format!("t {:?} has plural forms and needs count=", key))
+                })??;
+                forms
+                    .get(self.0.category(n))
+                    .or_else(|| forms.get("other"))
+                    .and_then(Value::as_str)
+                    .map(String::from)
+                    .ok_or_else(|| RenderError::new(format!("t {:?} has no \"other\" form", key)))
+            }
+            Some(_) => Err(RenderError::new(format!(
+                "t {:?}: messages must be strings or objects of plural forms",
+                key
+            ))),
+        }
+    }
+}
+
+impl HelperDef for TranslateHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> std::result::Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let key = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("t helper expects a message key"))?;
+        let message = self.message(h, key)?;
+        Ok(Some(ScopedJson::Derived(Value::String(fill(&message, h)))))
+    }
+}
+
+struct PluralHelper(Arc<Catalog>);
+
+impl HelperDef for PluralHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> std::result::Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let n = h
+            .param(0)
+            .and_then(|p| p.value().as_f64())
+            .ok_or_else(|| RenderError::new("plural helper expects a number"))?;
+        let i = if self.0.is_one(n) { 1 } else { 2 };
+        let form = h
+            .param(i)
+            .ok_or_else(|| RenderError::new("plural helper expects two forms"))?;
+        Ok(Some(ScopedJson::Derived(form.value().clone())))
+    }
+}
+
+/// Register `t` and `plural` for `catalog`.
+pub fn register(hb: &mut Handlebars, catalog: Catalog) {
+    let catalog = Arc::new(catalog);
+    hb.register_helper("t", Box::new(TranslateHelper(catalog.clone())));
+    hb.register_helper("plural", Box::new(PluralHelper(catalog)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn catalog(locale: &str, messages: Value) -> Catalog {
+        Catalog {
+            locale: Some(locale.to_string()),
+            messages: messages.as_object().unwrap().clone(),
+        }
+    }
+
+    #[test]
+    fn pluralizes_by_locale() {
+        let mut hb = Handlebars::new();
+        let template = "{{#each counts as |n|}}{{n}} {{plural n \"file\" \"files\"}}, {{/each}}";
+        let data = json!({"counts": [0, 1, 1.5, 2]});
+
+        register(&mut hb, Catalog::default());
+        let rendered = hb.render_template(template, &data);
+        assert_eq!(rendered.unwrap(), "0 files, 1 file, 1.5 files, 2 files, ");
+        register(&mut hb, catalog("fr-FR", json!({})));
+        let rendered = hb.render_template(template, &data);
+        assert_eq!(rendered.unwrap(), "0 file, 1 file, 1.5 file, 2 files, ");
+    }
+
+    #[test]
+    fn translates_from_catalogs() {
+        let mut hb = Handlebars::new();
+        let messages = json!({
+            "Hello, {name}!": "Hallo, {name}!",
+            "{count} files": {"one": "{count} Datei", "other": "{count} Dateien"},
+        });
+        register(&mut hb, catalog("de", messages));
+        let data = json!({"user": "Ana"});
+        let render = |t: &str| hb.render_template(t, &data).unwrap();
+
+        assert_eq!(render("{{t \"Hello, {name}!\" name=user}}"), "Hallo, Ana!");
+        assert_eq!(
+            render("{{t \"{count} files\" count=1}}; {{t \"{count} files\" count=3}}"),
+            "1 Datei; 3 Dateien"
+        );
+        assert_eq!(
+            render("{{t \"Untranslated {name}\" name=user}}"),
+            "Untranslated Ana"
+        );
+        assert!(hb
+            .render_template("{{t \"{count} files\"}}", &data)
+            .is_err());
+    }
+}
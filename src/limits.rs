@@ -0,0 +1,491 @@
+//! Limits on partial nesting, output size and render time, so a runaway
+//! template fails with an error instead of overflowing the stack, filling the
+//! disk or stalling a whole multigen.
+//!
+//! Partials are wrapped in a block helper (see `instrument`) that counts how
+//! deeply they are nested on the current thread.  Renders can't be
+//! interrupted from outside, so the deadline set by `with_timeout` is
+//! checked whenever output is written, a partial is entered, a helper is
+//! called or a block renders its contents, and `wait` kills the commands
+//! still running at the deadline.
+//!
+//! `--max-io` bounds file operations separately from the render jobs of
+//! `--max-jobs`: each read of an input and each write of a buffered chunk
+//! of output waits for one of its slots, so the jobs keep rendering while
+//! only a few of them touch the disk at once.
+//!
+//! `in_order` hands out work one item at a time, in order, to every thread
+//! of the pool.  Unlike a parallel iterator, which splits its items into
+//! ranges, this starts the first items first, so multigen can start its
+//! largest renders before the small ones instead of leaving one for last.
+
+use std::cell::Cell;
+use std::io::{Error as IOError, ErrorKind, Read, Result as IOResult, Write};
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use handlebars::template::{HelperTemplate, Parameter, Template, TemplateElement, TemplateMapping};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    Renderable,
+};
+use serde_json::Value;
+
+use crate::trace;
+
+use TemplateElement::*;
+
+pub const HELPER_NAME: &str = "__ttgen_depth";
+pub const DEADLINE_HELPER_NAME: &str = "__ttgen_deadline";
+pub const DEFAULT_MAX_DEPTH: &str = "64";
+pub const DEFAULT_MAX_OUTPUT: &str = "1G";
+
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(64);
+static MAX_OUTPUT: AtomicU64 = AtomicU64::new(1 << 30);
+static MAX_IO: IoLimit = IoLimit::new();
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static DEADLINE: Cell<Option<(Instant, Duration)>> = const { Cell::new(None) };
+}
+
+pub fn set_max_depth(depth: usize) {
+    MAX_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+pub fn set_max_output(bytes: u64) {
+    MAX_OUTPUT.store(bytes, Ordering::Relaxed);
+}
+
+/// Allow at most `n` file operations at a time, or any number for 0.
+pub fn set_max_io(n: usize) {
+    MAX_IO.max.store(n, Ordering::Relaxed);
+}
+
+/// At most `max` operations at a time, or any number for 0.
+struct IoLimit {
+    max: AtomicUsize,
+    busy: Mutex<usize>,
+    free: Condvar,
+}
+
+impl IoLimit {
+    const fn new() -> Self {
+        IoLimit {
+            max: AtomicUsize::new(0),
+            busy: Mutex::new(0),
+            free: Condvar::new(),
+        }
+    }
+
+    fn run<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let _slot = IoSlot::take(self);
+        f()
+    }
+}
+
+/// A slot taken from an `IoLimit`, given back when dropped.
+struct IoSlot<'a>(&'a IoLimit);
+
+impl<'a> IoSlot<'a> {
+    fn take(limit: &'a IoLimit) -> Option<Self> {
+        let max = limit.max.load(Ordering::Relaxed);
+        if max == 0 {
+            return None;
+        }
+        let mut busy = limit.busy.lock().unwrap();
+        while *busy >= max {
+            busy = limit.free.wait(busy).unwrap();
+        }
+        *busy += 1;
+        Some(IoSlot(limit))
+    }
+}
+
+impl Drop for IoSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() -= 1;
+        self.0.free.notify_one();
+    }
+}
+
+/// Run the file operation `f` within the `--max-io` limit.  `f` must not
+/// start another, which could wait forever for a slot its caller holds.
+pub fn io<T, F: FnOnce() -> T>(f: F) -> T {
+    MAX_IO.run(f)
+}
+
+/// A writer whose every write and flush, or a reader whose every read, is a
+/// file operation for `io`.
+pub struct IoLimited<W>(pub W);
+
+impl<R: Read> Read for IoLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        io(|| self.0.read(buf))
+    }
+}
+
+impl<W: Write> Write for IoLimited<W> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        io(|| self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        io(|| self.0.flush())
+    }
+}
+
+/// Run `f` on each of `items` on the threads of the pool, starting them in
+/// order.
+pub fn in_order<T: Sync, F: Fn(&T) + Sync>(items: &[T], f: F) {
+    let next = AtomicUsize::new(0);
+    let work = || {
+        while let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) {
+            f(item);
+        }
+    };
+    let threads = rayon::current_num_threads().min(items.len());
+    rayon::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|_| work());
+        }
+    });
+}
+
+/// Run `f` with renders on this thread failing once `timeout` has passed.
+pub fn with_timeout<T, F: FnOnce() -> T>(timeout: Option<Duration>, f: F) -> T {
+    let deadline = timeout.map(|t| (Instant::now() + t, t));
+    let previous = DEADLINE.with(|d| d.replace(deadline));
+    let result = f();
+    DEADLINE.with(|d| d.set(previous));
+    result
+}
+
+fn check_deadline() -> IOResult<()> {
+    match DEADLINE.with(Cell::get) {
+        Some((deadline, timeout)) if Instant::now() > deadline => Err(IOError::new(
+            ErrorKind::TimedOut,
+            format!("render timed out after {}s", timeout.as_secs_f64()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn render_deadline() -> HelperResult {
+    check_deadline().map_err(|e| RenderError::new(e.to_string()))
+}
+
+/// Wait for `child` to exit, killing it once the deadline set by
+/// `with_timeout` has passed.
+pub fn wait(child: &mut Child) -> IOResult<ExitStatus> {
+    if DEADLINE.with(Cell::get).is_none() {
+        return child.wait();
+    }
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if let Err(e) = check_deadline() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Parse a byte count, with an optional `K`, `M` or `G` suffix.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let text = s.trim();
+    let (digits, scale) = match text.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&text[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&text[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("invalid size: {}", s))
+}
+
+struct DepthHelper;
+
+impl HelperDef for DepthHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        render_deadline()?;
+        let depth = DEPTH.with(Cell::get) + 1;
+        let max = MAX_DEPTH.load(Ordering::Relaxed);
+        if depth > max {
+            let name = h.param(0).and_then(|p| p.value().as_str()).unwrap_or("?");
+            let msg = format!("partial {:?} nested more than {} deep", name, max);
+            return Err(RenderError::new(msg));
+        }
+
+        DEPTH.with(|d| d.set(depth));
+        let result = match h.template() {
+            Some(t) => t.render(r, ctx, rc, out),
+            None => Ok(()),
+        };
+        DEPTH.with(|d| d.set(depth - 1));
+        result
+    }
+}
+
+/// Fails once the render deadline has passed, and writes nothing.
+struct DeadlineHelper;
+
+impl HelperDef for DeadlineHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        render_deadline()
+    }
+}
+
+/// Register the helpers that instrumented templates call into.
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper(HELPER_NAME, Box::new(DepthHelper));
+    hb.register_helper(DEADLINE_HELPER_NAME, Box::new(DeadlineHelper));
+}
+
+fn deadline_call() -> TemplateElement {
+    HelperExpression(Box::new(HelperTemplate {
+        name: DEADLINE_HELPER_NAME.to_string(),
+        params: Vec::new(),
+        hash: Default::default(),
+        block_param: None,
+        template: None,
+        inverse: None,
+        block: false,
+    }))
+}
+
+/// Start `t` with a deadline check, so a block checks it each time it
+/// renders its contents, such as on every iteration of `each`.
+fn check_first(t: &mut Template, pos: Option<TemplateMapping>) {
+    t.elements.insert(0, deadline_call());
+    if let (Some(m), Some(p)) = (t.mapping.as_mut(), pos) {
+        m.insert(0, p);
+    }
+}
+
+/// Wrap every partial in `t`, including those in nested blocks and inline
+/// partial definitions, in the depth counting helper, and check the
+/// deadline before every helper call and at the start of every block.
+pub fn instrument(t: &mut Template) {
+    let elements = std::mem::take(&mut t.elements);
+    let mapping = t.mapping.take();
+    let mut new_mapping = mapping.as_ref().map(|_| Vec::new());
+
+    for (idx, mut el) in elements.into_iter().enumerate() {
+        let pos = mapping.as_ref().and_then(|m| m.get(idx)).cloned();
+        let check = match el {
+            // Calls inserted by other instrumentation.
+            HelperExpression(ref ht) if ht.name.starts_with(trace::INSERTED_PREFIX) => false,
+            HelperExpression(_) => true,
+            HelperBlock(ref mut ht) => {
+                if let Some(t) = ht.template.as_mut() {
+                    instrument(t);
+                    check_first(t, pos.clone());
+                }
+                if let Some(t) = ht.inverse.as_mut() {
+                    instrument(t);
+                    check_first(t, pos.clone());
+                }
+                true
+            }
+            DirectiveBlock(ref mut dt) => {
+                if let Some(t) = dt.template.as_mut() {
+                    instrument(t);
+                }
+                false
+            }
+            PartialExpression(ref mut dt) | PartialBlock(ref mut dt) => {
+                if let Some(t) = dt.template.as_mut() {
+                    instrument(t);
+                }
+                let name = match dt.name {
+                    Parameter::Name(ref n) => n.clone(),
+                    _ => "<dynamic>".to_string(),
+                };
+
+                let partial = std::mem::replace(&mut el, RawString(String::new()));
+                el = HelperBlock(Box::new(HelperTemplate {
+                    name: HELPER_NAME.to_string(),
+                    params: vec![Parameter::Literal(Value::from(name))],
+                    hash: Default::default(),
+                    block_param: None,
+                    template: Some(Template {
+                        name: None,
+                        elements: vec![partial],
+                        mapping: pos.clone().map(|p| vec![p]),
+                    }),
+                    inverse: None,
+                    block: true,
+                }));
+                // The depth helper checks the deadline itself.
+                false
+            }
+            _ => false,
+        };
+
+        if check {
+            t.elements.push(deadline_call());
+            if let (Some(m), Some(p)) = (new_mapping.as_mut(), pos.clone()) {
+                m.push(p);
+            }
+        }
+        t.elements.push(el);
+        if let (Some(m), Some(p)) = (new_mapping.as_mut(), pos) {
+            m.push(p);
+        }
+    }
+
+    t.mapping = new_mapping;
+}
+
+/// Fails writes once more than the configured maximum output size has been
+/// written through it, or the render deadline has passed.
+pub struct LimitWriter<W: Write> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> LimitWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            remaining: MAX_OUTPUT.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<W: Write> Write for LimitWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        check_deadline()?;
+        if buf.len() as u64 > self.remaining {
+            let max = MAX_OUTPUT.load(Ordering::Relaxed);
+            let msg = format!("output exceeds the maximum size of {} bytes", max);
+            return Err(IOError::other(msg));
+        }
+        let n = self.inner.write(buf)?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::WriteOutput;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size(" 4K "), Ok(4096));
+        assert_eq!(parse_size("2m"), Ok(2 << 20));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1T").is_err());
+    }
+
+    #[test]
+    fn self_including_partial_fails() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+
+        let source = "{{#*inline \"loop\"}}x{{> loop}}{{/inline}}{{> loop}}";
+        let mut t = Template::compile2(source, true).unwrap();
+        instrument(&mut t);
+
+        let ctx = Context::wraps(Value::Null).unwrap();
+        let mut out = WriteOutput(Vec::new());
+        let err = t
+            .render(&hb, &ctx, &mut RenderContext::new(None), &mut out)
+            .unwrap_err();
+        assert_eq!(err.desc, "partial \"loop\" nested more than 64 deep");
+        assert_eq!(DEPTH.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn writes_fail_past_the_deadline() {
+        let mut out = Vec::new();
+        let result = with_timeout(Some(Duration::from_secs(0)), || {
+            std::thread::sleep(Duration::from_millis(5));
+            LimitWriter::new(&mut out).write_all(b"late")
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(LimitWriter::new(&mut out).write_all(b"fine").is_ok());
+    }
+
+    #[test]
+    fn long_loops_fail_past_the_deadline() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let source = "{{#each items}}{{/each}}";
+        let mut t = Template::compile2(source, true).unwrap();
+        instrument(&mut t);
+
+        let items = vec![Value::Null; 1_000_000];
+        let ctx = Context::wraps(serde_json::json!({ "items": items })).unwrap();
+        let mut out = WriteOutput(Vec::new());
+        let err = with_timeout(Some(Duration::from_millis(1)), || {
+            t.render(&hb, &ctx, &mut RenderContext::new(None), &mut out)
+        })
+        .unwrap_err();
+        assert!(err.desc.contains("timed out"), "{}", err.desc);
+    }
+
+    #[test]
+    fn runs_each_item_once() {
+        let items: Vec<usize> = (0..100).collect();
+        let ran = Mutex::new(Vec::new());
+        in_order(&items, |&i| ran.lock().unwrap().push(i));
+        let mut ran = ran.into_inner().unwrap();
+        ran.sort();
+        assert_eq!(ran, items);
+    }
+
+    #[test]
+    fn io_is_limited_across_threads() {
+        static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+        static PEAK: AtomicUsize = AtomicUsize::new(0);
+        static LIMIT: IoLimit = IoLimit::new();
+        LIMIT.max.store(2, Ordering::Relaxed);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    LIMIT.run(|| {
+                        let active = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+                        PEAK.fetch_max(active, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(5));
+                        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert!(PEAK.load(Ordering::SeqCst) <= 2);
+    }
+}
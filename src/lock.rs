@@ -0,0 +1,92 @@
+//! The lock that keeps two runs of one spec from writing its outputs at once.
+//!
+//! `multigen` holds an advisory lock on `SPEC.lock` while it runs, and waits
+//! for it if another run holds it.  The lock goes with the process, so one
+//! that crashed never leaves it held, and the file itself is left in place.
+//! `--no-lock` skips it.  Locking is only supported on unix; elsewhere runs
+//! aren't locked.
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+
+/// A held lock, released when dropped.
+pub struct SpecLock {
+    _file: File,
+}
+
+/// The lock file of the spec at `spec`.
+fn lock_path(spec: &Path) -> PathBuf {
+    let mut path = spec.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+#[cfg(unix)]
+fn flock(file: &File, wait: bool) -> std::io::Result<bool> {
+    use std::io::{Error as IOError, ErrorKind};
+    use std::os::unix::io::AsRawFd;
+
+    let op = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    // SAFETY: the descriptor is open for as long as `file` is borrowed.
+    if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+        return Ok(true);
+    }
+    match IOError::last_os_error() {
+        e if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn flock(_: &File, _: bool) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+fn open(spec: &Path) -> Result<File> {
+    let path = lock_path(spec);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .within(path.display())?;
+    Ok(file)
+}
+
+/// Take the lock of `spec`, waiting for the run that holds it, if any.
+pub fn lock(spec: &Path) -> Result<SpecLock> {
+    let file = open(spec)?;
+    if !flock(&file, false)? {
+        eprintln!("waiting for another run of {} to finish", spec.display());
+        flock(&file, true)?;
+    }
+    Ok(SpecLock { _file: file })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn excludes_other_holders() {
+        let dir = std::env::temp_dir().join(format!("ttgen-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let spec = dir.join("spec.json");
+        let try_lock = || flock(&open(&spec).unwrap_or_else(|e| panic!("{}", e)), false);
+
+        let held = lock(&spec).unwrap_or_else(|e| panic!("{}", e));
+        assert!(dir.join("spec.json.lock").exists());
+        assert!(!try_lock().unwrap());
+        drop(held);
+        assert!(try_lock().unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
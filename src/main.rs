@@ -9,9 +9,30 @@ use std::env::args_os;
 use std::fmt::Display;
 
 mod cli;
+mod console;
+mod env;
 mod error;
+mod exec;
+mod files;
+mod frontmatter;
+mod git;
+mod helpers;
+mod host;
+mod i18n;
+mod limits;
+mod lock;
+mod missing;
+mod os_path;
+mod plugin;
+mod profile;
 mod render;
+mod select;
 mod spec;
+mod state;
+mod subst;
+mod trace;
+mod usage;
+mod whitespace;
 
 fn exit<D: Display>(msg: D, exitcode: i32) -> ! {
     if exitcode == 0 {
@@ -28,6 +49,6 @@ fn main() {
 
     let mut app = cli::get_parser();
     if let Err(e) = cli::parse_args(&mut app, args_os()) {
-        exit(e, 1);
+        exit(e.report(e.to_string()), 1);
     };
 }
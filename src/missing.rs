@@ -0,0 +1,120 @@
+//! Missing variable collection for `--collect-missing`.
+//!
+//! Strict mode stops at the first missing variable.  Instead, the registry
+//! renders leniently and templates are instrumented (see `trace::instrument`)
+//! with a helper that records every lookup that resolves to nothing, and
+//! writes a placeholder where its value would have gone.
+
+use std::cell::RefCell;
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext};
+
+pub const HELPER_NAME: &str = "__ttgen_missing";
+
+thread_local! {
+    static MISSING: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+struct MissingHelper;
+
+impl HelperDef for MissingHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        rc: &mut RenderContext<'reg>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        // Strict mode only applies to expressions, so helper parameters
+        // are left alone here too.
+        let label = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+        let at = match label.strip_suffix(" lookup") {
+            Some(at) => at,
+            None => return Ok(()),
+        };
+
+        match h.param(1) {
+            Some(p) if p.is_value_missing() => {
+                let path = p.path().map(String::as_str).unwrap_or("?");
+                // Block templates are unnamed; the enclosing file is the root.
+                let template = rc
+                    .get_current_template_name()
+                    .or_else(|| rc.get_root_template_name())
+                    .map_or("?", String::as_str);
+                MISSING.with(|m| {
+                    if let Some(missing) = m.borrow_mut().as_mut() {
+                        missing.push(format!("{}:{}: {}", template, at, path));
+                    }
+                });
+                out.write(&format!("<missing {}>", path))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Register the helper that instrumented templates call into.
+///
+/// Strict mode is turned off, so rendering continues past missing values.
+pub fn register(hb: &mut Handlebars) {
+    hb.set_strict_mode(false);
+    hb.register_helper(HELPER_NAME, Box::new(MissingHelper));
+}
+
+/// Whether `hb` has missing variable collection registered.
+pub fn enabled(hb: &Handlebars) -> bool {
+    hb.get_helper(HELPER_NAME).is_some()
+}
+
+/// Run `f`, and return every missing variable that renders within it looked
+/// up, as `template:line:col: path`.
+///
+/// Nested calls record into the outermost one and report nothing themselves.
+pub fn collect<T, F: FnOnce() -> T>(f: F) -> (T, Vec<String>) {
+    let outermost = MISSING.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.is_some() {
+            return false;
+        }
+        *m = Some(Vec::new());
+        true
+    });
+    let result = f();
+    if !outermost {
+        return (result, Vec::new());
+    }
+    let missing = MISSING.with(|m| m.borrow_mut().take()).unwrap_or_default();
+    (result, missing)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::{instrument, WriteOutput};
+    use handlebars::{Renderable, Template};
+
+    #[test]
+    fn collects_every_missing_lookup() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+
+        let source = "{{a}} {{b}}\n{{#each list}}{{c}}{{/each}}";
+        let mut t = Template::compile_with_name(source, "t.hbs".to_string(), true).unwrap();
+        instrument(&mut t, HELPER_NAME);
+
+        let ctx = Context::wraps(serde_json::json!({"b": 1, "list": [{}, {"c": 2}]})).unwrap();
+        let mut out = WriteOutput(Vec::new());
+        let ((), missing) = collect(|| {
+            let mut rc = RenderContext::new(t.name.as_ref());
+            t.render(&hb, &ctx, &mut rc, &mut out).unwrap();
+        });
+
+        assert_eq!(
+            String::from_utf8(out.0).unwrap(),
+            "<missing a> 1\n<missing c>2"
+        );
+        assert_eq!(missing, vec!["t.hbs:1:1: a", "t.hbs:2:15: c"]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Spec paths that aren't UTF-8.
+//!
+//! An entry's `data`, `template` and `output`, and the paths of a spec's
+//! `helpers`, are JSON strings, or, for names that aren't UTF-8, objects
+//! holding the raw bytes in base64: `{"base64": "b3V0/y50eHQ="}`.  They're
+//! written back the same way, so such entries round-trip through the build
+//! state.  Raw bytes only name files on unix.
+
+// serde_derive 1.0.92 puts the impls it derives in named consts.
+#![allow(non_local_definitions)]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::de::{Deserializer, Error as DeError, MapAccess, Visitor};
+use serde::ser::{Error as SerError, SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::helpers::{base64_decode, base64_encode};
+
+#[cfg(unix)]
+fn to_bytes(path: &Path) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn to_bytes(_: &Path) -> Option<&[u8]> {
+    None
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+pub fn serialize<S: Serializer>(path: &Path, s: S) -> Result<S::Ok, S::Error> {
+    if let Some(text) = path.to_str() {
+        return s.serialize_str(text);
+    }
+    let bytes = to_bytes(path)
+        .ok_or_else(|| S::Error::custom(format!("{}: not valid unicode", path.display())))?;
+    let mut map = s.serialize_map(Some(1))?;
+    map.serialize_entry("base64", &base64_encode(bytes, false))?;
+    map.end()
+}
+
+struct PathVisitor;
+
+impl<'de> Visitor<'de> for PathVisitor {
+    type Value = PathBuf;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a path, or an object with the path's bytes as \"base64\"")
+    }
+
+    fn visit_str<E: DeError>(self, s: &str) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(s))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PathBuf, A::Error> {
+        let mut path = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key != "base64" {
+                return Err(A::Error::unknown_field(&key, &["base64"]));
+            }
+            let encoded: String = map.next_value()?;
+            let bytes = base64_decode(&encoded)
+                .ok_or_else(|| A::Error::custom(format!("invalid base64 path {:?}", encoded)))?;
+            let decoded = from_bytes(bytes)
+                .ok_or_else(|| A::Error::custom("paths that aren't UTF-8 need unix"))?;
+            path = Some(decoded);
+        }
+        path.ok_or_else(|| A::Error::missing_field("base64"))
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PathBuf, D::Error> {
+    d.deserialize_any(PathVisitor)
+}
+
+#[derive(Serialize, Deserialize)]
+struct OsPath(#[serde(with = "self")] PathBuf);
+
+/// `path` as a JSON value, written like a spec path.
+pub fn to_value(path: &Path) -> Value {
+    serde_json::to_value(OsPath(path.to_path_buf()))
+        .unwrap_or_else(|_| Value::from(path.display().to_string()))
+}
+
+/// The same, for the paths of maps such as a spec's `helpers`.
+pub mod map {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        paths: &BTreeMap<String, PathBuf>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = s.serialize_map(Some(paths.len()))?;
+        for (key, path) in paths {
+            map.serialize_entry(key, &OsPath(path.clone()))?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<BTreeMap<String, PathBuf>, D::Error> {
+        let paths = BTreeMap::<String, OsPath>::deserialize(d)?;
+        Ok(paths.into_iter().map(|(key, path)| (key, path.0)).collect())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Entry {
+        #[serde(with = "super")]
+        path: PathBuf,
+    }
+
+    #[test]
+    fn round_trips_paths_that_are_not_utf8() {
+        let raw = from_bytes(b"out\xff.txt".to_vec()).unwrap();
+        let value = serde_json::to_value(Entry { path: raw.clone() }).unwrap();
+        assert_eq!(value, json!({"path": {"base64": "b3V0/y50eHQ="}}));
+        let entry: Entry = serde_json::from_value(value).unwrap();
+        assert_eq!(entry.path, raw);
+
+        let plain = json!({"path": "out.txt"});
+        assert_eq!(
+            serde_json::to_value(serde_json::from_value::<Entry>(plain.clone()).unwrap()).unwrap(),
+            plain
+        );
+        assert!(serde_json::from_value::<Entry>(json!({"path": 1})).is_err());
+        assert!(serde_json::from_value::<Entry>(json!({"path": {"base64": "!"}})).is_err());
+    }
+
+    #[test]
+    fn round_trips_maps_of_paths() {
+        #[derive(Serialize, Deserialize)]
+        struct Helpers {
+            #[serde(with = "super::map")]
+            helpers: BTreeMap<String, PathBuf>,
+        }
+
+        let value = json!({"helpers": {"a": "a.sh", "b": {"base64": "Yv8uc2g="}}});
+        let parsed: Helpers = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(parsed.helpers["b"], from_bytes(b"b\xff.sh".to_vec()).unwrap());
+        assert_eq!(to_value(&parsed.helpers["b"]), value["helpers"]["b"]);
+        assert_eq!(serde_json::to_value(parsed).unwrap(), value);
+    }
+}
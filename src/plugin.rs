@@ -0,0 +1,266 @@
+//! Native helper plugins, loaded with `--plugin PATH`.
+//!
+//! A plugin is a shared library exporting this C interface:
+//!
+//! ```c
+//! /* Arguments arrive as UTF-8 JSON: {"params": [...], "hash": {...}}.
+//!  * On success return 0 and point *out at the text to insert; otherwise
+//!  * return non-zero and point *out at an error message, or leave it NULL. */
+//! typedef int (*ttgen_helper_fn)(const char *args, char **out);
+//! typedef void (*ttgen_add_fn)(void *ctx, const char *name, ttgen_helper_fn helper);
+//!
+//! /* Must return 1, the version of this interface. */
+//! uint32_t ttgen_plugin_abi(void);
+//! /* Calls add(ctx, ...) once for each helper the plugin provides. */
+//! void ttgen_plugin_register(void *ctx, ttgen_add_fn add);
+//! /* Frees a string a helper returned through *out. */
+//! void ttgen_plugin_free(char *s);
+//! ```
+//!
+//! Libraries stay loaded for the rest of the process.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::ptr;
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+};
+use serde_json::{json, Map, Value};
+
+use crate::error::*;
+
+pub const ABI_VERSION: u32 = 1;
+
+type HelperFn = unsafe extern "C" fn(*const c_char, *mut *mut c_char) -> c_int;
+type AddFn = unsafe extern "C" fn(*mut c_void, *const c_char, HelperFn);
+type RegisterFn = unsafe extern "C" fn(*mut c_void, AddFn);
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+type AbiFn = unsafe extern "C" fn() -> u32;
+
+struct PluginHelper {
+    call: HelperFn,
+    free: FreeFn,
+}
+
+impl HelperDef for PluginHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let params: Vec<&Value> = h.params().iter().map(|p| p.value()).collect();
+        let hash: Map<String, Value> = h
+            .hash()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.value().clone()))
+            .collect();
+        // Serialized JSON escapes control characters, so it can't contain NUL.
+        let args = CString::new(json!({"params": params, "hash": hash}).to_string())
+            .map_err(|e| RenderError::new(e.to_string()))?;
+
+        let mut text: *mut c_char = ptr::null_mut();
+        // SAFETY: `args` is a valid C string for the duration of the call, and
+        // `text` is only read back if the plugin set it.
+        let status = unsafe { (self.call)(args.as_ptr(), &mut text) };
+        let text = if text.is_null() {
+            String::new()
+        } else {
+            // SAFETY: the plugin returned a NUL-terminated string, which goes
+            // back to its own allocator once copied.
+            unsafe {
+                let copy = CStr::from_ptr(text).to_string_lossy().into_owned();
+                (self.free)(text);
+                copy
+            }
+        };
+
+        if status != 0 {
+            let reason = if text.is_empty() { "failed" } else { &text };
+            return Err(RenderError::new(format!("helper {}: {}", h.name(), reason)));
+        }
+        out.write(&text)?;
+        Ok(())
+    }
+}
+
+unsafe extern "C" fn add(ctx: *mut c_void, name: *const c_char, call: HelperFn) {
+    // SAFETY: `ctx` is the list passed to `ttgen_plugin_register` by
+    // `register_all`, which outlives the call.
+    let helpers = &mut *(ctx as *mut Vec<(String, HelperFn)>);
+    if !name.is_null() {
+        helpers.push((CStr::from_ptr(name).to_string_lossy().into_owned(), call));
+    }
+}
+
+/// Ask a plugin for its helpers and register them with `hb`, or only the one
+/// called `only`.
+fn register_all(
+    hb: &mut Handlebars,
+    register: RegisterFn,
+    free: FreeFn,
+    only: Option<&str>,
+) -> Vec<String> {
+    let mut helpers: Vec<(String, HelperFn)> = Vec::new();
+    // SAFETY: `add` only runs during this call and casts `ctx` back to `helpers`.
+    unsafe { register(&mut helpers as *mut _ as *mut c_void, add) };
+
+    let mut names = Vec::new();
+    for (name, call) in helpers {
+        if only.is_some_and(|only| only != name) {
+            continue;
+        }
+        hb.register_helper(&name, Box::new(PluginHelper { call, free }));
+        names.push(name);
+    }
+    names
+}
+
+#[cfg(unix)]
+mod dl {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn last_error() -> String {
+        // SAFETY: dlerror returns NULL or a NUL-terminated message.
+        unsafe {
+            let msg = libc::dlerror();
+            if msg.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(msg).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    pub struct Library(*mut c_void);
+
+    impl Library {
+        pub fn open(path: &Path) -> std::result::Result<Self, String> {
+            let cpath = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            // SAFETY: `cpath` is a valid C string; the handle is never closed.
+            let handle = unsafe { libc::dlopen(cpath.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+            if handle.is_null() {
+                Err(last_error())
+            } else {
+                Ok(Library(handle))
+            }
+        }
+
+        pub fn symbol(&self, name: &str) -> std::result::Result<*mut c_void, String> {
+            let cname = CString::new(name).map_err(|e| e.to_string())?;
+            // SAFETY: the handle came from dlopen and stays open.
+            let sym = unsafe { libc::dlsym(self.0, cname.as_ptr()) };
+            if sym.is_null() {
+                Err(format!("missing symbol {}", name))
+            } else {
+                Ok(sym)
+            }
+        }
+    }
+}
+
+/// Load the plugin at `path`, returning its register and free functions.
+#[cfg(unix)]
+fn open(path: &Path) -> Result<(RegisterFn, FreeFn)> {
+    let fail = |msg: String| PluginError::from(format!("{}: {}", path.display(), msg));
+    // dlerror already names the library.
+    let lib = dl::Library::open(path).map_err(PluginError::from)?;
+
+    // SAFETY: the symbols are declared by the plugin interface above; a
+    // library exporting them with other signatures is undefined behaviour,
+    // as with any C interface.
+    let (abi, register, free) = unsafe {
+        (
+            std::mem::transmute::<*mut c_void, AbiFn>(
+                lib.symbol("ttgen_plugin_abi").map_err(fail)?,
+            ),
+            std::mem::transmute::<*mut c_void, RegisterFn>(
+                lib.symbol("ttgen_plugin_register").map_err(fail)?,
+            ),
+            std::mem::transmute::<*mut c_void, FreeFn>(
+                lib.symbol("ttgen_plugin_free").map_err(fail)?,
+            ),
+        )
+    };
+
+    // SAFETY: see above.
+    let version = unsafe { abi() };
+    if version != ABI_VERSION {
+        let msg = format!("plugin interface {}, expected {}", version, ABI_VERSION);
+        return Err(fail(msg).into());
+    }
+    Ok((register, free))
+}
+
+#[cfg(not(unix))]
+fn open(path: &Path) -> Result<(RegisterFn, FreeFn)> {
+    let msg = format!("{}: plugins are only supported on unix", path.display());
+    Err(PluginError::from(msg).into())
+}
+
+/// Load the plugin at `path` and register its helpers with `hb`, returning
+/// their names.
+pub fn load(hb: &mut Handlebars, path: &Path) -> Result<Vec<String>> {
+    let (register, free) = open(path)?;
+    Ok(register_all(hb, register, free, None))
+}
+
+/// Load the plugin at `path` and register only its helper called `name`.
+pub fn load_helper(hb: &mut Handlebars, path: &Path, name: &str) -> Result<()> {
+    let (register, free) = open(path)?;
+    if register_all(hb, register, free, Some(name)).is_empty() {
+        let msg = format!("{}: no helper named {}", path.display(), name);
+        return Err(PluginError::from(msg).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    unsafe extern "C" fn shout(args: *const c_char, out: *mut *mut c_char) -> c_int {
+        let args: Value = serde_json::from_slice(CStr::from_ptr(args).to_bytes()).unwrap();
+        let (text, status) = match args["params"][0].as_str() {
+            Some(s) => (
+                format!(
+                    "{}{}",
+                    s.to_uppercase(),
+                    args["hash"]["end"].as_str().unwrap_or("")
+                ),
+                0,
+            ),
+            None => ("expected a string".to_string(), 1),
+        };
+        *out = CString::new(text).unwrap().into_raw();
+        status
+    }
+
+    unsafe extern "C" fn free(s: *mut c_char) {
+        drop(CString::from_raw(s));
+    }
+
+    unsafe extern "C" fn register(ctx: *mut c_void, add: AddFn) {
+        add(ctx, b"shout\0".as_ptr() as *const c_char, shout);
+    }
+
+    #[test]
+    fn registers_and_calls_plugin_helpers() {
+        let mut hb = Handlebars::new();
+        assert!(register_all(&mut hb, register, free, Some("other")).is_empty());
+        assert_eq!(register_all(&mut hb, register, free, None), vec!["shout"]);
+
+        let data = json!({"name": "ttgen", "n": 1});
+        let rendered = hb.render_template("{{shout name end=\"!\"}}", &data);
+        assert_eq!(rendered.unwrap(), "TTGEN!");
+
+        let err = hb.render_template("{{shout n}}", &data).unwrap_err();
+        assert!(err.to_string().contains("helper shout: expected a string"));
+    }
+}
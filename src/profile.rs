@@ -0,0 +1,105 @@
+//! Per-phase timings of a run, enabled with `multigen --profile-out FILE`.
+//!
+//! Each span of work is written to FILE as a complete event of the Chrome
+//! trace format, which `chrome://tracing`, Perfetto and speedscope all open.
+//! The event is named for its phase: `spec load`, `read`, `parse`, `hash`,
+//! `compile`, `render` or `write`, and its `for` argument is the entry or
+//! file it was for.  Threads are numbered in the order of their first span.
+//!
+//! Outputs are buffered, so `render` includes the writes of full buffers and
+//! `write` covers creating the output and writing out the rest.  Data files
+//! with `select` are read, hashed and parsed in one pass, all under `parse`.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+
+use crate::error::*;
+
+static PROFILE: OnceCell<Profile> = OnceCell::new();
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD: usize = THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+struct Profile {
+    path: PathBuf,
+    start: Instant,
+    events: Mutex<Vec<Value>>,
+}
+
+/// Record the spans of this run, written to `path` by `save`.
+pub fn start(path: &Path) {
+    let _ = PROFILE.set(Profile {
+        path: path.to_path_buf(),
+        start: Instant::now(),
+        events: Mutex::new(Vec::new()),
+    });
+}
+
+/// Run `f` as a span of `phase` for `subject`, if profiling.
+pub fn span<T, F: FnOnce() -> T>(phase: &str, subject: &dyn Display, f: F) -> T {
+    let profile = match PROFILE.get() {
+        Some(profile) => profile,
+        None => return f(),
+    };
+    let start = profile.start.elapsed();
+    let result = f();
+    let duration = profile.start.elapsed() - start;
+    let event = json!({
+        "name": phase,
+        "cat": "ttgen",
+        "ph": "X",
+        "ts": start.as_secs_f64() * 1e6,
+        "dur": duration.as_secs_f64() * 1e6,
+        "pid": std::process::id(),
+        "tid": THREAD.with(|&id| id),
+        "args": {"for": subject.to_string()},
+    });
+    profile.events.lock().unwrap().push(event);
+    result
+}
+
+/// Write the recorded spans out, if profiling.
+pub fn save() -> Result<()> {
+    let profile = match PROFILE.get() {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+    let events = profile.events.lock().unwrap();
+    let trace = json!({"traceEvents": *events, "displayTimeUnit": "ms"});
+    fs::write(&profile.path, serde_json::to_vec(&trace)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_chrome_trace_events() {
+        let path = std::env::temp_dir().join(format!("ttgen-profile-{}", std::process::id()));
+        start(&path);
+        assert_eq!(span("render", &"example", || 42), 42);
+        save().unwrap_or_else(|e| panic!("{}", e));
+
+        let trace: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let event = events
+            .iter()
+            .find(|e| e["args"]["for"] == "example")
+            .unwrap();
+        assert_eq!(event["name"], "render");
+        assert_eq!(event["ph"], "X");
+        assert!(event["dur"].as_f64().unwrap() >= 0.0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,211 @@
-use std::fs::File;
-use std::io::{copy, prelude::*};
-use std::path::Path;
+//! Rendering spec entries with the shared registry.
+//!
+//! A run configures one `Handlebars` registry, with every helper, partial and
+//! instrumentation registered up front, then shares it by reference across
+//! the rayon pool; nothing registers anything per entry.  `TemplateCache`
+//! and `DataCache` are likewise built once and only read while rendering.
+//! Run-wide values such as `set_git` are set once, before the first render,
+//! and the state a render changes as it goes (the escape mode, partial depth
+//! and deadline, and what `--collect-missing` and `--warn-unused` record) is
+//! thread-local, so entries rendering at the same time never meet.  For the
+//! same reason a render never waits on other jobs of the pool, as with
+//! `rayon::join`: the thread could run another entry meanwhile, on top of
+//! the state of the first.  Work it does in parallel runs on threads of its
+//! own (see `join`).
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{copy, prelude::*, BufReader, BufWriter, Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use chrono::Utc;
-use handlebars::{Context, Handlebars, Helper, Output, RenderContext, RenderError};
-use once_cell::sync::Lazy;
-use serde_json::{Map, Value};
-use sha2::{Digest, Sha256};
+use chrono::{DateTime, TimeZone, Utc};
+use handlebars::{
+    html_escape, no_escape, Context, Handlebars, Helper, Output, RenderContext, RenderError,
+    Renderable, Template, TemplateRenderError,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use rayon::prelude::*;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256, Sha512};
+use walkdir::WalkDir;
 
+use crate::env;
 use crate::error::*;
-use crate::spec::TemplateDef;
+use crate::files;
+use crate::frontmatter::{self, FrontMatter};
+use crate::helpers;
+use crate::i18n;
+use crate::limits::{self, IoLimited, LimitWriter};
+use crate::missing;
+use crate::profile;
+use crate::select;
+use crate::spec::{Engine, Escape, HashAlgorithm, TemplateDef, Whitespace};
+use crate::state;
+use crate::subst;
+use crate::trace;
+use crate::usage;
+use crate::whitespace;
 
 static NAME: Lazy<String> = Lazy::new(|| clap::crate_name!().to_string());
 static VERSION: Lazy<String> = Lazy::new(|| clap::crate_version!().to_string());
-static DATESTAMP: Lazy<String> = Lazy::new(|| Utc::now().to_rfc3339());
+static REPRODUCIBLE: AtomicBool = AtomicBool::new(false);
+static NOW: Lazy<DateTime<Utc>> = Lazy::new(|| {
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok();
+    run_time(epoch, REPRODUCIBLE.load(Ordering::Relaxed))
+});
+static DATESTAMP: Lazy<String> = Lazy::new(|| NOW.to_rfc3339());
+static GIT: OnceCell<Value> = OnceCell::new();
+static BUILD_ID: OnceCell<u64> = OnceCell::new();
+static HOST: OnceCell<Map<String, Value>> = OnceCell::new();
+static CONTEXT: OnceCell<Map<String, Value>> = OnceCell::new();
+static SIBLINGS: OnceCell<Vec<(PathBuf, Map<String, Value>)>> = OnceCell::new();
+
+// The registry and caches are shared across the pool.
+const _: fn() = || {
+    fn shared<T: Send + Sync>() {}
+    shared::<Handlebars>();
+    shared::<TemplateCache>();
+    shared::<DataCache>();
+};
+
+thread_local! {
+    // The registry is shared across threads, so the per-template escape mode
+    // is looked up by the registered escape fn at render time.
+    static ESCAPE: Cell<Escape> = const { Cell::new(Escape::Html) };
+}
+
+/// The time of this run: `SOURCE_DATE_EPOCH`, for reproducible builds, or
+/// else the current time, or the Unix epoch in reproducible mode.  A
+/// malformed `SOURCE_DATE_EPOCH` is warned about and ignored.
+fn run_time(epoch: Option<String>, reproducible: bool) -> DateTime<Utc> {
+    let fallback = || {
+        if reproducible {
+            Utc.timestamp(0, 0)
+        } else {
+            Utc::now()
+        }
+    };
+    let epoch = match epoch {
+        Some(epoch) => epoch,
+        None => return fallback(),
+    };
+    match epoch
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+    {
+        Some(time) => time,
+        None => {
+            warn!("ignoring invalid SOURCE_DATE_EPOCH {:?}", epoch);
+            fallback()
+        }
+    }
+}
+
+/// The time of this run, as given to templates in `date`, `day` and `epoch`.
+pub fn now() -> DateTime<Utc> {
+    *NOW
+}
+
+/// Make the run time fixed, so that rendering unchanged inputs gives the
+/// same output: `SOURCE_DATE_EPOCH` if set, or else the Unix epoch.  Must be
+/// called before anything is rendered.
+pub fn set_reproducible() {
+    REPRODUCIBLE.store(true, Ordering::Relaxed);
+}
+
+/// Add `context` to the root context of every template.  Its keys may not
+/// shadow the built-in values.
+pub fn set_context(context: Map<String, Value>) {
+    let _ = CONTEXT.set(context);
+}
+
+/// Give every template the `siblings` list: the name, output and data
+/// `fields` of each of `specs`.  Data that can't be read lists null fields;
+/// the entry itself reports the error when it's rendered.
+pub fn set_siblings(specs: &[TemplateDef], fields: &[String]) {
+    let siblings = specs
+        .par_iter()
+        .map(|spec| {
+            let data = match DataFile::load(&spec.data, HashAlgorithm::None, spec.select.as_deref())
+            {
+                Ok(data) => data.value,
+                Err(e) => {
+                    debug!("{}: no sibling fields: {}", spec.name, e);
+                    Arc::new(Value::Null)
+                }
+            };
+            let selected: Map<String, Value> = fields
+                .iter()
+                .map(|f| (f.clone(), data.get(f).cloned().unwrap_or(Value::Null)))
+                .collect();
+            let mut sibling = Map::new();
+            sibling.insert("name".to_string(), Value::from(spec.name.clone()));
+            sibling.insert(
+                "output".to_string(),
+                Value::from(spec.output.display().to_string()),
+            );
+            sibling.insert("data".to_string(), Value::Object(selected));
+            (spec.output.clone(), sibling)
+        })
+        .collect();
+    let _ = SIBLINGS.set(siblings);
+}
+
+/// Give every template the `host`, `user` and `cwd` values read by
+/// `host::metadata`.
+pub fn set_host(host: Map<String, Value>) {
+    let _ = HOST.set(host);
+}
+
+/// Give every template this run's `build_id`.
+pub fn set_build_id(id: u64) {
+    let _ = BUILD_ID.set(id);
+}
+
+/// Give every template `git` metadata, as read by `git::metadata`.
+pub fn set_git(git: Value) {
+    let _ = GIT.set(git);
+}
+
+/// The `git` metadata given to every template, if set.
+pub fn git() -> Option<&'static Value> {
+    GIT.get()
+}
+
+/// The host values given to every template, if set.
+pub fn host() -> Option<&'static Map<String, Value>> {
+    HOST.get()
+}
+
+fn escape(data: &str) -> String {
+    match ESCAPE.with(Cell::get) {
+        Escape::Html => html_escape(data),
+        Escape::Latex => helpers::latex_escape(data),
+        Escape::None => no_escape(data),
+    }
+}
+
+/// Sets the escape mode for renders on this thread until dropped.
+struct EscapeGuard(Escape);
+
+impl EscapeGuard {
+    fn set(mode: Escape) -> Self {
+        Self(ESCAPE.with(|e| e.replace(mode)))
+    }
+}
+
+impl Drop for EscapeGuard {
+    fn drop(&mut self) {
+        ESCAPE.with(|e| e.set(self.0));
+    }
+}
 
 fn pyprint(
     h: &Helper,
@@ -48,24 +239,340 @@ fn pyprint(
 pub fn get_renderer() -> Handlebars {
     let mut hb = Handlebars::new();
     hb.set_strict_mode(true);
+    hb.register_escape_fn(escape);
     hb.register_template_string("rst_stamp", include_str!("builtins/rst_stamp.hbs"))
         .expect("rst stamp failed to compile");
     hb.register_helper("pyprint", Box::new(pyprint));
+    helpers::register(&mut hb);
+    files::register(&mut hb, None);
+    env::register(&mut hb, Vec::new());
+    i18n::register(&mut hb, Default::default());
+    limits::register(&mut hb);
     hb
 }
 
-fn hash_file<P: AsRef<Path>>(p: P) -> Result<String> {
-    let mut stream = File::open(p)?;
-    let mut hasher = Sha256::new();
-    copy(&mut stream, &mut hasher)?;
-    Ok(format!("{:x}", hasher.result()))
+/// The hex digest of whatever `feed` writes, or null for `HashAlgorithm::None`,
+/// in which case `feed` isn't called.
+fn hash_with<F>(algorithm: HashAlgorithm, feed: F) -> Result<Value>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let digest = match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            feed(&mut hasher)?;
+            format!("{:x}", hasher.result())
+        }
+        HashAlgorithm::Sha512 => {
+            let mut hasher = Sha512::new();
+            feed(&mut hasher)?;
+            format!("{:x}", hasher.result())
+        }
+        HashAlgorithm::None => return Ok(Value::Null),
+    };
+    Ok(Value::String(digest))
+}
+
+fn hash_reader<R: Read>(mut stream: R, algorithm: HashAlgorithm) -> Result<Value> {
+    hash_with(algorithm, |hasher| {
+        copy(&mut stream, hasher)?;
+        Ok(())
+    })
+}
+
+/// Read buffer size for hashing input files, so that large inputs are
+/// hashed in few reads rather than in `copy`'s 8 KiB pieces.
+const HASH_BUFFER: usize = 1024 * 1024;
+
+fn open_input(p: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::with_capacity(HASH_BUFFER, File::open(p)?))
+}
+
+/// Hash the relative paths and contents of every file below `p`, in name
+/// order.  Each is preceded by its length, so moving bytes between a path
+/// and a file's contents changes the digest.
+fn hash_tree<P: AsRef<Path>>(p: P, algorithm: HashAlgorithm) -> Result<Value> {
+    let root = p.as_ref();
+    hash_with(algorithm, |hasher| {
+        let walker = WalkDir::new(root).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walker {
+            let entry = entry.map_err(IOError::from)?;
+            if entry.file_type().is_file() {
+                let relative = entry
+                    .path()
+                    .strip_prefix(root)
+                    .unwrap_or_else(|_| entry.path());
+                let relative = relative.to_string_lossy();
+                hasher.write_all(&(relative.len() as u64).to_le_bytes())?;
+                hasher.write_all(relative.as_bytes())?;
+                limits::io(|| {
+                    let input = open_input(entry.path())?;
+                    let len = input.get_ref().metadata()?.len();
+                    hasher.write_all(&len.to_le_bytes())?;
+                    copy(&mut input.take(len), hasher).map_err(Error::from)
+                })?;
+            }
+        }
+        Ok(())
+    })
 }
 
-fn create_root_map(spec: &TemplateDef) -> Result<Map<String, Value>> {
-    let mut root_map = Map::new();
-    root_map.insert("name".to_string(), Value::from(&**NAME));
-    root_map.insert("version".to_string(), Value::from(&**VERSION));
-    root_map.insert("date".to_string(), Value::from(&**DATESTAMP));
+/// The SHA-256 digest of the file or tree at `p`.
+pub fn digest(p: &Path) -> Result<String> {
+    let algorithm = HashAlgorithm::Sha256;
+    let digest = if p.is_dir() {
+        hash_tree(p, algorithm)?
+    } else {
+        state::cached_digest(p, algorithm, || {
+            limits::io(|| hash_reader(open_input(p)?, algorithm))
+        })?
+    };
+    Ok(digest.as_str().unwrap_or_default().to_string())
+}
+
+/// A reader that also feeds what it reads to `hasher`.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut dyn Write,
+}
+
+impl<'a, R: Read> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// A parsed data file together with the digest of its contents.
+#[derive(Clone)]
+pub struct DataFile {
+    hash: Value,
+    value: Arc<Value>,
+}
+
+impl DataFile {
+    /// Read `p` once, hashing and parsing the same bytes.  For large files
+    /// the two take about as long, so they run in parallel.
+    fn load<P: AsRef<Path>>(p: P, algorithm: HashAlgorithm, select: Option<&str>) -> Result<Self> {
+        let p = p.as_ref();
+        let file = p.display();
+        if let Some(pointer) = select {
+            return profile::span("parse", &file, || Self::stream(p, algorithm, pointer))
+                .within(&file);
+        }
+        let bytes = profile::span("read", &file, || limits::io(|| fs::read(p))).within(&file)?;
+        let (hash, value) = join(
+            || {
+                profile::span("hash", &file, || {
+                    state::cached_digest(p, algorithm, || hash_reader(&bytes[..], algorithm))
+                })
+            },
+            || profile::span("parse", &file, || serde_json::from_slice(&bytes)),
+        );
+        Ok(Self {
+            hash: hash?,
+            value: Arc::new(value.within(&file)?),
+        })
+    }
+
+    /// Parse only the part of `p` at `pointer`, hashing the file as it is
+    /// read rather than holding all of it, unless its digest is cached.
+    fn stream(p: &Path, algorithm: HashAlgorithm, pointer: &str) -> Result<Self> {
+        let open = || -> Result<_> {
+            let file = limits::io(|| File::open(p))?;
+            Ok(IoLimited(file))
+        };
+        let mut value = None;
+        let hash = state::cached_digest(p, algorithm, || {
+            hash_with(algorithm, |hasher| {
+                let inner = open()?;
+                let reader = BufReader::with_capacity(HASH_BUFFER, HashingReader { inner, hasher });
+                value = Some(select::from_reader(reader, pointer)?);
+                Ok(())
+            })
+        })?;
+        let value = match value {
+            Some(value) => value,
+            None => select::from_reader(BufReader::with_capacity(HASH_BUFFER, open()?), pointer)?,
+        };
+        Ok(Self {
+            hash,
+            value: Arc::new(value),
+        })
+    }
+}
+
+/// Run `a` and `b` in parallel, `b` on a thread of its own.  Unlike with
+/// `rayon::join`, the caller waits for `b` without running other jobs.
+fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA,
+    B: FnOnce() -> RB + Send,
+    RB: Send,
+{
+    std::thread::scope(|s| {
+        let b = s.spawn(b);
+        let a = a();
+        (a, b.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+    })
+}
+
+/// The template digest from `hash_template` together with `spec`'s data,
+/// hashed and loaded in parallel.
+fn load_inputs<F>(spec: &TemplateDef, hash_template: F) -> Result<(Value, DataFile)>
+where
+    F: FnOnce() -> Result<Value>,
+{
+    let (hash, data) = join(
+        || profile::span("hash", &spec.template.display(), hash_template),
+        || DataFile::load(&spec.data, spec.hash, spec.select.as_deref()),
+    );
+    Ok((hash?, data?))
+}
+
+/// Data files referenced by more than one spec entry, parsed once per run.
+pub struct DataCache(HashMap<DataKey, DataFile>);
+
+/// A data file by canonical path, digest and selected part.
+type DataKey = (PathBuf, HashAlgorithm, Option<String>);
+
+impl DataCache {
+    /// Load, in parallel, every data file shared by several entries of `specs`.
+    ///
+    /// Files that fail to load are left out, so the entries using them report
+    /// the error when they are rendered.
+    pub fn build<'a, I>(specs: I) -> Self
+    where
+        I: IntoIterator<Item = &'a TemplateDef>,
+    {
+        let mut uses: HashMap<DataKey, usize> = HashMap::new();
+        for spec in specs {
+            *uses.entry(Self::key(spec)).or_default() += 1;
+        }
+        let shared: Vec<DataKey> = uses
+            .into_iter()
+            .filter(|&(_, n)| n > 1)
+            .map(|(key, _)| key)
+            .collect();
+
+        let cache = shared
+            .into_par_iter()
+            .filter_map(|(p, algorithm, select)| {
+                match DataFile::load(&p, algorithm, select.as_deref()) {
+                    Ok(data) => Some(((p, algorithm, select), data)),
+                    Err(e) => {
+                        debug!("not caching {}: {}", p.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self(cache)
+    }
+
+    /// The data file's canonical path, like `TemplateCache::key`.
+    fn key(spec: &TemplateDef) -> DataKey {
+        let path = fs::canonicalize(&spec.data).unwrap_or_else(|_| spec.data.clone());
+        (path, spec.hash, spec.select.clone())
+    }
+
+    fn get_or_load(&self, spec: &TemplateDef) -> Result<DataFile> {
+        match self.0.get(&Self::key(spec)) {
+            Some(data) => Ok(data.clone()),
+            None => DataFile::load(&spec.data, spec.hash, spec.select.as_deref()),
+        }
+    }
+}
+
+/// `spec`'s output relative to the working directory, without `.` or `..`
+/// components that can be resolved, or as given if it can't be made relative.
+fn relative_output(spec: &TemplateDef) -> PathBuf {
+    let output = &spec.output;
+    let relative = if output.is_absolute() {
+        std::env::current_dir()
+            .ok()
+            .and_then(|cwd| helpers::relative_to(output, &cwd).ok())
+    } else {
+        helpers::relative_to(output, Path::new("")).ok()
+    };
+    relative.unwrap_or_else(|| output.clone())
+}
+
+/// A line for each output path shared by several of `specs`, naming them.
+pub fn duplicate_outputs(specs: &[TemplateDef]) -> Vec<String> {
+    let mut entries: BTreeMap<PathBuf, Vec<&str>> = BTreeMap::new();
+    for spec in specs {
+        entries
+            .entry(relative_output(spec))
+            .or_default()
+            .push(&spec.name);
+    }
+    entries
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(output, names)| {
+            format!("{} is the output of {}", output.display(), names.join(", "))
+        })
+        .collect()
+}
+
+/// The `spec` value: the entry's name and output path, as given and
+/// relative to the working directory.
+fn spec_value(spec: &TemplateDef) -> Value {
+    let output = &spec.output;
+    let relative = relative_output(spec);
+    json!({
+        "name": spec.name,
+        "output": output.display().to_string(),
+        "output_relative": relative.display().to_string(),
+    })
+}
+
+/// The `siblings` of `spec`, each with a `link` to its output from the
+/// directory of `spec`'s own.
+fn siblings_value(spec: &TemplateDef, siblings: &[(PathBuf, Map<String, Value>)]) -> Value {
+    let base = spec.output.parent().unwrap_or_else(|| Path::new(""));
+    let siblings = siblings.iter().map(|(output, sibling)| {
+        let link = helpers::relative_to(output, base).unwrap_or_else(|_| output.clone());
+        let mut sibling = sibling.clone();
+        sibling.insert("link".to_string(), Value::from(link.display().to_string()));
+        Value::Object(sibling)
+    });
+    Value::Array(siblings.collect())
+}
+
+/// The root values that are the same for every entry of a run, built on the
+/// first render, once the run-wide settings are made.
+static BASE: Lazy<Map<String, Value>> = Lazy::new(|| {
+    let mut base = Map::new();
+    base.insert("name".to_string(), Value::from(&**NAME));
+    base.insert("version".to_string(), Value::from(&**VERSION));
+    base.insert("date".to_string(), Value::from(&**DATESTAMP));
+    base.insert("epoch".to_string(), Value::from(NOW.timestamp()));
+    base.insert(
+        "day".to_string(),
+        Value::from(NOW.format("%Y-%m-%d").to_string()),
+    );
+    base.insert("rst_stamp".to_string(), Value::from("rst_stamp"));
+    if let Some(git) = GIT.get() {
+        base.insert("git".to_string(), git.clone());
+    }
+    if let Some(&id) = BUILD_ID.get() {
+        base.insert("build_id".to_string(), Value::from(id));
+    }
+    if let Some(host) = HOST.get() {
+        base.extend(host.clone());
+    }
+    base
+});
+
+fn create_root_map(
+    spec: &TemplateDef,
+    template_hash: Value,
+    data: DataFile,
+) -> Result<Map<String, Value>> {
+    let mut root_map = BASE.clone();
     root_map.insert(
         "data_file".to_string(),
         Value::from(spec.data.display().to_string()),
@@ -74,28 +581,677 @@ fn create_root_map(spec: &TemplateDef) -> Result<Map<String, Value>> {
         "template_file".to_string(),
         Value::from(spec.template.display().to_string()),
     );
-    root_map.insert("data_hash".to_string(), Value::from(hash_file(&spec.data)?));
-    root_map.insert(
-        "template_hash".to_string(),
-        Value::from(hash_file(&spec.template)?),
-    );
-    root_map.insert(
-        "root".to_string(),
-        serde_json::from_reader(File::open(&spec.data)?)?,
-    );
-    root_map.insert("rst_stamp".to_string(), Value::from("rst_stamp"));
+    root_map.insert("data_hash".to_string(), data.hash);
+    root_map.insert("template_hash".to_string(), template_hash);
+    // Data used by this entry alone is moved.  A handlebars context owns
+    // its data, so entries sharing a cached file each render a copy of it;
+    // the file is still only read and parsed once.
+    let value = Arc::try_unwrap(data.value).unwrap_or_else(|shared| (*shared).clone());
+    root_map.insert("root".to_string(), value);
+    root_map.insert("spec".to_string(), spec_value(spec));
+    if let Some(siblings) = SIBLINGS.get() {
+        root_map.insert("siblings".to_string(), siblings_value(spec, siblings));
+    }
+    for (key, value) in CONTEXT.get().into_iter().flatten() {
+        if root_map.contains_key(key) {
+            let msg = format!("context value {:?} shadows a built-in value", key);
+            return Err(RenderError::new(msg).into());
+        }
+        root_map.insert(key.clone(), value.clone());
+    }
 
     Ok(root_map)
 }
 
+/// Write buffer size for rendered output, which handlebars writes in many
+/// small pieces.
+pub const OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// Create `p` for writing rendered output.  Callers must flush the writer,
+/// since errors are lost if it is only dropped.
+pub fn create_output<P: AsRef<Path>>(p: P) -> Result<BufWriter<IoLimited<File>>> {
+    let file = limits::io(|| File::create(p))?;
+    Ok(BufWriter::with_capacity(OUTPUT_BUFFER, IoLimited(file)))
+}
+
+/// Lines of context quoted on either side of an error.
+const SNIPPET_CONTEXT: usize = 2;
+
+/// Number of front matter lines that precede `body` in `source`.
+fn body_offset(source: &str, body: &str) -> usize {
+    source[..source.len() - body.len()].matches('\n').count()
+}
+
+/// Quote the lines of `body` around `line`, with a caret under `column`.
+/// Line numbers are shifted by `offset` so they match the file.
+fn snippet(body: &str, line: usize, column: usize, offset: usize) -> String {
+    let lines: Vec<&str> = body.lines().collect();
+    let first = line.saturating_sub(SNIPPET_CONTEXT).max(1);
+    let last = (line + SNIPPET_CONTEXT).min(lines.len().max(line));
+    let width = (last + offset).to_string().len();
+
+    let mut out = format!("{:w$} |\n", "", w = width);
+    for n in first..=last {
+        let text = lines.get(n - 1).copied().unwrap_or("");
+        out.push_str(&format!("{:>w$} | {}\n", n + offset, text, w = width));
+        if n == line {
+            // Keep tabs so the caret lines up with the quoted text.
+            let pad: String = text
+                .chars()
+                .take(column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!("{:w$} | {}^\n", "", pad, w = width));
+        }
+    }
+    out
+}
+
+/// The message of `e`.  handlebars describes wrapped errors, e.g. I/O errors,
+/// with the deprecated `Error::description`, so prefer the cause's own.
+#[allow(deprecated)]
+fn message(e: &RenderError) -> String {
+    match std::error::Error::cause(e) {
+        Some(cause) => cause.to_string(),
+        None => e.desc.clone(),
+    }
+}
+
+/// Turn a handlebars error raised in `body` into a `SourceError` quoting the
+/// offending lines.  Errors without a position, or raised in another template
+/// such as a partial, are returned unchanged.
+fn locate(err: Error, name: &str, body: &str, offset: usize) -> Error {
+    let (template, line, column, message) = match &err {
+        TTGenError::RenderError(e) => (&e.template_name, e.line_no, e.column_no, message(e)),
+        TTGenError::TemplateRenderError(e) => match &**e {
+            TemplateRenderError::TemplateError(e) => (
+                &e.template_name,
+                e.line_no,
+                e.column_no,
+                e.reason.to_string(),
+            ),
+            TemplateRenderError::RenderError(e) => {
+                (&e.template_name, e.line_no, e.column_no, message(e))
+            }
+            _ => return err,
+        },
+        _ => return err,
+    };
+    let ours = template.as_ref().is_none_or(|t| t == name);
+
+    match (line, column) {
+        (Some(line), Some(column)) if ours => SourceError {
+            code: err.code(),
+            name: name.to_string(),
+            line: line + offset,
+            column,
+            message,
+            snippet: snippet(body, line, column, offset),
+        }
+        .into(),
+        _ => err,
+    }
+}
+
+/// Parse `source` as a handlebars template named `name`, without rendering it.
+///
+/// Front matter is validated and skipped; reported lines match the file.
+pub fn check_source(name: &str, source: &str) -> Result<()> {
+    let (_, body) = frontmatter::split(source)?;
+    Template::compile_with_name(body, name.to_string(), true).map_err(|e| {
+        locate(
+            TemplateRenderError::from(e).into(),
+            name,
+            body,
+            body_offset(source, body),
+        )
+    })?;
+    Ok(())
+}
+
+/// Resolve the output path declared in the front matter of `source`, if any.
+pub fn front_matter_output(
+    spec: &TemplateDef,
+    source: &str,
+    hb: &Handlebars,
+) -> Result<Option<PathBuf>> {
+    let (front, _) = frontmatter::split(source)?;
+    match front.output {
+        Some(pattern) => {
+            let (template_hash, data) =
+                load_inputs(spec, || hash_reader(source.as_bytes(), spec.hash))?;
+            let mut root_map = create_root_map(spec, template_hash, data)?;
+            root_map.extend(front.context);
+            Ok(Some(hb.render_template(&pattern, &root_map)?.into()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A render context holding `root_map`, moved in rather than serialized
+/// into a copy.
+fn context(root_map: Map<String, Value>) -> Context {
+    let mut ctx = Context::null();
+    *ctx.data_mut() = Value::Object(root_map);
+    ctx
+}
+
+/// The root map of `ctx`, which `context` made.
+fn root_of(ctx: &Context) -> &Map<String, Value> {
+    match ctx.data() {
+        Value::Object(map) => map,
+        _ => unreachable!("render contexts hold the root map"),
+    }
+}
+
+/// Merge the front matter context, if any, over the root context.  A
+/// borrowed context is only copied when there is something to merge.
+fn scoped_root<'a>(ctx: Cow<'a, Context>, front: &FrontMatter) -> Cow<'a, Context> {
+    if front.context.is_empty() {
+        return ctx;
+    }
+    let mut ctx = ctx.into_owned();
+    if let Value::Object(map) = ctx.data_mut() {
+        map.extend(front.context.clone());
+    }
+    Cow::Owned(ctx)
+}
+
+/// Compile `body` as the template `name`, instrumented for whichever of
+/// tracing, usage tracking and missing variable collection are registered
+/// with `hb`, and with partials wrapped to limit their nesting.
+fn compile(name: &str, body: &str, ws: Whitespace, hb: &Handlebars) -> Result<Template> {
+    let mut template = Template::compile_with_name(body, name.to_string(), true)
+        .map_err(TemplateRenderError::from)?;
+    whitespace::apply(&mut template, ws);
+    for helper in &[trace::HELPER_NAME, usage::HELPER_NAME, missing::HELPER_NAME] {
+        if hb.get_helper(helper).is_some() {
+            trace::instrument(&mut template, helper);
+        }
+    }
+    limits::instrument(&mut template);
+    Ok(template)
+}
+
+/// Render `template` with `ctx`.
+fn render_compiled<W: Write>(
+    template: &Template,
+    ctx: &Context,
+    hb: &Handlebars,
+    writer: &mut W,
+) -> StdResult<(), RenderError> {
+    usage::index(ctx);
+    let mut rc = RenderContext::new(template.name.as_ref());
+    template.render(hb, ctx, &mut rc, &mut trace::WriteOutput(writer))
+}
+
+/// Run `render` for `spec`, reporting what the instrumentation registered
+/// with `hb` found: unused data keys are warned about, missing variables
+/// fail the render once it has finished.
+fn tracked<F>(spec: &TemplateDef, hb: &Handlebars, render: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    if missing::enabled(hb) {
+        let (result, missing) = missing::collect(|| tracked_usage(spec, hb, render));
+        return match result {
+            Ok(()) if !missing.is_empty() => Err(Undefined::from(missing).into()),
+            _ => result,
+        };
+    }
+    tracked_usage(spec, hb, render)
+}
+
+fn tracked_usage<F>(spec: &TemplateDef, hb: &Handlebars, render: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    if !usage::enabled(hb) {
+        return render();
+    }
+    let (result, unused) = usage::track(render);
+    if result.is_ok() && !unused.is_empty() {
+        eprintln!(
+            "warning: {}: unused keys in {}: {}",
+            spec.name,
+            spec.data.display(),
+            unused.join(", ")
+        );
+    }
+    result
+}
+
+/// Render `source`, read from the template file `name`, with `ctx`.
+fn render_body<W: Write>(
+    spec: &TemplateDef,
+    name: &str,
+    source: &str,
+    ctx: Cow<Context>,
+    hb: &Handlebars,
+    writer: &mut W,
+) -> Result<()> {
+    let (front, body) = frontmatter::split(source)?;
+    let ctx = scoped_root(ctx, &front);
+
+    let _escape = EscapeGuard::set(front.escape.unwrap_or_default());
+    let writer = &mut LimitWriter::new(writer);
+    let rendered = match spec.engine {
+        Engine::Handlebars => compile(name, body, spec.whitespace, hb)
+            .and_then(|t| Ok(render_compiled(&t, &ctx, hb, writer)?)),
+        Engine::Subst => subst::render(body, root_of(&ctx), writer),
+    };
+    rendered.map_err(|e| locate(e, name, body, body_offset(source, body)))
+}
+
+struct CachedTemplate {
+    /// The template path, so render errors point at the file.
+    name: String,
+    template: Template,
+    hash: Value,
+    front: FrontMatter,
+    body: String,
+    /// Front matter lines stripped from the start of `body`.
+    offset: usize,
+}
+
+/// File templates read, hashed and compiled once, then shared by every spec
+/// entry that references them with the same whitespace and hash settings.
+pub struct TemplateCache(HashMap<TemplateKey, CachedTemplate>);
+
+/// A template's canonical path, so every spelling of it shares one entry,
+/// and the settings it's compiled and hashed with.
+type TemplateKey = (PathBuf, Whitespace, HashAlgorithm);
+
+impl TemplateCache {
+    /// Compile, in parallel, each distinct file template referenced by
+    /// `specs` for `hb`.
+    ///
+    /// Templates that fail to load are left out, so the entries using them
+    /// report the error when they are rendered.
+    pub fn build<'a, I>(specs: I, hb: &Handlebars) -> Self
+    where
+        I: IntoIterator<Item = &'a TemplateDef>,
+    {
+        let mut distinct = HashMap::new();
+        for spec in specs {
+            if !spec.template.is_dir() {
+                distinct.entry(Self::key(spec)).or_insert(spec);
+            }
+        }
+        let cache = distinct
+            .into_par_iter()
+            .filter_map(|(key, spec)| match Self::load(spec, hb) {
+                Ok(cached) => Some((key, cached)),
+                Err(e) => {
+                    debug!("not caching {}: {}", spec.template.display(), e);
+                    None
+                }
+            })
+            .collect();
+        Self(cache)
+    }
+
+    fn key(spec: &TemplateDef) -> TemplateKey {
+        let path = fs::canonicalize(&spec.template).unwrap_or_else(|_| spec.template.clone());
+        (path, spec.whitespace, spec.hash)
+    }
+
+    fn load(spec: &TemplateDef, hb: &Handlebars) -> Result<CachedTemplate> {
+        let file = spec.template.display();
+        let source = profile::span("read", &file, || {
+            limits::io(|| fs::read_to_string(&spec.template))
+        })
+        .within(&file)?;
+        let (front, body) = frontmatter::split(&source)?;
+        let name = file.to_string();
+        let template = profile::span("compile", &file, || {
+            compile(&name, body, spec.whitespace, hb)
+        })?;
+
+        let hash = profile::span("hash", &file, || {
+            state::cached_digest(&spec.template, spec.hash, || {
+                hash_reader(source.as_bytes(), spec.hash)
+            })
+        })?;
+
+        Ok(CachedTemplate {
+            hash,
+            offset: body_offset(&source, body),
+            body: body.to_string(),
+            name,
+            template,
+            front,
+        })
+    }
+}
+
+fn render_cached<W: Write>(
+    spec: &TemplateDef,
+    cached: &CachedTemplate,
+    data: DataFile,
+    hb: &Handlebars,
+    writer: &mut W,
+) -> Result<()> {
+    cached.front.reject_output().within(&cached.name)?;
+    let ctx = context(create_root_map(spec, cached.hash.clone(), data)?);
+    let ctx = scoped_root(Cow::Owned(ctx), &cached.front);
+
+    let _escape = EscapeGuard::set(cached.front.escape.unwrap_or_default());
+    let writer = &mut LimitWriter::new(writer);
+    let rendered = match spec.engine {
+        Engine::Handlebars => {
+            render_compiled(&cached.template, &ctx, hb, writer).map_err(Error::from)
+        }
+        Engine::Subst => subst::render(&cached.body, root_of(&ctx), writer),
+    };
+    rendered.map_err(|e| locate(e, &cached.name, &cached.body, cached.offset))
+}
+
+/// Render `source` as the template body of `spec`, e.g. when it was read from stdin.
+pub fn with_source_writer<W: Write>(
+    spec: &TemplateDef,
+    source: &str,
+    hb: &Handlebars,
+    writer: &mut W,
+) -> Result<()> {
+    tracked(spec, hb, || {
+        let (template_hash, data) =
+            load_inputs(spec, || hash_reader(source.as_bytes(), spec.hash))?;
+        let ctx = context(create_root_map(spec, template_hash, data)?);
+        let name = spec.template.display().to_string();
+        profile::span("render", &spec.name, || {
+            render_body(spec, &name, source, Cow::Owned(ctx), hb, writer)
+        })
+    })
+}
+
+/// Render the file template of `spec`, which names its own output.
 pub fn with_writer<W: Write>(spec: &TemplateDef, hb: &Handlebars, writer: &mut W) -> Result<()> {
-    let root_map = create_root_map(spec)?;
-    let mut tmpl_reader = File::open(&spec.template)?;
-    hb.render_template_source_to_write(&mut tmpl_reader, &root_map, writer)?;
+    let mut source = String::new();
+    limits::io(|| File::open(&spec.template)?.read_to_string(&mut source))
+        .within(spec.template.display())?;
+    let (front, _) = frontmatter::split(&source)?;
+    front.reject_output().within(spec.template.display())?;
+    with_source_writer(spec, &source, hb, writer)
+}
+
+/// Render each component of `relative` that contains an expression.
+fn render_path(relative: &Path, root_map: &Map<String, Value>, hb: &Handlebars) -> Result<PathBuf> {
+    let _escape = EscapeGuard::set(Escape::None);
+    let mut rendered = PathBuf::new();
+    for component in relative.components() {
+        let name = component.as_os_str().to_string_lossy();
+        if !name.contains("{{") {
+            rendered.push(component);
+            continue;
+        }
+
+        let name = hb.render_template(&name, root_map)?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            let msg = format!("{} renders to invalid name {:?}", relative.display(), name);
+            return Err(IOError::new(ErrorKind::InvalidData, msg).into());
+        }
+        rendered.push(name);
+    }
+    Ok(rendered)
+}
+
+/// Each file and directory below the `spec.template` directory, with the path
+/// under `spec.output` it renders to, parents before their contents.
+fn tree_targets<'a>(
+    spec: &'a TemplateDef,
+    root_map: &'a Map<String, Value>,
+    hb: &'a Handlebars,
+) -> impl Iterator<Item = Result<(walkdir::DirEntry, PathBuf)>> + 'a {
+    let walker = WalkDir::new(&spec.template)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    walker.into_iter().map(move |entry| {
+        let entry = entry.map_err(IOError::from)?;
+        let relative = entry
+            .path()
+            .strip_prefix(&spec.template)
+            .unwrap_or_else(|_| entry.path());
+        let target = spec.output.join(render_path(relative, root_map, hb)?);
+        Ok((entry, target))
+    })
+}
+
+/// The paths `with_tree` renders `spec` to, each with whether it is a
+/// directory, parents before their contents.
+pub fn tree_outputs(spec: &TemplateDef, hb: &Handlebars) -> Result<Vec<(PathBuf, bool)>> {
+    let data = DataFile::load(&spec.data, HashAlgorithm::None, spec.select.as_deref())?;
+    let root_map = create_root_map(spec, Value::Null, data)?;
+    tree_targets(spec, &root_map, hb)
+        .map(|found| found.map(|(entry, target)| (target, entry.file_type().is_dir())))
+        .collect()
+}
+
+/// Render every file below the `spec.template` directory into a mirrored tree
+/// under `spec.output`.  File and directory names may contain expressions too;
+/// files that aren't UTF-8 are copied verbatim.  Every file renders with the
+/// one context, copied only for files whose front matter adds to it.
+fn with_tree(spec: &TemplateDef, hb: &Handlebars) -> Result<()> {
+    let (template_hash, data) = load_inputs(spec, || hash_tree(&spec.template, spec.hash))?;
+    let ctx = context(create_root_map(spec, template_hash, data)?);
+    for found in tree_targets(spec, root_of(&ctx), hb) {
+        let (entry, target) = found?;
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(p) = target.parent() {
+            fs::create_dir_all(p)?;
+        }
+
+        match String::from_utf8(limits::io(|| fs::read(entry.path()))?) {
+            Ok(source) => {
+                let mut writer = create_output(&target)?;
+                let name = entry.path().display().to_string();
+                frontmatter::split(&source)?
+                    .0
+                    .reject_output()
+                    .within(&name)?;
+                render_body(spec, &name, &source, Cow::Borrowed(&ctx), hb, &mut writer)?;
+                writer.flush()?;
+            }
+            Err(e) => limits::io(|| fs::write(&target, e.into_bytes()))?,
+        }
+    }
     Ok(())
 }
 
+/// Like `with`, but renders templates found in `templates` by name and
+/// reuses data files already parsed into `data`.
+pub fn with_cache(
+    spec: &TemplateDef,
+    templates: &TemplateCache,
+    data: &DataCache,
+    hb: &Handlebars,
+) -> Result<()> {
+    match templates.0.get(&TemplateCache::key(spec)) {
+        Some(cached) => tracked(spec, hb, || {
+            let data = data.get_or_load(spec)?;
+            let mut writer = profile::span("write", &spec.name, || create_output(&spec.output))?;
+            profile::span("render", &spec.name, || {
+                render_cached(spec, cached, data, hb, &mut writer)
+            })?;
+            Ok(profile::span("write", &spec.name, || writer.flush())?)
+        }),
+        None => with(spec, hb),
+    }
+}
+
 pub fn with(spec: &TemplateDef, hb: &Handlebars) -> Result<()> {
-    let mut writer = File::create(&spec.output)?;
-    with_writer(spec, hb, &mut writer)
+    if spec.template.is_dir() {
+        let render = || profile::span("render", &spec.name, || with_tree(spec, hb));
+        return tracked(spec, hb, render);
+    }
+    let mut writer = profile::span("write", &spec.name, || create_output(&spec.output))?;
+    with_writer(spec, hb, &mut writer)?;
+    Ok(profile::span("write", &spec.name, || writer.flush())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spec_value_has_relative_output() {
+        let spec = |output: PathBuf| {
+            TemplateDef::new_unchecked("docs".into(), "d.json".into(), "t.hbs".into(), output)
+        };
+        let value = spec_value(&spec("./out/../docs/index.rst".into()));
+        assert_eq!(value["name"], "docs");
+        assert_eq!(value["output"], "./out/../docs/index.rst");
+        assert_eq!(value["output_relative"], "docs/index.rst");
+        let cwd = std::env::current_dir().unwrap();
+        let value = spec_value(&spec(cwd.join("index.rst")));
+        assert_eq!(value["output_relative"], "index.rst");
+    }
+
+    #[test]
+    fn finds_duplicate_outputs() {
+        let cwd = std::env::current_dir().unwrap();
+        let specs: Vec<TemplateDef> = [
+            ("a", cwd.join("docs/index.rst")),
+            ("b", "docs/other.rst".into()),
+            ("c", "./docs/../docs/index.rst".into()),
+        ]
+        .iter()
+        .map(|(name, output)| {
+            let (d, t) = ("d.json".into(), "t.hbs".into());
+            TemplateDef::new_unchecked(name.to_string(), d, t, output.clone())
+        })
+        .collect();
+        assert_eq!(
+            duplicate_outputs(&specs),
+            vec!["docs/index.rst is the output of a, c"]
+        );
+        assert!(duplicate_outputs(&specs[..2]).is_empty());
+    }
+
+    #[test]
+    fn siblings_link_from_each_output() {
+        let spec = |name: &str, output: &str| {
+            TemplateDef::new_unchecked(name.into(), "d.json".into(), "t.hbs".into(), output.into())
+        };
+        let sibling = |output: &str| {
+            let mut sibling = Map::new();
+            sibling.insert("output".to_string(), Value::from(output));
+            (PathBuf::from(output), sibling)
+        };
+        let siblings = [sibling("docs/index.html"), sibling("docs/api/io.html")];
+        let value = siblings_value(&spec("io", "docs/api/io.html"), &siblings);
+        assert_eq!(value[0]["link"], "../index.html");
+        assert_eq!(value[1]["link"], "io.html");
+        assert_eq!(value[1]["output"], "docs/api/io.html");
+    }
+
+    #[test]
+    fn run_time_honors_source_date_epoch_and_reproducible_mode() {
+        let time = run_time(Some("1700000000".to_string()), false);
+        assert_eq!(time.to_rfc3339(), "2023-11-14T22:13:20+00:00");
+        assert_eq!(run_time(Some("1700000000".to_string()), true), time);
+        let before = Utc::now();
+        assert!(run_time(Some("yesterday".to_string()), false) >= before);
+        assert!(run_time(None, false) >= before);
+        assert_eq!(run_time(None, true).timestamp(), 0);
+        assert_eq!(run_time(Some("".to_string()), true).timestamp(), 0);
+    }
+
+    #[test]
+    fn tree_digests_separate_paths_from_contents() {
+        let dir = std::env::temp_dir().join(format!("ttgen-tree-{}", std::process::id()));
+        let tree = |name: &str, text: &str| {
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(name), text).unwrap();
+            hash_tree(&dir, HashAlgorithm::Sha256).unwrap_or_else(|e| panic!("{}", e))
+        };
+        assert_ne!(tree("a", "bc"), tree("ab", "c"));
+        assert_eq!(tree("a", "bc"), tree("a", "bc"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scoped_root_copies_only_to_merge() {
+        let ctx = context(Map::new());
+        let front = FrontMatter::default();
+        assert!(matches!(
+            scoped_root(Cow::Borrowed(&ctx), &front),
+            Cow::Borrowed(_)
+        ));
+
+        let (front, _) = frontmatter::split("---ttgen\ncontext:\n  x: 1\n---\n")
+            .ok()
+            .unwrap();
+        let scoped = scoped_root(Cow::Borrowed(&ctx), &front);
+        assert_eq!(root_of(&scoped)["x"], 1);
+        assert!(root_of(&ctx).is_empty());
+    }
+
+    #[test]
+    fn check_source_reports_file_lines() {
+        let source = "---ttgen\nescape: none\n---\nok\n{{#each items}}\n{{/if}}\n";
+        let line = match check_source("example.hbs", source) {
+            Err(TTGenError::SourceError(e)) => Some(e.line),
+            _ => None,
+        };
+        assert_eq!(line, Some(6));
+        assert!(check_source("example.hbs", "{{#each items}}{{/each}}").is_ok());
+    }
+
+    #[test]
+    fn entries_collect_only_their_own_missing_variables() {
+        let dir = std::env::temp_dir().join(format!("ttgen-missing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = dir.join("d.json");
+        let pad = "x".repeat(1 << 20);
+        fs::write(&data, serde_json::json!({ "pad": pad }).to_string()).unwrap();
+        let specs: Vec<TemplateDef> = (0..16)
+            .map(|i| {
+                let template = dir.join(format!("t{}.hbs", i));
+                fs::write(&template, format!("{{{{var{}}}}}", i)).unwrap();
+                let output = dir.join(format!("out{}.txt", i));
+                TemplateDef::new_unchecked(i.to_string(), data.clone(), template, output)
+            })
+            .collect();
+        let mut hb = get_renderer();
+        missing::register(&mut hb);
+
+        let found = std::sync::Mutex::new(Vec::new());
+        limits::in_order(&specs, |spec| {
+            let timeout = Some(std::time::Duration::from_secs(60));
+            let result = limits::with_timeout(timeout, || with(spec, &hb));
+            let err = result.err().map_or_else(String::new, |e| e.to_string());
+            found.lock().unwrap().push((spec.name.clone(), err));
+        });
+        for (name, err) in found.into_inner().unwrap() {
+            assert_eq!(err.lines().count(), 1, "{}", err);
+            assert!(err.ends_with(&format!(": var{}\n", name)), "{}", err);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn render_errors_quote_the_source() {
+        let spec =
+            TemplateDef::new_unchecked("t".into(), "d.json".into(), "t.hbs".into(), "-".into());
+        let source = "---ttgen\nescape: none\n---\none\ntwo\n\t{{missing}}\nfour\n";
+        let mut hb = get_renderer();
+        hb.set_strict_mode(true);
+
+        let ctx = Cow::Owned(context(Map::new()));
+        let err = render_body(&spec, "t.hbs", source, ctx, &hb, &mut Vec::new());
+        let err = match err {
+            Err(TTGenError::SourceError(e)) => e,
+            _ => panic!("expected a located error"),
+        };
+        assert_eq!((err.line, err.column), (6, 2));
+        assert_eq!(
+            err.snippet,
+            "  |\n4 | one\n5 | two\n6 | \t{{missing}}\n  | \t^\n7 | four\n"
+        );
+    }
 }
@@ -0,0 +1,163 @@
+//! Partial parsing of data files, for entries with `select`.
+//!
+//! `"select": "/pages/3"` gives the template only that subtree of its data
+//! file as `root`, addressed by a JSON Pointer as in `Value::pointer`.  The
+//! file is streamed rather than read in one piece, and the rest of the
+//! document is checked but skipped without being built, so a data file far
+//! larger than its parsed subtree stays within memory.
+
+use std::fmt;
+use std::io::{Error as IOError, ErrorKind, Read};
+
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::*;
+
+/// The value at `path`, or `None` if there is none.
+struct Select<'a> {
+    path: &'a [String],
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for Select<'a> {
+    type Value = Option<Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> std::result::Result<Self::Value, D::Error> {
+        if self.path.is_empty() {
+            Value::deserialize(d).map(Some)
+        } else {
+            d.deserialize_any(self)
+        }
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Select<'a> {
+    type Value = Option<Value>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let (key, rest) = self.path.split_first().unwrap();
+        let mut found = None;
+        while let Some(k) = map.next_key::<String>()? {
+            if found.is_none() && k == *key {
+                found = map.next_value_seed(Select { path: rest })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let (key, rest) = self.path.split_first().unwrap();
+        let index = key.parse::<usize>().ok();
+        let mut found = None;
+        for i in 0.. {
+            let more = if index == Some(i) {
+                seq.next_element_seed(Select { path: rest })?
+                    .map(|v| found = v)
+                    .is_some()
+            } else {
+                seq.next_element::<IgnoredAny>()?.is_some()
+            };
+            if !more {
+                break;
+            }
+        }
+        Ok(found)
+    }
+
+    // Scalars have nothing below them.
+    fn visit_bool<E>(self, _: bool) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E>(self, _: &str) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+}
+
+/// Read errors as I/O errors, so transient ones are retried, and the rest as
+/// JSON errors.
+fn json_error(e: serde_json::Error) -> Error {
+    if e.is_io() {
+        IOError::from(e).into()
+    } else {
+        e.into()
+    }
+}
+
+/// Parse only the value at `pointer` in the JSON document read from
+/// `reader`, which should be buffered.
+pub fn from_reader<R: Read>(reader: R, pointer: &str) -> Result<Value> {
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        let msg = format!("select {:?} must be empty or start with '/'", pointer);
+        return Err(IOError::new(ErrorKind::InvalidInput, msg).into());
+    }
+    let path: Vec<String> = pointer
+        .split('/')
+        .skip(1)
+        .map(|s| s.replace("~1", "/").replace("~0", "~"))
+        .collect();
+
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let found = Select { path: &path }
+        .deserialize(&mut de)
+        .map_err(json_error)?;
+    de.end().map_err(json_error)?;
+    found.ok_or_else(|| {
+        let msg = format!("select {:?} is not in the data", pointer);
+        IOError::new(ErrorKind::NotFound, msg).into()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn selects_like_pointers() {
+        let text = r#"{"pages": [{"title": "a"}, {"title": "b", "x/y": 1}], "big": [1, 2, 3]}"#;
+        let doc: Value = serde_json::from_str(text).unwrap();
+        for pointer in &["", "/pages", "/pages/1", "/pages/1/title", "/pages/1/x~1y"] {
+            let selected = from_reader(text.as_bytes(), pointer);
+            let selected = selected.unwrap_or_else(|e| panic!("{}", e));
+            assert_eq!(Some(&selected), doc.pointer(pointer));
+        }
+        assert_eq!(
+            from_reader(text.as_bytes(), "/pages/0").unwrap_or_else(|e| panic!("{}", e)),
+            json!({"title": "a"})
+        );
+        for pointer in &["/missing", "/pages/2", "/pages/x", "/big/0/deeper", "pages"] {
+            assert!(from_reader(text.as_bytes(), pointer).is_err());
+        }
+        assert!(from_reader(&b"{\"a\": 1} trailing"[..], "/a").is_err());
+    }
+}
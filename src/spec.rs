@@ -1,11 +1,19 @@
-use std::fs::metadata;
-use std::io::Error as IOError;
+// serde_derive 1.0.92 puts the impls it derives in named consts.
+#![allow(non_local_definitions)]
+
+use std::collections::BTreeMap;
+use std::fs::{self, metadata, File};
+use std::io::{Error as IOError, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use walkdir::WalkDir;
 
-use crate::error::Missing;
+use crate::error::{self, Missing};
+use crate::os_path;
 
 pub enum OutputStatus {
     UpToDate,
@@ -16,18 +24,208 @@ pub enum OutputStatus {
 
 use OutputStatus::{CannotDetermine, FileMissing, OutOfDate, UpToDate};
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    #[default]
+    Handlebars,
+    Subst,
+}
+
+impl Engine {
+    pub const fn variants() -> [&'static str; 2] {
+        ["handlebars", "subst"]
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "handlebars" => Ok(Engine::Handlebars),
+            "subst" => Ok(Engine::Subst),
+            other => Err(format!("unknown engine: {}", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escape {
+    #[default]
+    Html,
+    Latex,
+    None,
+}
+
+impl FromStr for Escape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(Escape::Html),
+            "latex" => Ok(Escape::Latex),
+            "none" => Ok(Escape::None),
+            other => Err(format!("unknown escape mode: {}", other)),
+        }
+    }
+}
+
+/// The digest given to templates as `data_hash` and `template_hash`, or
+/// `none` to skip reading the inputs for it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    None,
+}
+
+/// Whitespace control for block tags, on top of handlebars' `~` markers.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Whitespace {
+    /// Drop the first newline after a block tag.
+    #[serde(default)]
+    pub trim_blocks: bool,
+    /// Drop spaces and tabs between the start of a line and a block tag.
+    #[serde(default)]
+    pub lstrip_blocks: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct TemplateDef {
     pub name: String,
+    #[serde(with = "os_path")]
     pub data: PathBuf,
+    #[serde(with = "os_path")]
     pub template: PathBuf,
+    #[serde(with = "os_path")]
     pub output: PathBuf,
+    #[serde(default)]
+    pub engine: Engine,
+    #[serde(default, flatten)]
+    pub whitespace: Whitespace,
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// JSON Pointer to the part of the data given to the template as `root`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<String>,
+}
+
+/// A multigen spec file.
+///
+/// Either a plain array of templates, or an object with the templates under
+/// `templates` alongside other settings.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Spec {
+    /// Helper names mapped to the script or plugin that implements them.
+    #[serde(default, with = "os_path::map")]
+    pub helpers: BTreeMap<String, PathBuf>,
+    /// Values added to the root context of every template.
+    #[serde(default)]
+    pub context: Map<String, Value>,
+    /// Data fields of every entry to list in `siblings`, for index pages.
+    /// Without this there is no `siblings` list.
+    #[serde(default)]
+    pub siblings: Option<Vec<String>>,
+    pub templates: Vec<TemplateDef>,
+}
+
+impl Spec {
+    /// Parse a spec straight into its entries, without an intermediate
+    /// `Value` tree.
+    pub fn from_slice(bytes: &[u8]) -> serde_json::Result<Self> {
+        match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'[') => Ok(Spec {
+                templates: serde_json::from_slice(bytes)?,
+                ..Default::default()
+            }),
+            _ => serde_json::from_slice(bytes),
+        }
+    }
+
+    /// Load the spec at `path`, read in one piece since a large spec read
+    /// through an unbuffered file is slow to parse.
+    ///
+    /// The whole spec is parsed before any entry is scheduled: entries are
+    /// not streamed, since multigen needs all of them up front for its
+    /// caches, siblings and staleness checks.
+    pub fn load(path: impl AsRef<Path>) -> error::Result<Self> {
+        Ok(Self::from_slice(&fs::read(path)?)?)
+    }
 }
 
 fn get_mod_time(p: impl AsRef<Path>) -> Result<SystemTime, IOError> {
     metadata(p)?.modified()
 }
 
+/// Newest (or oldest) modification time of the files below `p`, or of `p`
+/// itself when it isn't a directory.  `None` for a directory without files.
+fn get_tree_mod_time(p: &Path, newest: bool) -> Result<Option<SystemTime>, IOError> {
+    if !p.is_dir() {
+        return get_mod_time(p).map(Some);
+    }
+
+    let mut found = None;
+    for entry in WalkDir::new(p) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let t = entry.metadata()?.modified()?;
+            found = Some(match found {
+                Some(f) if newest => std::cmp::max(f, t),
+                Some(f) => std::cmp::min(f, t),
+                None => t,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Why the input `what` at `p` can't be used, if it can't.  Only templates
+/// may be directories.
+fn input_problem(what: &str, p: &Path, dir_ok: bool) -> Option<String> {
+    let shown = p.display();
+    match fs::symlink_metadata(p) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Some(format!("missing {}: {}", what, shown))
+        }
+        Err(e) => return Some(format!("unreadable {}: {}: {}", what, shown, e)),
+        Ok(meta) if meta.file_type().is_symlink() && !p.exists() => {
+            let target = fs::read_link(p).unwrap_or_default();
+            return Some(format!(
+                "broken symlink for {}: {} -> {}",
+                what,
+                shown,
+                target.display()
+            ));
+        }
+        Ok(_) => {}
+    }
+    let readable = if !p.is_dir() {
+        File::open(p).map(drop)
+    } else if dir_ok {
+        fs::read_dir(p).map(drop)
+    } else {
+        return Some(format!("{} is a directory: {}", what, shown));
+    };
+    readable
+        .err()
+        .map(|e| format!("unreadable {}: {}: {}", what, shown, e))
+}
+
+/// Total size of the files below `p`, or of `p` itself.  Unreadable files
+/// count as empty.
+fn get_tree_size(p: &Path) -> u64 {
+    WalkDir::new(p)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
 impl TemplateDef {
     pub fn new<S, P>(name: S, data: P, template: P, output: P) -> Result<Self, Missing>
     where
@@ -51,33 +249,69 @@ impl TemplateDef {
             data,
             template,
             output,
+            engine: Engine::Handlebars,
+            whitespace: Whitespace {
+                trim_blocks: false,
+                lstrip_blocks: false,
+            },
+            hash: HashAlgorithm::Sha256,
+            select: None,
         }
     }
 
+    pub fn with_engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn with_whitespace(mut self, whitespace: Whitespace) -> Self {
+        self.whitespace = whitespace;
+        self
+    }
+
+    pub fn with_hash(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn validate_data(&self) -> Result<(), Missing> {
+        match input_problem("data file", &self.data, false) {
+            Some(problem) => Err(vec![problem].into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the template and data can be read, reporting why each
+    /// can't: missing, a broken symlink, a directory where a file is needed,
+    /// or unreadable, with the OS error.
     pub fn validate_files(&self) -> Result<(), Missing> {
-        let data_exists = self.data.exists();
-        let template_exists = self.template.exists();
-
-        match (data_exists, template_exists) {
-            (true, true) => Ok(()),
-            (data, template) => {
-                let mut missing = Vec::new();
-                if template {
-                    missing.push(format!("template file: {}", self.template.display()));
-                }
-                if data {
-                    missing.push(format!("data file: {}", self.data.display()));
-                }
-                Err(missing.into())
-            }
+        let problems: Vec<String> = vec![
+            input_problem("template", &self.template, true),
+            input_problem("data file", &self.data, false),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.into())
         }
     }
 
+    /// The size of the data and template, to start the largest renders first.
+    pub fn input_size(&self) -> u64 {
+        get_tree_size(&self.data) + get_tree_size(&self.template)
+    }
+
     pub fn should_build(&self) -> bool {
-        if let UpToDate = self.up_to_date() {
-            false
-        } else {
-            true
+        match self.up_to_date() {
+            UpToDate => false,
+            CannotDetermine(e) => {
+                debug!("{}: cannot determine staleness: {}", self.name, e);
+                true
+            }
+            _ => true,
         }
     }
 
@@ -86,8 +320,11 @@ impl TemplateDef {
             return FileMissing;
         }
 
-        let output_modified = match get_mod_time(&self.output) {
-            Ok(t) => t,
+        let output_modified = match get_tree_mod_time(&self.output, false) {
+            Ok(Some(t)) => t,
+            Ok(None) => {
+                return OutOfDate;
+            }
             Err(e) => {
                 return CannotDetermine(e);
             }
@@ -100,8 +337,8 @@ impl TemplateDef {
             }
         };
 
-        let template_modified = match get_mod_time(&self.template) {
-            Ok(t) => t,
+        let template_modified = match get_tree_mod_time(&self.template, true) {
+            Ok(t) => t.unwrap_or(UNIX_EPOCH),
             Err(e) => {
                 return CannotDetermine(e);
             }
@@ -118,7 +355,6 @@ impl TemplateDef {
 #[cfg(test)]
 mod test {
     use super::*;
-    use serde_json;
 
     #[test]
     fn deser_single() {
@@ -139,4 +375,108 @@ mod test {
 
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn deser_engine() {
+        let actual: TemplateDef = serde_json::from_value(serde_json::json!({
+            "name": "example",
+            "data": "example.json",
+            "template": "example.conf.in",
+            "output": "example.conf",
+            "engine": "subst"
+        }))
+        .unwrap();
+
+        assert_eq!(actual.engine, Engine::Subst);
+    }
+
+    #[test]
+    fn deser_whitespace() {
+        let actual: TemplateDef = serde_json::from_value(serde_json::json!({
+            "name": "example",
+            "data": "example.json",
+            "template": "example.rst.hbs",
+            "output": "example.rst",
+            "trim_blocks": true
+        }))
+        .unwrap();
+
+        assert!(actual.whitespace.trim_blocks);
+        assert!(!actual.whitespace.lstrip_blocks);
+    }
+
+    #[test]
+    fn deser_hash() {
+        let actual: TemplateDef = serde_json::from_value(serde_json::json!({
+            "name": "example",
+            "data": "huge.json",
+            "template": "example.hbs",
+            "output": "example.rst",
+            "hash": "none"
+        }))
+        .unwrap();
+
+        assert_eq!(actual.hash, HashAlgorithm::None);
+    }
+
+    #[test]
+    fn deser_spec() {
+        let template = serde_json::json!({
+            "name": "example",
+            "data": "example.json",
+            "template": "example.hbs",
+            "output": "example.rst"
+        });
+
+        let parse = |v: Value| Spec::from_slice(&serde_json::to_vec(&v).unwrap()).unwrap();
+        let list = parse(serde_json::json!([template.clone()]));
+        assert!(list.helpers.is_empty());
+        assert_eq!(list.templates.len(), 1);
+
+        let full = parse(serde_json::json!({
+            "helpers": {"slugify": "scripts/slugify.py"},
+            "context": {"channel": "beta"},
+            "templates": [template.clone()]
+        }));
+        assert_eq!(full.helpers["slugify"], Path::new("scripts/slugify.py"));
+        assert_eq!(full.context["channel"], "beta");
+        assert_eq!(full.templates, list.templates);
+
+        let bytes = format!("\n  [{}]", template);
+        assert_eq!(Spec::from_slice(bytes.as_bytes()).unwrap(), list);
+    }
+
+    #[test]
+    fn validation_tells_why_inputs_are_unusable() {
+        let dir = std::env::temp_dir().join(format!("ttgen-validate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("t.hbs");
+        fs::write(&template, "").unwrap();
+        let problems = |data: &Path| {
+            let spec = TemplateDef::new_unchecked(
+                "x".into(),
+                data.to_path_buf(),
+                template.clone(),
+                dir.join("out"),
+            );
+            spec.validate_files().err().map(|e| e.to_string())
+        };
+
+        assert_eq!(problems(&template), None);
+        let missing = dir.join("missing.json");
+        let expected = format!("missing data file: {}\n", missing.display());
+        assert_eq!(problems(&missing), Some(expected));
+        let expected = format!("data file is a directory: {}\n", dir.display());
+        assert_eq!(problems(&dir), Some(expected));
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.json");
+            std::os::unix::fs::symlink(&missing, &link).unwrap();
+            let problem = problems(&link).unwrap();
+            assert!(problem.starts_with("broken symlink for data file: "));
+            assert!(problem.contains("missing.json"));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,350 @@
+//! State kept between runs.
+//!
+//! `--build-counter FILE` keeps the number of the last run in FILE, plain
+//! text, and gives each run the next one as `build_id`.  A missing file
+//! starts the count at 1.
+//!
+//! `--hash-cache FILE` keeps the input digests of the last run, keyed by
+//! path, with the modification time and size they were computed for.
+//! Inputs whose time and size still match reuse their digest instead of
+//! being hashed again.
+//!
+//! `multigen --state` decides what to build by content instead of by
+//! modification time.  The state file, `.ttgen-state` beside the spec unless
+//! given as `--state=FILE`, records for each entry's last successful build
+//! the digests of its data, template and output, the ttgen version, the
+//! entry itself and the options it was rendered with, including the digests
+//! of the files they name and the values `--git` and `--host-info` added.
+//! An entry is skipped only while all of them are unchanged.  Checkouts,
+//! `touch` and restored caches move times without changing content, and the
+//! output's digest catches outputs edited or deleted since.
+
+// serde_derive 1.0.92 puts the impls it derives in named consts.
+#![allow(non_local_definitions)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::*;
+use crate::render;
+use crate::spec::{HashAlgorithm, TemplateDef};
+
+static HASH_CACHE: OnceCell<HashCache> = OnceCell::new();
+
+/// Replace `path` with `contents` through a temporary file, so a failed
+/// write never leaves it truncated.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Increment the counter in `path` and return its new value.
+pub fn next_build_id(path: &Path) -> Result<u64> {
+    let last = match fs::read_to_string(path) {
+        Ok(text) => text.trim().parse::<u64>().map_err(|e| {
+            let msg = format!("{}: invalid build counter: {}", path.display(), e);
+            IOError::new(ErrorKind::InvalidData, msg)
+        })?,
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    let id = last + 1;
+    write_atomic(path, format!("{}\n", id).as_bytes())?;
+    Ok(id)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Digest {
+    secs: u64,
+    nanos: u32,
+    size: u64,
+    algorithm: HashAlgorithm,
+    digest: String,
+}
+
+impl Digest {
+    /// An entry for the current state of `path`, with an empty digest.
+    fn stat(path: &Path, algorithm: HashAlgorithm) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Digest {
+            secs: mtime.as_secs(),
+            nanos: mtime.subsec_nanos(),
+            size: meta.len(),
+            algorithm,
+            digest: String::new(),
+        })
+    }
+
+    fn matches(&self, other: &Digest) -> bool {
+        (self.secs, self.nanos, self.size, self.algorithm)
+            == (other.secs, other.nanos, other.size, other.algorithm)
+    }
+}
+
+struct HashCache {
+    path: PathBuf,
+    digests: Mutex<HashMap<String, Digest>>,
+    changed: AtomicBool,
+}
+
+impl HashCache {
+    /// The digests cached in `path`.  A missing or unreadable cache starts
+    /// out empty.
+    fn load(path: &Path) -> Self {
+        let digests = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        HashCache {
+            path: path.to_path_buf(),
+            digests: Mutex::new(digests),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// The digest of the file at `path`, from this cache if its time and
+    /// size are unchanged, or else from `compute`.
+    fn digest<F>(&self, path: &Path, algorithm: HashAlgorithm, compute: F) -> Result<Value>
+    where
+        F: FnOnce() -> Result<Value>,
+    {
+        let mut stat = match Digest::stat(path, algorithm) {
+            Some(stat) => stat,
+            None => return compute(),
+        };
+        let key = path.display().to_string();
+        if let Some(cached) = self.digests.lock().unwrap().get(&key) {
+            if cached.matches(&stat) {
+                return Ok(Value::from(cached.digest.clone()));
+            }
+        }
+
+        let digest = compute()?;
+        if let Value::String(s) = &digest {
+            stat.digest = s.clone();
+            self.digests.lock().unwrap().insert(key, stat);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        Ok(digest)
+    }
+
+    /// Write this cache back, if it changed.
+    fn save(&self) -> Result<()> {
+        if !self.changed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let digests = self.digests.lock().unwrap();
+        write_atomic(&self.path, &serde_json::to_vec(&*digests)?)
+    }
+}
+
+/// Use the digests cached in `path`, written back by `save_hash_cache`.
+pub fn load_hash_cache(path: &Path) {
+    let _ = HASH_CACHE.set(HashCache::load(path));
+}
+
+/// The digest of the file at `path`, from the hash cache if one is in use,
+/// or else from `compute`.
+pub fn cached_digest<F>(path: &Path, algorithm: HashAlgorithm, compute: F) -> Result<Value>
+where
+    F: FnOnce() -> Result<Value>,
+{
+    match HASH_CACHE.get() {
+        Some(cache) if algorithm != HashAlgorithm::None => cache.digest(path, algorithm, compute),
+        _ => compute(),
+    }
+}
+
+/// Write the hash cache back, if one is in use and it changed.
+pub fn save_hash_cache() -> Result<()> {
+    HASH_CACHE.get().map_or(Ok(()), HashCache::save)
+}
+
+/// One entry's last successful build.  Fields missing from older state
+/// files never match, so those entries are rebuilt once.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Build {
+    data: String,
+    template: String,
+    output: String,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    entry: Value,
+    #[serde(default)]
+    options: Value,
+}
+
+impl Build {
+    fn of(spec: &TemplateDef, options: &Value) -> Result<Self> {
+        Ok(Build {
+            data: render::digest(&spec.data)?,
+            template: render::digest(&spec.template)?,
+            output: render::digest(&spec.output)?,
+            version: clap::crate_version!().to_string(),
+            entry: serde_json::to_value(spec)?,
+            options: options.clone(),
+        })
+    }
+}
+
+/// The builds recorded by `--state`, keyed by output path.
+pub struct BuildState {
+    path: PathBuf,
+    /// The options of this run, or `None` to ignore those of past builds.
+    options: Option<Value>,
+    builds: Mutex<BTreeMap<String, Build>>,
+}
+
+impl BuildState {
+    /// The builds recorded in `path`, none if it doesn't exist yet, to be
+    /// compared with and updated by builds with `options`.
+    pub fn load(path: &Path, options: Option<Value>) -> Result<Self> {
+        let builds = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(BuildState {
+            path: path.to_path_buf(),
+            options,
+            builds: Mutex::new(builds),
+        })
+    }
+
+    /// Whether `spec` and its inputs, output and options are those of its
+    /// last recorded build.
+    pub fn is_current(&self, spec: &TemplateDef) -> bool {
+        let key = spec.output.display().to_string();
+        let recorded_options = match self.builds.lock().unwrap().get(&key) {
+            Some(recorded) => recorded.options.clone(),
+            None => return false,
+        };
+        let options = self.options.as_ref().unwrap_or(&recorded_options);
+        match Build::of(spec, options) {
+            Ok(current) => self.builds.lock().unwrap().get(&key) == Some(&current),
+            Err(e) => {
+                debug!("{}: cannot compare with the last build: {}", spec.name, e);
+                false
+            }
+        }
+    }
+
+    /// Record a successful build of `spec`.
+    pub fn record(&self, spec: &TemplateDef) -> Result<()> {
+        let options = self.options.clone().unwrap_or_default();
+        let build = Build::of(spec, &options)?;
+        let key = spec.output.display().to_string();
+        self.builds.lock().unwrap().insert(key, build);
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let builds = self.builds.lock().unwrap();
+        write_atomic(&self.path, &serde_json::to_vec_pretty(&*builds)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spec::Engine;
+
+    #[test]
+    fn counts_builds() {
+        let dir = std::env::temp_dir().join(format!("ttgen-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("build-counter");
+        let next = || next_build_id(&path).unwrap_or_else(|e| panic!("{}", e));
+
+        assert_eq!(next(), 1);
+        assert_eq!(next(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+        fs::write(&path, "two").unwrap();
+        assert!(next_build_id(&path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reuses_digests_of_unchanged_files() {
+        let dir = std::env::temp_dir().join(format!("ttgen-hashes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cache, input) = (dir.join("hashes.json"), dir.join("input.json"));
+        fs::write(&input, "{}").unwrap();
+        let hashes = HashCache::load(&cache);
+        let digest = |value: &str| {
+            let d = hashes.digest(&input, HashAlgorithm::Sha256, || Ok(Value::from(value)));
+            d.unwrap_or_else(|e| panic!("{}", e))
+        };
+
+        assert_eq!(digest("first"), "first");
+        assert_eq!(digest("second"), "first");
+        fs::write(&input, "{\"changed\": true}").unwrap();
+        assert_eq!(digest("third"), "third");
+        let sha512 = hashes.digest(&input, HashAlgorithm::Sha512, || Ok(Value::from("512")));
+        assert_eq!(sha512.unwrap_or_else(|e| panic!("{}", e)), "512");
+
+        hashes.save().unwrap_or_else(|e| panic!("{}", e));
+        let saved: HashMap<String, Digest> =
+            serde_json::from_slice(&fs::read(&cache).unwrap()).unwrap();
+        assert_eq!(saved[&input.display().to_string()].digest, "512");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compares_builds_by_content() {
+        let dir = std::env::temp_dir().join(format!("ttgen-builds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, text: &str| {
+            let path = dir.join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+        let spec = TemplateDef::new_unchecked(
+            "example".into(),
+            file("data.json", "{}"),
+            file("template.hbs", "x"),
+            file("output.txt", "x"),
+        );
+        let path = dir.join("state.json");
+        let load = |options: Option<Value>| {
+            BuildState::load(&path, options).unwrap_or_else(|e| panic!("{}", e))
+        };
+        let state = load(Some(Value::from("strict")));
+
+        assert!(!state.is_current(&spec));
+        state.record(&spec).unwrap_or_else(|e| panic!("{}", e));
+        assert!(state.is_current(&spec));
+        file("data.json", "{}");
+        assert!(state.is_current(&spec));
+        file("output.txt", "edited");
+        assert!(!state.is_current(&spec));
+
+        state.record(&spec).unwrap_or_else(|e| panic!("{}", e));
+        state.save().unwrap_or_else(|e| panic!("{}", e));
+        assert!(load(Some(Value::from("strict"))).is_current(&spec));
+        assert!(!load(Some(Value::from("lenient"))).is_current(&spec));
+        assert!(load(None).is_current(&spec));
+        let state = load(None);
+        assert!(!state.is_current(&spec.clone().with_engine(Engine::Subst)));
+        fs::remove_file(&spec.output).unwrap();
+        assert!(!state.is_current(&spec));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Minimal `${var}` substitution engine.
+//!
+//! Placeholders are dotted paths into the root context, e.g. `${root.server.port}`.
+//! There are no conditionals, loops, or helpers; `$${` emits a literal `${`.
+
+use std::io::prelude::*;
+
+use serde_json::{Map, Value};
+
+use crate::error::*;
+
+fn lookup<'a>(root: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let mut current = root.get(parts.next()?)?;
+    for part in parts {
+        current = match current {
+            Value::Object(m) => m.get(part)?,
+            Value::Array(v) => v.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+fn render_value(path: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Null => Ok(String::new()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        _ => Err(SubstError::from(format!("{} is not a scalar value", path)).into()),
+    }
+}
+
+pub fn render<W: Write>(source: &str, root: &Map<String, Value>, writer: &mut W) -> Result<()> {
+    let mut rest = source;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            writer.write_all(&rest.as_bytes()[..start - 1])?;
+            writer.write_all(b"${")?;
+            rest = &rest[start + 2..];
+            continue;
+        }
+        writer.write_all(&rest.as_bytes()[..start])?;
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| SubstError::from("unterminated placeholder".to_string()))?;
+        let path = after[..end].trim();
+        let value = lookup(root, path)
+            .ok_or_else(|| SubstError::from(format!("undefined variable: {}", path)))?;
+        writer.write_all(render_value(path, value)?.as_bytes())?;
+        rest = &after[end + 1..];
+    }
+    writer.write_all(rest.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render_str(source: &str, root: Value) -> Result<String> {
+        let root = match root {
+            Value::Object(m) => m,
+            _ => unreachable!(),
+        };
+        let mut out = Vec::new();
+        render(source, &root, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn substitutes_paths() {
+        let root = serde_json::json!({
+            "name": "ttgen",
+            "root": {"server": {"port": 8080, "hosts": ["a", "b"]}}
+        });
+        let actual = render_str("${name}:${root.server.port} ${root.server.hosts.1}", root);
+        assert_eq!(actual.ok().unwrap(), "ttgen:8080 b");
+    }
+
+    #[test]
+    fn escapes_and_plain_dollars() {
+        let root = serde_json::json!({"name": "ttgen"});
+        let actual = render_str("$$HOME $${name} ${name}", root);
+        assert_eq!(actual.ok().unwrap(), "$$HOME ${name} ttgen");
+    }
+
+    #[test]
+    fn undefined_variable_fails() {
+        let root = serde_json::json!({"name": "ttgen"});
+        assert!(render_str("${nope}", root).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Render tracing for `generate --trace`.
+//!
+//! handlebars has no evaluation hooks, so the compiled template is
+//! instrumented instead: ahead of every variable lookup, helper call and
+//! partial, a call to the trace helper is inserted with the same parameters.
+//! It resolves them, reports the values and writes nothing to the output, so
+//! the original elements still render exactly as before.
+//!
+//! `instrument` takes the helper name, so other passes such as data key
+//! usage can reuse it with a helper of their own.
+
+use std::io::{Error as IOError, Write};
+use std::sync::{Arc, Mutex};
+
+use handlebars::template::{HelperTemplate, Parameter, TemplateElement, TemplateMapping};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, PathAndJson, RenderContext,
+    RenderError, Template,
+};
+use serde_json::Value;
+
+use TemplateElement::*;
+
+pub const INSERTED_PREFIX: &str = "__ttgen_";
+pub const HELPER_NAME: &str = "__ttgen_trace";
+const MAX_VALUE_LEN: usize = 60;
+
+pub type Sink = Arc<Mutex<Box<dyn Write + Send>>>;
+
+struct TraceHelper {
+    sink: Sink,
+}
+
+fn describe(p: &PathAndJson) -> String {
+    let value = if p.is_value_missing() {
+        "<missing>".to_string()
+    } else {
+        let rendered = p.value().to_string();
+        match rendered.char_indices().nth(MAX_VALUE_LEN) {
+            Some((i, _)) => format!("{}...", &rendered[..i]),
+            None => rendered,
+        }
+    };
+    match p.path() {
+        Some(path) => format!("{} = {}", path, value),
+        None => value,
+    }
+}
+
+impl HelperDef for TraceHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        let label = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+        let mut parts: Vec<String> = h.params().iter().skip(1).map(describe).collect();
+
+        let mut keys: Vec<&String> = h.hash().keys().collect();
+        keys.sort();
+        for k in keys {
+            parts.push(format!("{}: {}", k, describe(&h.hash()[k])));
+        }
+
+        let mut sink = self
+            .sink
+            .lock()
+            .map_err(|_| RenderError::new("trace sink poisoned"))?;
+        writeln!(sink, "trace: {} {}", label, parts.join(", ")).map_err(RenderError::from)?;
+        Ok(())
+    }
+}
+
+/// Register the helper that instrumented templates call into.
+pub fn register(hb: &mut Handlebars, sink: Sink) {
+    hb.register_helper(HELPER_NAME, Box::new(TraceHelper { sink }));
+}
+
+fn trace_call(
+    helper: &str,
+    label: String,
+    params: &[Parameter],
+    ht: Option<&HelperTemplate>,
+) -> TemplateElement {
+    let mut all = vec![Parameter::Literal(Value::from(label))];
+    all.extend_from_slice(params);
+    HelperExpression(Box::new(HelperTemplate {
+        name: helper.to_string(),
+        params: all,
+        hash: ht.map(|ht| ht.hash.clone()).unwrap_or_default(),
+        block_param: None,
+        template: None,
+        inverse: None,
+        block: false,
+    }))
+}
+
+/// Insert calls to `helper` throughout `t`, including nested block templates.
+///
+/// Each call gets a `line:col kind` label, then the parameters and hash of
+/// the element it precedes.
+pub fn instrument(t: &mut Template, helper: &str) {
+    let elements = std::mem::take(&mut t.elements);
+    let mapping = t.mapping.take();
+    let mut new_mapping = mapping.as_ref().map(|_| Vec::new());
+
+    for (idx, mut el) in elements.into_iter().enumerate() {
+        let pos = mapping.as_ref().and_then(|m| m.get(idx)).cloned();
+        let at = match pos {
+            Some(TemplateMapping(line, col)) => format!("{}:{}", line, col),
+            None => "?".to_string(),
+        };
+
+        let trace = match el {
+            // Calls inserted by an earlier pass, e.g. tracing plus usage.
+            HelperExpression(ref ht) if ht.name.starts_with(INSERTED_PREFIX) => None,
+            Expression(ref p) | HTMLExpression(ref p) => Some(trace_call(
+                helper,
+                format!("{} lookup", at),
+                std::slice::from_ref(p),
+                None,
+            )),
+            HelperExpression(ref mut ht) | HelperBlock(ref mut ht) => {
+                if let Some(t) = ht.template.as_mut() {
+                    instrument(t, helper);
+                }
+                if let Some(t) = ht.inverse.as_mut() {
+                    instrument(t, helper);
+                }
+                let label = format!("{} helper {}", at, ht.name);
+                Some(trace_call(helper, label, &ht.params, Some(ht)))
+            }
+            PartialExpression(ref mut dt) | PartialBlock(ref mut dt) => {
+                if let Some(t) = dt.template.as_mut() {
+                    instrument(t, helper);
+                }
+                match dt.name {
+                    Parameter::Name(ref n) => Some(trace_call(
+                        helper,
+                        format!("{} partial {}", at, n),
+                        &dt.params,
+                        None,
+                    )),
+                    ref dynamic => {
+                        let mut params = vec![dynamic.clone()];
+                        params.extend_from_slice(&dt.params);
+                        Some(trace_call(helper, format!("{} partial", at), &params, None))
+                    }
+                }
+            }
+            DirectiveBlock(ref mut dt) => {
+                if let Some(t) = dt.template.as_mut() {
+                    instrument(t, helper);
+                }
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(trace) = trace {
+            t.elements.push(trace);
+            if let (Some(m), Some(p)) = (new_mapping.as_mut(), pos.clone()) {
+                m.push(p);
+            }
+        }
+        t.elements.push(el);
+        if let (Some(m), Some(p)) = (new_mapping.as_mut(), pos) {
+            m.push(p);
+        }
+    }
+
+    t.mapping = new_mapping;
+}
+
+/// `Output` adapter over any writer; handlebars keeps its own private.
+pub struct WriteOutput<W: Write>(pub W);
+
+impl<W: Write> Output for WriteOutput<W> {
+    fn write(&mut self, seg: &str) -> Result<(), IOError> {
+        self.0.write_all(seg.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use handlebars::Renderable;
+
+    #[test]
+    fn traces_without_changing_output() {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        register(&mut hb, Arc::new(Mutex::new(Box::new(Shared(log.clone())))));
+
+        let mut t =
+            Template::compile2("{{#each items}}<{{this}}>{{/each}} {{name}}", true).unwrap();
+        instrument(&mut t, HELPER_NAME);
+
+        let data = serde_json::json!({"items": [1, 2], "name": "a&b"});
+        let ctx = Context::wraps(&data).unwrap();
+        let mut out = WriteOutput(Vec::new());
+        t.render(&hb, &ctx, &mut RenderContext::new(None), &mut out)
+            .unwrap();
+
+        assert_eq!(String::from_utf8(out.0).unwrap(), "<1><2> a&amp;b");
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("helper each items = [1,2]"));
+        assert!(log.contains("lookup this = 2"));
+        assert!(log.contains("lookup name = \"a&b\""));
+    }
+}
@@ -0,0 +1,169 @@
+//! Data key usage for `--warn-unused`.
+//!
+//! Templates are instrumented as for tracing (see `trace::instrument`), but
+//! the inserted helper only records which top-level key of `root` each
+//! resolved value lives under.  Values are matched by address against the
+//! render context, so lookups through `../`, `@root`, `with` and `each` are
+//! attributed to the key they actually read.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, PathAndJson, RenderContext,
+};
+use serde_json::Value;
+
+pub const HELPER_NAME: &str = "__ttgen_usage";
+
+/// Index entry for the `root` object itself, which counts as every key.
+const ALL_KEYS: usize = usize::MAX;
+
+thread_local! {
+    static USAGE: RefCell<Option<Usage>> = const { RefCell::new(None) };
+}
+
+#[derive(Default)]
+struct Usage {
+    keys: Vec<String>,
+    /// Address of every value below `root`, mapped to its index in `keys`.
+    index: HashMap<usize, usize>,
+    used: BTreeSet<usize>,
+}
+
+fn address(v: &Value) -> usize {
+    v as *const Value as usize
+}
+
+fn walk(v: &Value, key: usize, index: &mut HashMap<usize, usize>) {
+    index.insert(address(v), key);
+    match v {
+        Value::Array(items) => items.iter().for_each(|item| walk(item, key, index)),
+        Value::Object(map) => map.values().for_each(|item| walk(item, key, index)),
+        _ => {}
+    }
+}
+
+impl Usage {
+    fn record(&mut self, p: &PathAndJson) {
+        match self.index.get(&address(p.value())) {
+            Some(&ALL_KEYS) => self.used.extend(0..self.keys.len()),
+            Some(&key) => {
+                self.used.insert(key);
+            }
+            None => {}
+        }
+    }
+}
+
+struct UsageHelper;
+
+impl HelperDef for UsageHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        USAGE.with(|u| {
+            if let Some(usage) = u.borrow_mut().as_mut() {
+                // The first parameter is the instrumentation label.
+                h.params().iter().skip(1).for_each(|p| usage.record(p));
+                h.hash().values().for_each(|p| usage.record(p));
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Register the helper that instrumented templates call into.
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper(HELPER_NAME, Box::new(UsageHelper));
+}
+
+/// Whether `hb` has usage tracking registered.
+pub fn enabled(hb: &Handlebars) -> bool {
+    hb.get_helper(HELPER_NAME).is_some()
+}
+
+/// Index the values below `root` in `ctx`, which the next render will use.
+///
+/// Does nothing outside of `track`.
+pub fn index(ctx: &Context) {
+    USAGE.with(|u| {
+        if let Some(usage) = u.borrow_mut().as_mut() {
+            usage.index.clear();
+            let root = &ctx.data()["root"];
+            usage.index.insert(address(root), ALL_KEYS);
+            if let Value::Object(map) = root {
+                if usage.keys.is_empty() {
+                    usage.keys = map.keys().cloned().collect();
+                }
+                for (key, v) in map.values().enumerate() {
+                    walk(v, key, &mut usage.index);
+                }
+            }
+        }
+    });
+}
+
+/// Run `f`, and return the top-level data keys that no render within it
+/// referenced, in data file order.
+///
+/// Nested calls record into the outermost one and report nothing themselves.
+pub fn track<T, F: FnOnce() -> T>(f: F) -> (T, Vec<String>) {
+    let outermost = USAGE.with(|u| {
+        let mut u = u.borrow_mut();
+        if u.is_some() {
+            return false;
+        }
+        *u = Some(Usage::default());
+        true
+    });
+    let result = f();
+    if !outermost {
+        return (result, Vec::new());
+    }
+
+    let usage = USAGE.with(|u| u.borrow_mut().take()).unwrap_or_default();
+    let Usage { keys, used, .. } = usage;
+    let unused = keys
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !used.contains(i))
+        .map(|(_, key)| key)
+        .collect();
+    (result, unused)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::{instrument, WriteOutput};
+    use handlebars::{Renderable, Template};
+
+    #[test]
+    fn reports_keys_never_looked_up() {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+
+        let source =
+            "{{#with root.site}}{{name}}{{/with}}{{#each root.items}}{{../root.sep}}{{/each}}";
+        let mut t = Template::compile2(source, true).unwrap();
+        instrument(&mut t, HELPER_NAME);
+
+        let data = serde_json::json!({"root": {
+            "site": {"name": "x"}, "items": [1], "sep": ",", "dead": true, "gone": [],
+        }});
+        let ctx = Context::wraps(&data).unwrap();
+        let ((), unused) = track(|| {
+            index(&ctx);
+            let mut out = WriteOutput(Vec::new());
+            t.render(&hb, &ctx, &mut RenderContext::new(None), &mut out)
+                .unwrap();
+        });
+        assert_eq!(unused, vec!["dead", "gone"]);
+    }
+}
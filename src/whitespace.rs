@@ -0,0 +1,130 @@
+//! Jinja-style whitespace control for block tags.
+//!
+//! handlebars only strips whitespace where a tag asks for it with `~`, so a
+//! block on a line of its own leaves that line's newline and indentation in
+//! the output.  `trim_blocks` drops the first newline after a block tag and
+//! `lstrip_blocks` drops the spaces and tabs before one at the start of a
+//! line.  Both work on the raw text of the compiled template.
+
+use handlebars::template::{Template, TemplateElement};
+
+use crate::spec::Whitespace;
+
+use TemplateElement::*;
+
+fn trim_newline(el: Option<&mut TemplateElement>) {
+    if let Some(RawString(s)) = el {
+        if s.starts_with('\n') {
+            s.remove(0);
+        } else if s.starts_with("\r\n") {
+            s.drain(..2);
+        }
+    }
+}
+
+/// Strip trailing spaces and tabs if they begin a line; without a newline in
+/// `s`, only when `s` itself is at the start of a line.
+fn lstrip(el: Option<&mut TemplateElement>, line_start: bool) {
+    if let Some(RawString(s)) = el {
+        let start = match s.rfind('\n') {
+            Some(i) => i + 1,
+            None if line_start => 0,
+            None => return,
+        };
+        if s[start..].chars().all(|c| c == ' ' || c == '\t') {
+            s.truncate(start);
+        }
+    }
+}
+
+fn apply_to(t: &mut Template, ws: Whitespace, root: bool) {
+    for i in 0..t.elements.len() {
+        let inner = match t.elements[i] {
+            HelperBlock(ref mut ht) => vec![ht.template.as_mut(), ht.inverse.as_mut()],
+            DirectiveBlock(ref mut dt) | PartialBlock(ref mut dt) => vec![dt.template.as_mut()],
+            _ => continue,
+        };
+
+        // Inside the block: after the opening tag or `else`, and before the
+        // next `else` or the closing tag.
+        for block in inner.into_iter().flatten() {
+            if ws.trim_blocks {
+                trim_newline(block.elements.first_mut());
+            }
+            if ws.lstrip_blocks {
+                lstrip(block.elements.last_mut(), false);
+            }
+            apply_to(block, ws, false);
+        }
+
+        // Around the block: before the opening tag, after the closing tag.
+        if ws.lstrip_blocks && i > 0 {
+            lstrip(t.elements.get_mut(i - 1), root && i == 1);
+        }
+        if ws.trim_blocks {
+            trim_newline(t.elements.get_mut(i + 1));
+        }
+    }
+}
+
+/// Apply the whitespace control in `ws` to the compiled template `t`.
+pub fn apply(t: &mut Template, ws: Whitespace) {
+    // Strip first: trimming removes the newlines that mark a line start.
+    if ws.lstrip_blocks {
+        let lstrip_only = Whitespace {
+            trim_blocks: false,
+            ..ws
+        };
+        apply_to(t, lstrip_only, true);
+    }
+    if ws.trim_blocks {
+        let trim_only = Whitespace {
+            lstrip_blocks: false,
+            ..ws
+        };
+        apply_to(t, trim_only, true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::WriteOutput;
+    use handlebars::{Context, Handlebars, RenderContext, Renderable};
+
+    fn render(source: &str, ws: Whitespace) -> String {
+        let mut t = Template::compile2(source, true).unwrap();
+        apply(&mut t, ws);
+        let ctx = Context::wraps(serde_json::json!({"items": [1, 2], "a": true})).unwrap();
+        let mut out = WriteOutput(Vec::new());
+        t.render(
+            &Handlebars::new(),
+            &ctx,
+            &mut RenderContext::new(None),
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out.0).unwrap()
+    }
+
+    #[test]
+    fn trims_and_lstrips_block_lines() {
+        let source =
+            "  {{#each items}}\n  {{#if @root.a}}\n  - {{this}}\n  {{/if}}\n{{/each}}\nend\n";
+        let both = Whitespace {
+            trim_blocks: true,
+            lstrip_blocks: true,
+        };
+        assert_eq!(render(source, both), "  - 1\n  - 2\nend\n");
+
+        let trim_only = Whitespace {
+            trim_blocks: true,
+            lstrip_blocks: false,
+        };
+        assert_eq!(render(source, trim_only), "      - 1\n      - 2\n  end\n");
+        assert_eq!(
+            render(source, Whitespace::default()),
+            "  \n  \n  - 1\n  \n\n  \n  - 2\n  \n\nend\n"
+        );
+    }
+}